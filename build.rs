@@ -1,4 +1,7 @@
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     tonic_build::compile_protos("proto/telegram.proto")?;
+    if std::env::var_os("CARGO_FEATURE_GTFS_RT").is_some() {
+        tonic_build::compile_protos("proto/gtfs-realtime.proto")?;
+    }
     Ok(())
 }
@@ -0,0 +1,14 @@
+-- Your SQL goes here
+
+-- Learned order in which a line passes the reporting points, see locations::route
+CREATE TABLE r09_reporting_point_sequences (
+	id BIGSERIAL PRIMARY KEY,
+	region BIGINT REFERENCES regions(id) NOT NULL,
+	line INT NOT NULL,
+	destination_number INT NOT NULL,
+	branch INT NOT NULL,
+	position INT NOT NULL,
+	reporting_point INT NOT NULL,
+	confidence DOUBLE PRECISION NOT NULL,
+	CONSTRAINT unique_sequence_position UNIQUE (region, line, destination_number, branch, position)
+);
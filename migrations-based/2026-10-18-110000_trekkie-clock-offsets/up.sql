@@ -0,0 +1,16 @@
+-- Your SQL goes here
+
+-- Estimated clock offsets between trekkie runs and stations, see measurements::clock
+CREATE TABLE trekkie_clock_offsets (
+	id BIGSERIAL PRIMARY KEY,
+	trekkie_run UUID REFERENCES trekkie_runs(id) ON DELETE CASCADE NOT NULL,
+	station UUID REFERENCES stations(id),
+	offset_ms BIGINT NOT NULL,
+	score DOUBLE PRECISION NOT NULL,
+	samples INT NOT NULL,
+	ground_truth BOOLEAN NOT NULL,
+	CONSTRAINT unique_run_station_offset UNIQUE (trekkie_run, station)
+);
+
+-- the constraint above treats NULL stations as distinct, so run-level offsets need their own index
+CREATE UNIQUE INDEX unique_run_offset ON trekkie_clock_offsets (trekkie_run) WHERE station IS NULL;
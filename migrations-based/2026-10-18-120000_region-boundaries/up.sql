@@ -0,0 +1,4 @@
+-- Your SQL goes here
+
+-- Optional GeoJSON Polygon or MultiPolygon geometry, see locations::boundary
+ALTER TABLE regions ADD COLUMN boundary JSONB;
@@ -0,0 +1,16 @@
+-- Your SQL goes here
+
+-- Telegrams per minute, see statistics::timeseries. Telegrams without line are counted with
+-- line -1, as NULL can't be part of the primary key.
+CREATE TABLE r09_telegram_rollups (
+	bucket TIMESTAMP NOT NULL,
+	region BIGINT REFERENCES regions(id) NOT NULL,
+	station UUID REFERENCES stations(id) NOT NULL,
+	line INT NOT NULL,
+	reporting_point INT NOT NULL,
+	telegrams BIGINT NOT NULL,
+	PRIMARY KEY (bucket, region, station, line, reporting_point)
+);
+
+CREATE INDEX r09_telegram_rollups_region_bucket ON r09_telegram_rollups (region, bucket);
+CREATE INDEX r09_telegram_rollups_station_bucket ON r09_telegram_rollups (station, bucket);
@@ -0,0 +1,16 @@
+-- Your SQL goes here
+
+-- GTFS route ids of vehicles, see gtfs_rt. NULL run or destination_number match any
+-- value, the mapping with the most matching columns wins.
+CREATE TABLE gtfs_mappings (
+	id BIGSERIAL PRIMARY KEY,
+	region BIGINT REFERENCES regions(id) NOT NULL,
+	line INT NOT NULL,
+	run INT,
+	destination_number INT,
+	route_id TEXT NOT NULL,
+	direction_id INT
+);
+
+CREATE UNIQUE INDEX unique_gtfs_mapping
+	ON gtfs_mappings (region, line, COALESCE(run, -1), COALESCE(destination_number, -1));
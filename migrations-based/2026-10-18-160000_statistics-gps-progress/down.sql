@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+
+ALTER TABLE region_statistics DROP COLUMN last_gps_id;
+ALTER TABLE user_statistics DROP COLUMN last_gps_id;
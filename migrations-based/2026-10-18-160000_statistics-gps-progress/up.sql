@@ -0,0 +1,4 @@
+-- Your SQL goes here
+
+ALTER TABLE region_statistics ADD COLUMN last_gps_id BIGINT;
+ALTER TABLE user_statistics ADD COLUMN last_gps_id BIGINT;
@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+
+ALTER TABLE r09_transmission_locations_raw DROP COLUMN geog;
+ALTER TABLE r09_transmission_locations DROP COLUMN geog;
+ALTER TABLE gps_points DROP COLUMN geog;
+ALTER TABLE stations DROP COLUMN geog;
@@ -0,0 +1,21 @@
+-- Your SQL goes here
+
+-- Generated PostGIS positions with GiST indexes, see locations::postgis. Run after the migrations
+-- in migrations-based.
+CREATE EXTENSION IF NOT EXISTS postgis;
+
+ALTER TABLE stations ADD COLUMN geog geography(Point, 4326)
+	GENERATED ALWAYS AS (geography(ST_SetSRID(ST_MakePoint(lon, lat), 4326))) STORED;
+CREATE INDEX stations_geog_idx ON stations USING GIST (geog);
+
+ALTER TABLE gps_points ADD COLUMN geog geography(Point, 4326)
+	GENERATED ALWAYS AS (geography(ST_SetSRID(ST_MakePoint(lon, lat), 4326))) STORED;
+CREATE INDEX gps_points_geog_idx ON gps_points USING GIST (geog);
+
+ALTER TABLE r09_transmission_locations ADD COLUMN geog geography(Point, 4326)
+	GENERATED ALWAYS AS (geography(ST_SetSRID(ST_MakePoint(lon, lat), 4326))) STORED;
+CREATE INDEX r09_transmission_locations_geog_idx ON r09_transmission_locations USING GIST (geog);
+
+ALTER TABLE r09_transmission_locations_raw ADD COLUMN geog geography(Point, 4326)
+	GENERATED ALWAYS AS (geography(ST_SetSRID(ST_MakePoint(lon, lat), 4326))) STORED;
+CREATE INDEX r09_transmission_locations_raw_geog_idx ON r09_transmission_locations_raw USING GIST (geog);
@@ -0,0 +1,205 @@
+//! This module turns the [`VehicleState`]s of the [`VehicleTracker`](crate::tracking::VehicleTracker)
+//! into GTFS-Realtime feeds, so journey planners can consume the live positions.
+//!
+//! R09 telegrams know nothing about GTFS, so every vehicle is mapped to a GTFS route through the
+//! `gtfs_mappings` table, see [`GtfsMapping`]. A mapping matches by region and line and
+//! optionally by run and destination number, the most specific one wins. Vehicles without
+//! mapping are left out of the feed.
+//!
+//! Runs can't be matched to a trip instance of the GTFS schedule, so the vehicle positions only
+//! carry a partial trip descriptor with the route, which GTFS-Realtime allows. No `TripUpdate`s
+//! are published, as they need a trip and at least one `StopTimeUpdate`.
+
+#[cfg(test)]
+mod tests;
+
+use crate::schema::gtfs_mappings;
+use crate::tracking::VehicleState;
+
+use diesel::{Identifiable, Insertable, PgConnection, Queryable, RunQueryDsl};
+use prost::Message;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Generated GTFS-Realtime messages, see `proto/gtfs-realtime.proto`
+#[allow(missing_docs)]
+pub mod proto {
+    tonic::include_proto!("transit_realtime");
+}
+
+use proto::{
+    feed_header, FeedEntity, FeedHeader, FeedMessage, Position, TripDescriptor, VehicleDescriptor,
+    VehiclePosition,
+};
+
+/// GTFS-Realtime version written into the feed header
+pub const GTFS_REALTIME_VERSION: &str = "2.0";
+
+/// This struct is used to query the GTFS route of vehicles from the database
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Queryable, Identifiable)]
+#[diesel(table_name = gtfs_mappings)]
+pub struct GtfsMapping {
+    /// Primary key
+    pub id: i64,
+    /// ID of the region
+    pub region: i64,
+    /// line (ger. linie) of the vehicles
+    pub line: i32,
+    /// run (ger. Kurs, -Laufnummer) of the vehicles, [`None`] matches any run
+    pub run: Option<i32>,
+    /// destination number of the vehicles, [`None`] matches any destination
+    pub destination_number: Option<i32>,
+    /// GTFS `route_id`
+    pub route_id: String,
+    /// GTFS `direction_id`, `0` or `1`
+    pub direction_id: Option<i32>,
+}
+
+/// This struct is used to insert mappings, see [`GtfsMapping`]
+#[derive(Debug, Clone, Serialize, Deserialize, Insertable)]
+#[diesel(table_name = gtfs_mappings)]
+pub struct InsertGtfsMapping {
+    /// Primary key. During INSERT should be [`None`] so DB can auto-increment it
+    pub id: Option<i64>,
+    /// ID of the region
+    pub region: i64,
+    /// line (ger. linie) of the vehicles
+    pub line: i32,
+    /// run (ger. Kurs, -Laufnummer) of the vehicles, [`None`] matches any run
+    pub run: Option<i32>,
+    /// destination number of the vehicles, [`None`] matches any destination
+    pub destination_number: Option<i32>,
+    /// GTFS `route_id`
+    pub route_id: String,
+    /// GTFS `direction_id`, `0` or `1`
+    pub direction_id: Option<i32>,
+}
+
+impl GtfsMapping {
+    /// Returns how specific the mapping matches the vehicle, [`None`] if it doesn't match. A
+    /// matching run is more specific than a matching destination number.
+    pub fn specificity(&self, state: &VehicleState) -> Option<(bool, bool)> {
+        let run = match self.run {
+            Some(run) if run != state.key.run => return None,
+            run => run.is_some(),
+        };
+        let destination = match self.destination_number {
+            Some(destination) if Some(destination) != state.destination_number => return None,
+            destination => destination.is_some(),
+        };
+        Some((run, destination))
+    }
+}
+
+/// Looks up the [`GtfsMapping`] of vehicles
+#[derive(Debug, Clone, Default)]
+pub struct GtfsMapper {
+    mappings: HashMap<(i64, i32), Vec<GtfsMapping>>,
+}
+
+impl GtfsMapper {
+    /// Creates a mapper from the given mappings
+    pub fn new(mappings: impl IntoIterator<Item = GtfsMapping>) -> Self {
+        let mut mapper = GtfsMapper::default();
+        for mapping in mappings {
+            mapper
+                .mappings
+                .entry((mapping.region, mapping.line))
+                .or_default()
+                .push(mapping);
+        }
+        mapper
+    }
+
+    /// Loads all mappings from the `gtfs_mappings` table
+    pub fn from_postgres(database_connection: &mut PgConnection) -> diesel::QueryResult<Self> {
+        let mappings: Vec<GtfsMapping> = gtfs_mappings::table.load(database_connection)?;
+        Ok(Self::new(mappings))
+    }
+
+    /// Returns the most specific mapping of the vehicle
+    pub fn lookup(&self, state: &VehicleState) -> Option<&GtfsMapping> {
+        self.mappings
+            .get(&(state.key.region, state.key.line))?
+            .iter()
+            .filter_map(|mapping| Some((mapping.specificity(state)?, mapping)))
+            .max_by_key(|(specificity, _)| *specificity)
+            .map(|(_, mapping)| mapping)
+    }
+}
+
+/// Feed entity id of the vehicle, unique as long as the vehicle is tracked
+pub fn entity_id(state: &VehicleState) -> String {
+    format!("{}-{}-{}", state.key.region, state.key.line, state.key.run)
+}
+
+/// Partial trip descriptor with the route and direction of the mapping
+pub fn trip_descriptor(mapping: &GtfsMapping) -> TripDescriptor {
+    TripDescriptor {
+        route_id: Some(mapping.route_id.clone()),
+        direction_id: mapping.direction_id.map(|direction| direction as u32),
+        ..Default::default()
+    }
+}
+
+/// Vehicle descriptor with the entity id and line and run as label
+pub fn vehicle_descriptor(state: &VehicleState) -> VehicleDescriptor {
+    VehicleDescriptor {
+        id: Some(entity_id(state)),
+        label: Some(format!("{}/{}", state.key.line, state.key.run)),
+        license_plate: None,
+    }
+}
+
+/// Position of the vehicle, the timestamp is in seconds
+pub fn vehicle_position(state: &VehicleState, mapping: &GtfsMapping) -> VehiclePosition {
+    VehiclePosition {
+        trip: Some(trip_descriptor(mapping)),
+        vehicle: Some(vehicle_descriptor(state)),
+        position: Some(Position {
+            latitude: state.lat as f32,
+            longitude: state.lon as f32,
+            bearing: state.heading.map(|heading| heading as f32),
+            odometer: None,
+            speed: state.speed.map(|speed| speed as f32),
+        }),
+        timestamp: Some(state.last_update / 1000),
+        ..Default::default()
+    }
+}
+
+/// Builds a full dataset feed of all mapped vehicles, `timestamp` is the unix time in seconds.
+/// Every vehicle is a single entity with its position.
+pub fn feed_message<'a>(
+    states: impl IntoIterator<Item = &'a VehicleState>,
+    mapper: &GtfsMapper,
+    timestamp: u64,
+) -> FeedMessage {
+    let mut entity: Vec<FeedEntity> = states
+        .into_iter()
+        .filter_map(|state| {
+            let mapping = mapper.lookup(state)?;
+            Some(FeedEntity {
+                id: entity_id(state),
+                is_deleted: None,
+                trip_update: None,
+                vehicle: Some(vehicle_position(state, mapping)),
+            })
+        })
+        .collect();
+    entity.sort_by(|a, b| a.id.cmp(&b.id));
+
+    FeedMessage {
+        header: FeedHeader {
+            gtfs_realtime_version: GTFS_REALTIME_VERSION.to_string(),
+            incrementality: Some(feed_header::Incrementality::FullDataset as i32),
+            timestamp: Some(timestamp),
+        },
+        entity,
+    }
+}
+
+/// Encodes the feed as protobuf, ready to be served as `application/x-protobuf`
+pub fn encode_feed(feed: &FeedMessage) -> Vec<u8> {
+    feed.encode_to_vec()
+}
@@ -0,0 +1,94 @@
+use super::*;
+use crate::locations::waypoint::{WayPointType, Waypoint};
+use crate::tracking::VehicleTracker;
+
+fn waypoint(run: i32, destination_number: Option<i32>) -> Waypoint {
+    Waypoint {
+        id: 0,
+        source: WayPointType::R09Telegram,
+        time: 1_683_000_000_000,
+        region: 0,
+        lat: 51.05,
+        lon: 13.74,
+        line: 11,
+        run,
+        delayed: Some(2.0),
+        r09_reporting_point: Some(1234),
+        r09_destination_number: destination_number,
+    }
+}
+
+fn mapping(id: i64, run: Option<i32>, destination_number: Option<i32>) -> GtfsMapping {
+    GtfsMapping {
+        id,
+        region: 0,
+        line: 11,
+        run,
+        destination_number,
+        route_id: format!("route-{id}"),
+        direction_id: Some(1),
+    }
+}
+
+#[test]
+fn test_gtfs_rt_feed() {
+    let mut tracker = VehicleTracker::default();
+    tracker.update(&waypoint(4, Some(7)));
+    tracker.update(&waypoint(5, Some(8)));
+    tracker.update(&waypoint(6, None));
+    let mut unmapped = waypoint(1, None);
+    unmapped.line = 12;
+    tracker.update(&unmapped);
+
+    let mapper = GtfsMapper::new(vec![
+        mapping(1, None, None),
+        mapping(2, None, Some(7)),
+        mapping(3, Some(4), None),
+        mapping(4, Some(5), Some(9)),
+    ]);
+
+    let feed = feed_message(tracker.vehicles(), &mapper, 1_683_000_060);
+    assert_eq!(feed.header.gtfs_realtime_version, GTFS_REALTIME_VERSION);
+    let ids: Vec<&str> = feed.entity.iter().map(|e| e.id.as_str()).collect();
+    assert_eq!(ids, vec!["0-11-4", "0-11-5", "0-11-6"]);
+
+    // the run is more specific than the destination number
+    let first = &feed.entity[0];
+    let vehicle = first.vehicle.as_ref().unwrap();
+    assert_eq!(
+        vehicle.trip.as_ref().unwrap().route_id.as_deref(),
+        Some("route-3")
+    );
+    assert_eq!(vehicle.timestamp, Some(1_683_000_000));
+
+    // the run matches, but the destination number doesn't, so the line mapping is used
+    let second = &feed.entity[1];
+    assert_eq!(
+        second
+            .vehicle
+            .as_ref()
+            .unwrap()
+            .trip
+            .as_ref()
+            .unwrap()
+            .route_id,
+        Some("route-1".to_string())
+    );
+
+    let encoded = encode_feed(&feed);
+    let decoded = FeedMessage::decode(encoded.as_slice()).unwrap();
+    assert_eq!(decoded, feed);
+
+    // every entity carries a vehicle position on a route, trip updates would need a trip and a
+    // stop time update
+    for entity in &decoded.entity {
+        let vehicle = entity.vehicle.as_ref().unwrap();
+        assert!(vehicle.position.is_some());
+        let trip = vehicle.trip.as_ref().unwrap();
+        assert!(trip.route_id.is_some() && trip.trip_id.is_none());
+        assert!(entity
+            .trip_update
+            .as_ref()
+            .is_none_or(|update| !update.stop_time_update.is_empty()));
+    }
+}
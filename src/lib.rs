@@ -61,6 +61,29 @@ pub mod time_serializer {
     {
         DateTime::<Utc>::deserialize(deserializer).map(|x| x.naive_utc())
     }
+
+    /// same as the parent module for optional timestamps
+    pub mod option {
+        use chrono::{DateTime, NaiveDateTime, Utc};
+        use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+        /// serializes the NaiveDateTime as DateTime<Utc> if present
+        pub fn serialize<S>(time: &Option<NaiveDateTime>, serializer: S) -> Result<S::Ok, S::Error>
+        where
+            S: Serializer,
+        {
+            time.map(|time| DateTime::<Utc>::from_naive_utc_and_offset(time, Utc))
+                .serialize(serializer)
+        }
+
+        /// deserializes an optional DateTime<Utc> into a NaiveDateTime
+        pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<NaiveDateTime>, D::Error>
+        where
+            D: Deserializer<'de>,
+        {
+            Option::<DateTime<Utc>>::deserialize(deserializer).map(|x| x.map(|x| x.naive_utc()))
+        }
+    }
 }
 
 ///
@@ -118,6 +141,20 @@ pub mod trekkie;
 #[cfg(feature = "statistics")]
 pub mod statistics;
 
+///
+/// This module keeps track of the live state of vehicles, which is derived from the stream of
+/// waypoints.
+///
+#[cfg(feature = "tracking")]
+pub mod tracking;
+
+///
+/// This module turns the live state of vehicles into GTFS-Realtime feeds, which can be consumed by
+/// journey planners.
+///
+#[cfg(feature = "gtfs_rt")]
+pub mod gtfs_rt;
+
 ///
 /// This module exports grpc definitions for services and structs that are used to communicate
 /// between services.
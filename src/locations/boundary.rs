@@ -0,0 +1,267 @@
+//! This module checks positions against the optional boundary of a region, a GeoJSON `Polygon`
+//! or `MultiPolygon` [`Geometry`] stored in the `boundary` column of `regions`. It is used to
+//! check that stations, GPS points and estimated transmission locations actually fall inside the
+//! region they claim (see [`BoundaryViolations`]) and to find the region of imported trekkie
+//! runs (see [`assign_region`]).
+
+use super::geojson::{Geometry, Position};
+use super::projection::{Coordinate, Projectable};
+use super::region::Region;
+use super::TransmissionLocation;
+use crate::locations::gps::GpsPoint;
+use crate::management::Station;
+use crate::schema::*;
+
+use diesel::{ExpressionMethods, PgConnection, QueryDsl, RunQueryDsl};
+use uuid::Uuid;
+
+/// Reasons a boundary is rejected by [`Geometry::validate_boundary`]. `polygon` and `ring` are
+/// the indices of the offending ring.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BoundaryError {
+    /// the geometry is neither a `Polygon` nor a `MultiPolygon`
+    NotPolygonal,
+    /// the boundary contains no polygon or a polygon without rings
+    Empty,
+    /// a linear ring has less than four positions
+    RingTooShort {
+        /// index of the polygon
+        polygon: usize,
+        /// index of the ring inside the polygon
+        ring: usize,
+    },
+    /// first and last position of a linear ring differ
+    RingNotClosed {
+        /// index of the polygon
+        polygon: usize,
+        /// index of the ring inside the polygon
+        ring: usize,
+    },
+    /// a position has less than two values or is out of range
+    InvalidPosition {
+        /// index of the polygon
+        polygon: usize,
+        /// index of the ring inside the polygon
+        ring: usize,
+        /// index of the position inside the ring
+        position: usize,
+    },
+}
+
+impl Geometry {
+    /// Iterates over all polygons, each given as list of linear rings. Geometries other than
+    /// `Polygon` and `MultiPolygon` have none.
+    pub fn polygons(&self) -> impl Iterator<Item = &[Vec<Position>]> {
+        let polygons: Vec<&[Vec<Position>]> = match self {
+            Geometry::Polygon { coordinates } => vec![coordinates.as_slice()],
+            Geometry::MultiPolygon { coordinates } => coordinates
+                .iter()
+                .map(|polygon| polygon.as_slice())
+                .collect(),
+            _ => Vec::new(),
+        };
+        polygons.into_iter()
+    }
+
+    /// Checks that the geometry can be used as boundary: it is a `Polygon` or `MultiPolygon`,
+    /// every polygon has an exterior ring and all rings are closed and made of valid positions
+    pub fn validate_boundary(&self) -> Result<(), BoundaryError> {
+        if !matches!(
+            self,
+            Geometry::Polygon { .. } | Geometry::MultiPolygon { .. }
+        ) {
+            return Err(BoundaryError::NotPolygonal);
+        }
+        let mut polygons = self.polygons().peekable();
+        if polygons.peek().is_none() {
+            return Err(BoundaryError::Empty);
+        }
+
+        for (polygon, rings) in polygons.enumerate() {
+            if rings.is_empty() {
+                return Err(BoundaryError::Empty);
+            }
+            for (ring, positions) in rings.iter().enumerate() {
+                if let Some(position) = positions.iter().position(|p| {
+                    p.len() < 2
+                        || !(-180.0..=180.0).contains(&p[0])
+                        || !(-90.0..=90.0).contains(&p[1])
+                }) {
+                    return Err(BoundaryError::InvalidPosition {
+                        polygon,
+                        ring,
+                        position,
+                    });
+                }
+                if positions.len() < 4 {
+                    return Err(BoundaryError::RingTooShort { polygon, ring });
+                }
+                if positions.first().map(|p| &p[..2]) != positions.last().map(|p| &p[..2]) {
+                    return Err(BoundaryError::RingNotClosed { polygon, ring });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns true if the position lies inside the exterior ring and outside all holes of any
+    /// polygon. Positions exactly on an edge may go either way, geometries without polygons
+    /// contain nothing.
+    pub fn contains(&self, position: &impl Projectable) -> bool {
+        let coordinate = position.coordinate();
+        self.polygons().any(|rings| match rings.split_first() {
+            Some((exterior, holes)) => {
+                ring_contains(exterior, &coordinate)
+                    && !holes.iter().any(|hole| ring_contains(hole, &coordinate))
+            }
+            None => false,
+        })
+    }
+
+    /// South-west and north-east corner of the bounding box of all exterior rings
+    pub fn bbox(&self) -> Option<(Coordinate, Coordinate)> {
+        self.polygons()
+            .filter_map(|rings| rings.first())
+            .flatten()
+            .filter(|position| position.len() >= 2)
+            .fold(None, |bbox, position| {
+                let (lon, lat) = (position[0], position[1]);
+                Some(match bbox {
+                    None => (Coordinate::new(lat, lon), Coordinate::new(lat, lon)),
+                    Some((south_west, north_east)) => (
+                        Coordinate::new(south_west.lat.min(lat), south_west.lon.min(lon)),
+                        Coordinate::new(north_east.lat.max(lat), north_east.lon.max(lon)),
+                    ),
+                })
+            })
+    }
+}
+
+/// Even-odd ray casting, longitude is used as x and latitude as y
+fn ring_contains(ring: &[Position], coordinate: &Coordinate) -> bool {
+    let (x, y) = (coordinate.lon, coordinate.lat);
+    let mut inside = false;
+    for edge in ring.windows(2) {
+        let (a, b) = (&edge[0], &edge[1]);
+        if a.len() < 2 || b.len() < 2 {
+            continue;
+        }
+        let (ax, ay, bx, by) = (a[0], a[1], b[0], b[1]);
+        if (ay > y) != (by > y) && x < ax + (y - ay) * (bx - ax) / (by - ay) {
+            inside = !inside;
+        }
+    }
+    inside
+}
+
+impl Region {
+    /// Returns true if the position lies inside the boundary of the region. Regions without
+    /// boundary contain every position.
+    pub fn contains(&self, position: &impl Projectable) -> bool {
+        self.boundary
+            .as_ref()
+            .is_none_or(|boundary| boundary.contains(position))
+    }
+}
+
+/// Finds the region the positions were recorded in: the active region whose boundary contains
+/// more than half of the positions. Regions without boundary are never assigned.
+pub fn assign_region<'a, T: Projectable>(
+    regions: &'a [Region],
+    positions: &[T],
+) -> Option<&'a Region> {
+    regions
+        .iter()
+        .filter(|region| !region.deactivated)
+        .filter_map(|region| {
+            let boundary = region.boundary.as_ref()?;
+            let inside = positions
+                .iter()
+                .filter(|position| boundary.contains(*position))
+                .count();
+            Some((region, inside))
+        })
+        .filter(|(_, inside)| 2 * inside > positions.len())
+        .max_by_key(|(_, inside)| *inside)
+        .map(|(region, _)| region)
+}
+
+/// Everything that claims to belong to a region but lies outside its boundary
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BoundaryViolations {
+    /// stations of the region outside the boundary
+    pub stations: Vec<Uuid>,
+    /// transmission locations of the region outside the boundary
+    pub transmission_locations: Vec<i64>,
+    /// points of trekkie runs in the region outside the boundary
+    pub gps_points: Vec<i64>,
+}
+
+impl BoundaryViolations {
+    /// Checks the given stations, transmission locations and GPS points against the boundary of
+    /// the region. Stations and transmission locations of other regions are ignored, the GPS
+    /// points are expected to belong to trekkie runs of the region.
+    pub fn check(
+        region: &Region,
+        stations: &[Station],
+        transmission_locations: &[TransmissionLocation],
+        gps_points: &[GpsPoint],
+    ) -> Self {
+        BoundaryViolations {
+            stations: stations
+                .iter()
+                .filter(|station| station.region == region.id && !region.contains(*station))
+                .map(|station| station.id)
+                .collect(),
+            transmission_locations: transmission_locations
+                .iter()
+                .filter(|location| location.region == region.id && !region.contains(*location))
+                .map(|location| location.id)
+                .collect(),
+            gps_points: gps_points
+                .iter()
+                .filter(|point| !region.contains(*point))
+                .map(|point| point.id)
+                .collect(),
+        }
+    }
+
+    /// Loads the region with its stations, transmission locations and the GPS points of its
+    /// trekkie runs and checks them, see [`BoundaryViolations::check`]
+    pub fn from_postgres(
+        database_connection: &mut PgConnection,
+        region_id: i64,
+    ) -> Result<Self, diesel::result::Error> {
+        let region: Region = regions::table
+            .filter(regions::id.eq(region_id))
+            .first(database_connection)?;
+        if region.boundary.is_none() {
+            return Ok(Self::default());
+        }
+
+        let stations: Vec<Station> = stations::table
+            .filter(stations::region.eq(region_id))
+            .load(database_connection)?;
+        let locations: Vec<TransmissionLocation> = r09_transmission_locations::table
+            .filter(r09_transmission_locations::region.eq(region_id))
+            .load(database_connection)?;
+        let gps_points: Vec<GpsPoint> = gps_points::table
+            .filter(
+                gps_points::trekkie_run.eq_any(
+                    trekkie_runs::table
+                        .filter(trekkie_runs::region.eq(region_id))
+                        .select(trekkie_runs::id),
+                ),
+            )
+            .load(database_connection)?;
+
+        Ok(Self::check(&region, &stations, &locations, &gps_points))
+    }
+
+    /// Returns true if nothing lies outside the boundary
+    pub fn is_empty(&self) -> bool {
+        self.stations.is_empty()
+            && self.transmission_locations.is_empty()
+            && self.gps_points.is_empty()
+    }
+}
@@ -0,0 +1,476 @@
+//! This module holds the estimators that infer a single transmission location from many raw
+//! per-run transmission locations (see [`TransmissionLocationRaw`]). Every raw location is
+//! weighted by the accuracy of the GPS fix it originates from, so precise measurements pull the
+//! estimate harder than sloppy ones. All estimators implement [`LocationEstimator`] and return
+//! the position together with an uncertainty radius.
+
+use super::gps::GpsPoint;
+use super::projection::{Coordinate, Projectable};
+use super::{
+    DistanceFrom, TransmissionLocaionError, TransmissionLocationRaw, SANE_INTERPOLATION_DISTANCE,
+};
+use crate::schema::gps_points;
+
+use diesel::{ExpressionMethods, PgConnection, QueryDsl, RunQueryDsl};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// Accuracy in meters assumed for raw locations whose GPS fix carried no accuracy
+pub const DEFAULT_GPS_ACCURACY: f64 = 10_f64;
+
+/// Parameters shared by all [`LocationEstimator`]s, and the minimal amount of evidence required
+/// before a transmission location is inferred at all
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EstimatorConfig {
+    /// distance in meters from the estimate at which raw locations are considered outliers
+    pub outlier_distance: f64,
+    /// minimal number of raw locations
+    pub min_samples: usize,
+    /// minimal number of distinct trekkie runs the raw locations originate from
+    pub min_trekkie_runs: usize,
+    /// minimal number of distinct users who recorded these trekkie runs
+    pub min_run_owners: usize,
+}
+
+impl Default for EstimatorConfig {
+    fn default() -> Self {
+        EstimatorConfig {
+            outlier_distance: SANE_INTERPOLATION_DISTANCE,
+            min_samples: 1,
+            min_trekkie_runs: 1,
+            min_run_owners: 1,
+        }
+    }
+}
+
+impl EstimatorConfig {
+    /// Returns true if the samples come from enough raw locations, trekkie runs and run owners
+    pub fn is_satisfied_by(&self, samples: &[WeightedLocation]) -> bool {
+        let runs: HashSet<uuid::Uuid> = samples.iter().map(|s| s.location.trekkie_run).collect();
+        let owners: HashSet<uuid::Uuid> = samples.iter().map(|s| s.location.run_owner).collect();
+
+        samples.len() >= self.min_samples
+            && runs.len() >= self.min_trekkie_runs
+            && owners.len() >= self.min_run_owners
+    }
+}
+
+/// Raw transmission location together with the accuracy of the GPS fix it was inferred from
+#[derive(Debug, Clone)]
+pub struct WeightedLocation {
+    /// the raw location
+    pub location: TransmissionLocationRaw,
+    /// accuracy of the originating GPS fix in meters
+    pub accuracy: Option<f64>,
+}
+
+impl From<TransmissionLocationRaw> for WeightedLocation {
+    fn from(location: TransmissionLocationRaw) -> Self {
+        WeightedLocation {
+            location,
+            accuracy: None,
+        }
+    }
+}
+
+impl WeightedLocation {
+    /// Attaches the accuracy of the GPS point of the originating run that is closest to the raw
+    /// location. `track` should contain the gps points of `location.trekkie_run`.
+    pub fn from_track(location: TransmissionLocationRaw, track: &[GpsPoint]) -> Self {
+        let accuracy = track
+            .iter()
+            .filter(|point| point.trekkie_run == location.trekkie_run)
+            .min_by(|a, b| {
+                let a = location.distance_from((a.lat, a.lon));
+                let b = location.distance_from((b.lat, b.lon));
+                a.total_cmp(&b)
+            })
+            .and_then(|point| point.accuracy);
+
+        WeightedLocation { location, accuracy }
+    }
+
+    /// Loads the gps points of the originating trekkie runs and attaches their accuracy to the
+    /// raw locations, see [`WeightedLocation::from_track`].
+    pub fn from_postgres(
+        database_connection: &mut PgConnection,
+        raw: Vec<TransmissionLocationRaw>,
+    ) -> Result<Vec<Self>, diesel::result::Error> {
+        let mut runs: Vec<uuid::Uuid> = raw.iter().map(|l| l.trekkie_run).collect();
+        runs.sort();
+        runs.dedup();
+
+        let mut tracks: HashMap<uuid::Uuid, Vec<GpsPoint>> = HashMap::new();
+        for point in gps_points::table
+            .filter(gps_points::trekkie_run.eq_any(runs))
+            .load::<GpsPoint>(database_connection)?
+        {
+            tracks.entry(point.trekkie_run).or_default().push(point);
+        }
+
+        Ok(raw
+            .into_iter()
+            .map(|location| {
+                let track = tracks
+                    .get(&location.trekkie_run)
+                    .map_or(&[][..], |t| t.as_slice());
+                Self::from_track(location, track)
+            })
+            .collect())
+    }
+
+    /// Weight of the sample, inverse of the squared accuracy
+    pub fn weight(&self) -> f64 {
+        1_f64 / self.accuracy().powi(2)
+    }
+
+    /// Accuracy in meters, falls back to [`DEFAULT_GPS_ACCURACY`] and is at least one meter
+    pub fn accuracy(&self) -> f64 {
+        self.accuracy
+            .filter(|a| a.is_finite() && *a > 0_f64)
+            .unwrap_or(DEFAULT_GPS_ACCURACY)
+            .max(1_f64)
+    }
+
+    fn position(&self) -> (f64, f64) {
+        (self.location.lat, self.location.lon)
+    }
+}
+
+/// Result of a [`LocationEstimator`]
+#[derive(Debug, Clone)]
+pub struct LocationEstimate {
+    /// estimated latitude
+    pub lat: f64,
+    /// estimated longitude
+    pub lon: f64,
+    /// radius in meters of the 1σ confidence circle around the estimate. Combines the spread of
+    /// the samples and their GPS accuracy, and shrinks with the number of samples.
+    pub uncertainty: f64,
+    /// samples the estimate was calculated from, after outliers were removed
+    pub inliers: Vec<WeightedLocation>,
+}
+
+impl LocationEstimate {
+    /// Builds an estimate for the given center from the samples that were kept
+    fn from_inliers(center: (f64, f64), inliers: &[&WeightedLocation]) -> Self {
+        let total_weight: f64 = inliers.iter().map(|s| s.weight()).sum();
+        let squared_weights: f64 = inliers.iter().map(|s| s.weight().powi(2)).sum();
+        let effective_samples = total_weight.powi(2) / squared_weights;
+
+        let spread: f64 = inliers
+            .iter()
+            .map(|s| {
+                s.weight() * (s.position().distance_from(center).powi(2) + s.accuracy().powi(2))
+            })
+            .sum::<f64>()
+            / total_weight;
+
+        LocationEstimate {
+            lat: center.0,
+            lon: center.1,
+            uncertainty: (spread / effective_samples).sqrt(),
+            inliers: inliers.iter().map(|s| (*s).clone()).collect(),
+        }
+    }
+}
+
+/// An algorithm that infers a single transmission location from weighted raw locations
+pub trait LocationEstimator {
+    /// Estimates the transmission location. `samples` is never empty, and all samples belong to
+    /// the same region and reporting point. Samples further than
+    /// [`EstimatorConfig::outlier_distance`] from the estimate must not count as inliers.
+    fn estimate(
+        &self,
+        samples: &[WeightedLocation],
+        config: &EstimatorConfig,
+    ) -> Result<LocationEstimate, TransmissionLocaionError>;
+}
+
+/// Weighted arithmetic mean of the positions
+fn weighted_mean(samples: &[&WeightedLocation]) -> (f64, f64) {
+    let total_weight: f64 = samples.iter().map(|s| s.weight()).sum();
+    let lat = samples
+        .iter()
+        .map(|s| s.weight() * s.location.lat)
+        .sum::<f64>()
+        / total_weight;
+    let lon = samples
+        .iter()
+        .map(|s| s.weight() * s.location.lon)
+        .sum::<f64>()
+        / total_weight;
+    (lat, lon)
+}
+
+/// Weighted geometric median (Weiszfeld's algorithm). The point minimizing the weighted sum of
+/// distances is not dragged away by a few wild outliers, unlike the arithmetic mean. Samples
+/// further away than [`EstimatorConfig::outlier_distance`] from the median are not counted as
+/// inliers.
+#[derive(Debug, Clone, Copy)]
+pub struct GeometricMedian {
+    /// maximum number of Weiszfeld iterations
+    pub max_iterations: usize,
+    /// the iteration stops once the median moves less than this many meters
+    pub tolerance: f64,
+}
+
+impl Default for GeometricMedian {
+    fn default() -> Self {
+        GeometricMedian {
+            max_iterations: 100,
+            tolerance: 0.01,
+        }
+    }
+}
+
+impl GeometricMedian {
+    /// Computes the weighted geometric median of the samples
+    pub fn median(&self, samples: &[&WeightedLocation]) -> (f64, f64) {
+        let (lat, lon) = weighted_mean(samples);
+        let origin = Coordinate::new(lat, lon);
+        let points: Vec<((f64, f64), f64)> = samples
+            .iter()
+            .map(|s| (s.location.coordinate().to_local(&origin), s.weight()))
+            .collect();
+
+        let mut current = (0_f64, 0_f64);
+        for _ in 0..self.max_iterations {
+            let (mut x, mut y, mut denominator) = (0_f64, 0_f64, 0_f64);
+            for ((px, py), weight) in &points {
+                // clamp the distance to avoid dividing by zero when hitting a sample exactly
+                let distance = ((px - current.0).powi(2) + (py - current.1).powi(2))
+                    .sqrt()
+                    .max(1e-6);
+                x += weight * px / distance;
+                y += weight * py / distance;
+                denominator += weight / distance;
+            }
+
+            let next = (x / denominator, y / denominator);
+            let moved = ((next.0 - current.0).powi(2) + (next.1 - current.1).powi(2)).sqrt();
+            current = next;
+            if moved < self.tolerance {
+                break;
+            }
+        }
+
+        let median = Coordinate::from_local(current, &origin);
+        (median.lat, median.lon)
+    }
+}
+
+impl LocationEstimator for GeometricMedian {
+    fn estimate(
+        &self,
+        samples: &[WeightedLocation],
+        config: &EstimatorConfig,
+    ) -> Result<LocationEstimate, TransmissionLocaionError> {
+        if samples.is_empty() {
+            return Err(TransmissionLocaionError::EmptyInput);
+        }
+
+        let all: Vec<&WeightedLocation> = samples.iter().collect();
+        let center = self.median(&all);
+        let inliers: Vec<&WeightedLocation> = all
+            .into_iter()
+            .filter(|s| s.position().distance_from(center) <= config.outlier_distance)
+            .collect();
+
+        if inliers.is_empty() {
+            return Err(TransmissionLocaionError::NoMatches);
+        }
+
+        Ok(LocationEstimate::from_inliers(center, &inliers))
+    }
+}
+
+/// Iterative sigma-clipping. Starting at the component-wise median, samples further away than
+/// `sigma` standard deviations (or [`EstimatorConfig::outlier_distance`]) from the current center
+/// are dropped and the weighted mean of the rest becomes the new center, until no more samples
+/// are dropped. Samples within their own GPS accuracy of the center are always kept.
+#[derive(Debug, Clone, Copy)]
+pub struct SigmaClipping {
+    /// number of standard deviations at which samples are clipped
+    pub sigma: f64,
+    /// maximum number of clipping iterations
+    pub max_iterations: usize,
+}
+
+impl Default for SigmaClipping {
+    fn default() -> Self {
+        SigmaClipping {
+            sigma: 2_f64,
+            max_iterations: 10,
+        }
+    }
+}
+
+impl LocationEstimator for SigmaClipping {
+    fn estimate(
+        &self,
+        samples: &[WeightedLocation],
+        config: &EstimatorConfig,
+    ) -> Result<LocationEstimate, TransmissionLocaionError> {
+        if samples.is_empty() {
+            return Err(TransmissionLocaionError::EmptyInput);
+        }
+
+        let component_median = |values: &mut Vec<f64>| {
+            values.sort_by(|a, b| a.total_cmp(b));
+            values[values.len() / 2]
+        };
+        let mut center = (
+            component_median(&mut samples.iter().map(|s| s.location.lat).collect()),
+            component_median(&mut samples.iter().map(|s| s.location.lon).collect()),
+        );
+        let mut inliers: Vec<&WeightedLocation> = samples.iter().collect();
+
+        for _ in 0..self.max_iterations {
+            let total_weight: f64 = inliers.iter().map(|s| s.weight()).sum();
+            let deviation = (inliers
+                .iter()
+                .map(|s| s.weight() * s.position().distance_from(center).powi(2))
+                .sum::<f64>()
+                / total_weight)
+                .sqrt();
+            // never clip a sample that is within its own GPS accuracy
+            let kept: Vec<&WeightedLocation> = inliers
+                .iter()
+                .copied()
+                .filter(|s| {
+                    let limit = (self.sigma * deviation)
+                        .max(s.accuracy())
+                        .min(config.outlier_distance);
+                    s.position().distance_from(center) <= limit
+                })
+                .collect();
+            if kept.is_empty() {
+                return Err(TransmissionLocaionError::NoMatches);
+            }
+
+            let converged = kept.len() == inliers.len();
+            center = weighted_mean(&kept);
+            inliers = kept;
+            if converged {
+                break;
+            }
+        }
+
+        Ok(LocationEstimate::from_inliers(center, &inliers))
+    }
+}
+
+/// DBSCAN clustering. Samples with at least `min_samples` neighbours within `epsilon` meters form
+/// clusters, the cluster with the highest total weight wins and its weighted mean is the
+/// estimate. Ties go to the cluster with more samples, then to the one found first. Members of a chained cluster further than [`EstimatorConfig::outlier_distance`] from
+/// the mean are dropped. Useful if a reporting point is regularly mixed up with a second location.
+#[derive(Debug, Clone, Copy)]
+pub struct Dbscan {
+    /// neighbourhood radius in meters
+    pub epsilon: f64,
+    /// minimal number of samples in the neighbourhood (including the sample itself) of a core
+    /// sample
+    pub min_samples: usize,
+}
+
+impl Default for Dbscan {
+    fn default() -> Self {
+        Dbscan {
+            epsilon: 20_f64,
+            min_samples: 2,
+        }
+    }
+}
+
+impl Dbscan {
+    /// Assigns every sample a cluster index, [`None`] marks noise
+    pub fn cluster(&self, samples: &[WeightedLocation]) -> Vec<Option<usize>> {
+        let neighbours: Vec<Vec<usize>> = samples
+            .iter()
+            .map(|a| {
+                samples
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, b)| a.position().distance_from(b.position()) <= self.epsilon)
+                    .map(|(j, _)| j)
+                    .collect()
+            })
+            .collect();
+
+        let mut labels: Vec<Option<usize>> = vec![None; samples.len()];
+        let mut visited = vec![false; samples.len()];
+        let mut cluster = 0;
+
+        for i in 0..samples.len() {
+            if visited[i] || neighbours[i].len() < self.min_samples {
+                continue;
+            }
+
+            let mut queue = vec![i];
+            while let Some(j) = queue.pop() {
+                if labels[j].is_none() {
+                    labels[j] = Some(cluster);
+                }
+                if visited[j] {
+                    continue;
+                }
+                visited[j] = true;
+                if neighbours[j].len() >= self.min_samples {
+                    queue.extend(neighbours[j].iter().filter(|k| !visited[**k]));
+                }
+            }
+            cluster += 1;
+        }
+
+        labels
+    }
+}
+
+impl LocationEstimator for Dbscan {
+    fn estimate(
+        &self,
+        samples: &[WeightedLocation],
+        config: &EstimatorConfig,
+    ) -> Result<LocationEstimate, TransmissionLocaionError> {
+        if samples.is_empty() {
+            return Err(TransmissionLocaionError::EmptyInput);
+        }
+
+        let labels = self.cluster(samples);
+        let mut clusters: BTreeMap<usize, Vec<&WeightedLocation>> = BTreeMap::new();
+        for (sample, label) in samples.iter().zip(labels) {
+            if let Some(label) = label {
+                clusters.entry(label).or_default().push(sample);
+            }
+        }
+
+        let (_, _, best) = clusters
+            .into_iter()
+            .map(|(label, members)| {
+                let weight: f64 = members.iter().map(|s| s.weight()).sum();
+                (label, weight, members)
+            })
+            .max_by(|(label_a, a, members_a), (label_b, b, members_b)| {
+                a.total_cmp(b)
+                    .then(members_a.len().cmp(&members_b.len()))
+                    .then(label_b.cmp(label_a))
+            })
+            .ok_or(TransmissionLocaionError::NoMatches)?;
+
+        let center = weighted_mean(&best);
+        let inliers: Vec<&WeightedLocation> = best
+            .into_iter()
+            .filter(|s| s.position().distance_from(center) <= config.outlier_distance)
+            .collect();
+        if inliers.is_empty() {
+            return Err(TransmissionLocaionError::NoMatches);
+        }
+
+        Ok(LocationEstimate::from_inliers(
+            weighted_mean(&inliers),
+            &inliers,
+        ))
+    }
+}
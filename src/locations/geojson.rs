@@ -0,0 +1,375 @@
+//! This module holds typed [GeoJSON](https://datatracker.ietf.org/doc/html/rfc7946) structures and
+//! the [`RegionFeatureCollection`], which exports everything known about a region (transmission
+//! locations, stations and learned reporting point sequences) as a standard `FeatureCollection`.
+//! The files can be opened in QGIS or JOSM, edited and parsed again, e.g. to promote manually
+//! corrected transmission locations to ground truth.
+
+use super::region::Region;
+use super::route::{LearnedRoute, RouteKey, SequencePoint};
+use super::{InsertTransmissionLocation, TransmissionLocation};
+use crate::management::Station;
+use crate::schema::*;
+
+use diesel::deserialize::{self, FromSql};
+use diesel::dsl::count_star;
+use diesel::pg::{Pg, PgValue};
+use diesel::serialize::{self, IsNull, Output, ToSql};
+use diesel::sql_types::Jsonb;
+use diesel::{AsExpression, ExpressionMethods, FromSqlRow, PgConnection, QueryDsl, RunQueryDsl};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::Write;
+use uuid::Uuid;
+
+/// GeoJSON position, longitude first, optionally followed by the elevation
+pub type Position = Vec<f64>;
+
+/// GeoJSON geometry, stored as JSONB e.g. in the `boundary` column of `regions`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, AsExpression, FromSqlRow)]
+#[diesel(sql_type = Jsonb)]
+#[serde(tag = "type")]
+pub enum Geometry {
+    /// single position
+    Point {
+        /// the position
+        coordinates: Position,
+    },
+    /// line through the positions
+    LineString {
+        /// the positions
+        coordinates: Vec<Position>,
+    },
+    /// polygon, the first ring is the exterior ring, all following rings are holes
+    Polygon {
+        /// linear rings, first and last position of each ring are the same
+        coordinates: Vec<Vec<Position>>,
+    },
+    /// several positions
+    MultiPoint {
+        /// the positions
+        coordinates: Vec<Position>,
+    },
+    /// several lines
+    MultiLineString {
+        /// the positions of each line
+        coordinates: Vec<Vec<Position>>,
+    },
+    /// several polygons, e.g. for regions with exclaves
+    MultiPolygon {
+        /// the linear rings of each polygon
+        coordinates: Vec<Vec<Vec<Position>>>,
+    },
+}
+
+impl Geometry {
+    /// Creates a point geometry from latitude and longitude
+    pub fn point(lat: f64, lon: f64) -> Self {
+        Geometry::Point {
+            coordinates: vec![lon, lat],
+        }
+    }
+
+    /// Returns latitude and longitude of point geometries. GIS editors often save single points
+    /// as `MultiPoint`, so a `MultiPoint` with exactly one position counts as point too.
+    pub fn as_point(&self) -> Option<(f64, f64)> {
+        let coordinates = match self {
+            Geometry::Point { coordinates } => coordinates,
+            Geometry::MultiPoint { coordinates } if coordinates.len() == 1 => &coordinates[0],
+            _ => return None,
+        };
+        (coordinates.len() >= 2).then(|| (coordinates[1], coordinates[0]))
+    }
+}
+
+impl FromSql<Jsonb, Pg> for Geometry {
+    fn from_sql(bytes: PgValue<'_>) -> deserialize::Result<Self> {
+        let value = <serde_json::Value as FromSql<Jsonb, Pg>>::from_sql(bytes)?;
+        Ok(serde_json::from_value(value)?)
+    }
+}
+
+impl ToSql<Jsonb, Pg> for Geometry {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> serialize::Result {
+        // jsonb version number
+        out.write_all(&[1])?;
+        serde_json::to_writer(out, self)?;
+        Ok(IsNull::No)
+    }
+}
+
+/// Marker for the `"type": "Feature"` member
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FeatureType {
+    /// the only valid value
+    #[default]
+    Feature,
+}
+
+/// Marker for the `"type": "FeatureCollection"` member
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FeatureCollectionType {
+    /// the only valid value
+    #[default]
+    FeatureCollection,
+}
+
+/// GeoJSON feature with typed properties
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Feature<P> {
+    /// always `"Feature"`
+    #[serde(rename = "type")]
+    pub kind: FeatureType,
+    /// geometry of the feature
+    pub geometry: Geometry,
+    /// properties of the feature
+    pub properties: P,
+}
+
+impl<P> Feature<P> {
+    /// Creates a feature
+    pub fn new(geometry: Geometry, properties: P) -> Self {
+        Feature {
+            kind: FeatureType::Feature,
+            geometry,
+            properties,
+        }
+    }
+}
+
+/// Properties of an exported [`TransmissionLocation`]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TransmissionLocationProperties {
+    /// primary key, [`None`] for locations added while editing
+    #[serde(default)]
+    pub id: Option<i64>,
+    /// region of the location
+    pub region: i64,
+    /// reporting point (*meldepunkt*) transmitted at this location
+    pub reporting_point: i32,
+    /// if the location was measured instead of inferred
+    pub ground_truth: bool,
+    /// number of raw locations the location was inferred from
+    #[serde(default)]
+    pub samples: Option<i64>,
+}
+
+/// Properties of an exported [`Station`]. Tokens and owners are never exported.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StationProperties {
+    /// station identifier
+    pub id: Uuid,
+    /// name of the station
+    pub name: String,
+    /// region of the station
+    pub region: i64,
+    /// if the station is approved to submit data
+    pub approved: bool,
+    /// elevation of the antenna
+    #[serde(default)]
+    pub elevation: Option<f64>,
+}
+
+/// Properties of a single branch of a [`LearnedRoute`]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SequenceProperties {
+    /// region of the route
+    pub region: i64,
+    /// line of the route
+    pub line: i32,
+    /// destination number of the route
+    pub destination_number: i32,
+    /// branch index, `0` is the main sequence
+    pub branch: i32,
+    /// reporting points of the branch in order, including those without known location
+    pub reporting_points: Vec<i32>,
+    /// share of trips that passed each reporting point
+    pub confidence: Vec<f64>,
+}
+
+/// Properties of the features in a [`RegionFeatureCollection`], distinguished by the `kind`
+/// property
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum RegionFeature {
+    /// a transmission location as point
+    TransmissionLocation(TransmissionLocationProperties),
+    /// a station as point
+    Station(StationProperties),
+    /// a branch of a reporting point sequence as line through the transmission locations
+    ReportingPointSequence(SequenceProperties),
+}
+
+/// GeoJSON `FeatureCollection` describing a region. The region itself is stored in the `region`
+/// foreign member.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RegionFeatureCollection {
+    /// always `"FeatureCollection"`
+    #[serde(rename = "type")]
+    pub kind: FeatureCollectionType,
+    /// the region
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub region: Option<Region>,
+    /// all features
+    pub features: Vec<Feature<RegionFeature>>,
+}
+
+impl RegionFeatureCollection {
+    /// Creates an empty collection
+    pub fn new(region: Option<Region>) -> Self {
+        RegionFeatureCollection {
+            kind: FeatureCollectionType::FeatureCollection,
+            region,
+            features: Vec::new(),
+        }
+    }
+
+    /// Adds transmission locations as points. `samples` maps reporting points to the number of
+    /// raw locations they were inferred from.
+    pub fn add_transmission_locations(
+        &mut self,
+        locations: &[TransmissionLocation],
+        samples: &HashMap<i32, i64>,
+    ) {
+        self.features.extend(locations.iter().map(|location| {
+            Feature::new(
+                Geometry::point(location.lat, location.lon),
+                RegionFeature::TransmissionLocation(TransmissionLocationProperties {
+                    id: Some(location.id),
+                    region: location.region,
+                    reporting_point: location.reporting_point,
+                    ground_truth: location.ground_truth,
+                    samples: samples.get(&location.reporting_point).copied(),
+                }),
+            )
+        }));
+    }
+
+    /// Adds stations as points. Stations that are not public or deactivated are left out.
+    pub fn add_stations(&mut self, stations: &[Station]) {
+        self.features.extend(
+            stations
+                .iter()
+                .filter(|station| station.public && !station.deactivated)
+                .map(|station| {
+                    Feature::new(
+                        Geometry::point(station.lat, station.lon),
+                        RegionFeature::Station(StationProperties {
+                            id: station.id,
+                            name: station.name.clone(),
+                            region: station.region,
+                            approved: station.approved,
+                            elevation: station.elevation,
+                        }),
+                    )
+                }),
+        );
+    }
+
+    /// Adds every branch of the routes as line through the transmission locations of its
+    /// reporting points. Branches with less than two located reporting points are left out.
+    pub fn add_sequences(&mut self, routes: &[LearnedRoute], locations: &[TransmissionLocation]) {
+        let positions: HashMap<(i64, i32), Position> = locations
+            .iter()
+            .map(|l| ((l.region, l.reporting_point), vec![l.lon, l.lat]))
+            .collect();
+
+        for route in routes {
+            for (branch, points) in route.branches.iter().enumerate() {
+                let coordinates: Vec<Position> = points
+                    .iter()
+                    .filter_map(|p| positions.get(&(route.key.region, p.reporting_point)))
+                    .cloned()
+                    .collect();
+                if coordinates.len() < 2 {
+                    continue;
+                }
+
+                self.features.push(Feature::new(
+                    Geometry::LineString { coordinates },
+                    RegionFeature::ReportingPointSequence(SequenceProperties {
+                        region: route.key.region,
+                        line: route.key.line,
+                        destination_number: route.key.destination_number,
+                        branch: branch as i32,
+                        reporting_points: points.iter().map(|p| p.reporting_point).collect(),
+                        confidence: points.iter().map(|p| p.confidence).collect(),
+                    }),
+                ));
+            }
+        }
+    }
+
+    /// Loads the region with its transmission locations, public stations and learned sequences
+    pub fn from_postgres(
+        database_connection: &mut PgConnection,
+        region_id: i64,
+    ) -> Result<Self, diesel::result::Error> {
+        let region: Region = regions::table
+            .filter(regions::id.eq(region_id))
+            .first(database_connection)?;
+
+        let locations: Vec<TransmissionLocation> = r09_transmission_locations::table
+            .filter(r09_transmission_locations::region.eq(region_id))
+            .load(database_connection)?;
+        let samples: HashMap<i32, i64> = r09_transmission_locations_raw::table
+            .filter(r09_transmission_locations_raw::region.eq(region_id))
+            .group_by(r09_transmission_locations_raw::reporting_point)
+            .select((
+                r09_transmission_locations_raw::reporting_point,
+                count_star(),
+            ))
+            .load::<(i32, i64)>(database_connection)?
+            .into_iter()
+            .collect();
+
+        let stations: Vec<Station> = stations::table
+            .filter(stations::region.eq(region_id))
+            .load(database_connection)?;
+
+        let mut rows: HashMap<RouteKey, Vec<SequencePoint>> = HashMap::new();
+        for row in r09_reporting_point_sequences::table
+            .filter(r09_reporting_point_sequences::region.eq(region_id))
+            .load::<SequencePoint>(database_connection)?
+        {
+            let key = RouteKey {
+                region: row.region,
+                line: row.line,
+                destination_number: row.destination_number,
+            };
+            rows.entry(key).or_default().push(row);
+        }
+        let mut routes: Vec<LearnedRoute> = rows
+            .into_iter()
+            .map(|(key, rows)| LearnedRoute::from_rows(key, rows))
+            .collect();
+        routes.sort_by_key(|route| (route.key.line, route.key.destination_number));
+
+        let mut collection = Self::new(Some(region));
+        collection.add_transmission_locations(&locations, &samples);
+        collection.add_stations(&stations);
+        collection.add_sequences(&routes, &locations);
+        Ok(collection)
+    }
+
+    /// Returns the transmission locations of the collection, e.g. after the file was edited. The
+    /// position is taken from the geometry, features without point geometry are left out.
+    pub fn transmission_locations(&self) -> Vec<InsertTransmissionLocation> {
+        self.features
+            .iter()
+            .filter_map(|feature| match &feature.properties {
+                RegionFeature::TransmissionLocation(properties) => {
+                    let (lat, lon) = feature.geometry.as_point()?;
+                    Some(InsertTransmissionLocation {
+                        id: properties.id,
+                        region: properties.region,
+                        reporting_point: properties.reporting_point,
+                        lat,
+                        lon,
+                        ground_truth: properties.ground_truth,
+                    })
+                }
+                _ => None,
+            })
+            .collect()
+    }
+}
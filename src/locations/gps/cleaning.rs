@@ -0,0 +1,488 @@
+//! This module cleans GPS tracks recorded by phones before they are used for correlation or map
+//! matching. The [`clean`] pipeline
+//!
+//! 1. drops points with poor or duplicate fixes,
+//! 2. drops speed spikes, i.e. jumps that are too fast or contradict the reported speed and
+//!    bearing of the device. A jump that the following point agrees with is kept instead, so a
+//!    wild first fix doesn't take the rest of the track with it,
+//! 3. smooths the positions with a constant velocity Kalman filter and Rauch-Tung-Striebel
+//!    smoother,
+//! 4. finds stop dwell periods and optionally drops the stationary drift within them,
+//! 5. simplifies the track with Douglas-Peucker.
+//!
+//! Every dropped point is reported together with the [`RemovalReason`].
+
+use super::GpsPoint;
+use crate::locations::projection::{Coordinate, Projectable};
+use crate::locations::DistanceFrom;
+
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+
+/// Parameters of the cleaning pipeline, every step can be disabled with [`None`]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CleaningConfig {
+    /// points with a horizontal accuracy worse than this many meters are dropped
+    pub max_accuracy: Option<f64>,
+    /// points that can only be reached faster than this many meters per second are dropped
+    pub max_speed: Option<f64>,
+    /// points are dropped if the speed needed to reach them exceeds the speed reported by the
+    /// device by more than this many meters per second
+    pub max_speed_deviation: Option<f64>,
+    /// points are dropped if the direction of movement deviates by more than this many degrees
+    /// from the bearing reported by the device
+    pub max_bearing_deviation: Option<f64>,
+    /// the bearing is only checked if the device reported at least this speed in meters per
+    /// second, as bearings are meaningless when standing still
+    pub min_bearing_speed: f64,
+    /// standard deviation of the acceleration in m/s² assumed by the Kalman filter
+    pub kalman_acceleration: Option<f64>,
+    /// accuracy in meters assumed for points without accuracy
+    pub default_accuracy: f64,
+    /// all points within this many meters of the first point of a stop belong to the stop
+    pub dwell_radius: f64,
+    /// minimal duration in milliseconds of a stop
+    pub min_dwell: i64,
+    /// only keep the first and last point of every stop
+    pub collapse_dwells: bool,
+    /// maximal distance in meters of dropped points from the simplified track
+    pub simplify_tolerance: Option<f64>,
+}
+
+impl Default for CleaningConfig {
+    fn default() -> Self {
+        CleaningConfig {
+            max_accuracy: Some(30_f64),
+            max_speed: Some(30_f64),
+            max_speed_deviation: Some(10_f64),
+            max_bearing_deviation: Some(90_f64),
+            min_bearing_speed: 3_f64,
+            kalman_acceleration: Some(1_f64),
+            default_accuracy: crate::locations::estimation::DEFAULT_GPS_ACCURACY,
+            dwell_radius: 15_f64,
+            min_dwell: 20 * 1000,
+            collapse_dwells: false,
+            simplify_tolerance: Some(2_f64),
+        }
+    }
+}
+
+/// Why a point was dropped
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum RemovalReason {
+    /// horizontal accuracy was worse than [`CleaningConfig::max_accuracy`]
+    PoorAccuracy,
+    /// another point with the same timestamp was kept
+    DuplicateTimestamp,
+    /// point could only be reached faster than [`CleaningConfig::max_speed`], or much faster than
+    /// the device reported
+    SpeedSpike,
+    /// direction of movement contradicts the bearing reported by the device
+    BearingMismatch,
+    /// point was inside a stop and [`CleaningConfig::collapse_dwells`] was set
+    StationaryDrift,
+    /// point was dropped by Douglas-Peucker
+    Simplified,
+}
+
+/// Single dropped point
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Removal {
+    /// the point as it was before smoothing
+    pub point: GpsPoint,
+    /// why it was dropped
+    pub reason: RemovalReason,
+}
+
+/// Period in which the vehicle didn't move, e.g. at a stop or traffic light
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Dwell {
+    /// timestamp of the first point of the stop
+    #[serde(with = "crate::time_serializer")]
+    pub start: NaiveDateTime,
+    /// timestamp of the last point of the stop
+    #[serde(with = "crate::time_serializer")]
+    pub end: NaiveDateTime,
+    /// mean latitude of the stop
+    pub lat: f64,
+    /// mean longitude of the stop
+    pub lon: f64,
+    /// number of points recorded during the stop
+    pub points: usize,
+}
+
+/// Result of [`clean`]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CleaningReport {
+    /// the cleaned points ordered by time
+    pub points: Vec<GpsPoint>,
+    /// dropped points
+    pub removed: Vec<Removal>,
+    /// detected stops
+    pub dwells: Vec<Dwell>,
+}
+
+impl CleaningReport {
+    /// Number of points dropped for the given reason
+    pub fn count(&self, reason: RemovalReason) -> usize {
+        self.removed
+            .iter()
+            .filter(|removal| removal.reason == reason)
+            .count()
+    }
+}
+
+/// Decides if `point` is a spike, given the last point that was kept
+fn spike(previous: &GpsPoint, point: &GpsPoint, config: &CleaningConfig) -> Option<RemovalReason> {
+    let distance = (previous.lat, previous.lon).distance_from((point.lat, point.lon));
+    let elapsed = (point.timestamp - previous.timestamp).num_milliseconds() as f64 / 1000_f64;
+    let speed = distance / elapsed;
+
+    if config.max_speed.is_some_and(|max| speed > max) {
+        return Some(RemovalReason::SpeedSpike);
+    }
+    if let (Some(max), Some(reported)) = (config.max_speed_deviation, point.speed) {
+        if speed > reported + max {
+            return Some(RemovalReason::SpeedSpike);
+        }
+    }
+    let moving = point
+        .speed
+        .is_some_and(|speed| speed >= config.min_bearing_speed);
+    if let (Some(max), Some(reported), true) = (config.max_bearing_deviation, point.bearing, moving)
+    {
+        // only check if the movement is large enough to have a meaningful direction
+        let accuracy = point.accuracy.unwrap_or(config.default_accuracy);
+        if distance > accuracy {
+            let deviation =
+                (previous.coordinate().bearing_to(&point.coordinate()) - reported).abs() % 360_f64;
+            if deviation.min(360_f64 - deviation) > max {
+                return Some(RemovalReason::BearingMismatch);
+            }
+        }
+    }
+
+    None
+}
+
+type Matrix = [[f64; 2]; 2];
+
+/// Kalman filter state of a single axis with position and velocity
+#[derive(Debug, Clone, Copy)]
+struct AxisState {
+    x: [f64; 2],
+    p: Matrix,
+}
+
+/// Constant velocity Kalman filter with Rauch-Tung-Striebel smoother, applied to both axes of
+/// the local projection independently. `measurements` are (time in seconds, position, variance).
+fn smooth_axis(measurements: &[(f64, f64, f64)], acceleration: f64) -> Vec<f64> {
+    let Some(first) = measurements.first() else {
+        return Vec::new();
+    };
+    let q = acceleration.powi(2);
+
+    let mut filtered: Vec<AxisState> = Vec::with_capacity(measurements.len());
+    let mut predicted: Vec<AxisState> = Vec::with_capacity(measurements.len());
+    let mut state = AxisState {
+        x: [first.1, 0_f64],
+        p: [[first.2, 0_f64], [0_f64, 100_f64]],
+    };
+    predicted.push(state);
+    filtered.push(state);
+
+    for window in measurements.windows(2) {
+        let (dt, z, r) = (window[1].0 - window[0].0, window[1].1, window[1].2);
+
+        // predict
+        let x = [state.x[0] + dt * state.x[1], state.x[1]];
+        let p = state.p;
+        let p = [
+            [
+                p[0][0] + dt * (p[1][0] + p[0][1]) + dt * dt * p[1][1] + q * dt.powi(4) / 4_f64,
+                p[0][1] + dt * p[1][1] + q * dt.powi(3) / 2_f64,
+            ],
+            [
+                p[1][0] + dt * p[1][1] + q * dt.powi(3) / 2_f64,
+                p[1][1] + q * dt * dt,
+            ],
+        ];
+        let prediction = AxisState { x, p };
+        predicted.push(prediction);
+
+        // update
+        let s = p[0][0] + r;
+        let k = [p[0][0] / s, p[1][0] / s];
+        let innovation = z - x[0];
+        state = AxisState {
+            x: [x[0] + k[0] * innovation, x[1] + k[1] * innovation],
+            p: [
+                [(1_f64 - k[0]) * p[0][0], (1_f64 - k[0]) * p[0][1]],
+                [p[1][0] - k[1] * p[0][0], p[1][1] - k[1] * p[0][1]],
+            ],
+        };
+        filtered.push(state);
+    }
+
+    // backward pass
+    let mut smoothed: Vec<[f64; 2]> = filtered.iter().map(|state| state.x).collect();
+    for k in (0..measurements.len() - 1).rev() {
+        let dt = measurements[k + 1].0 - measurements[k].0;
+        let p = filtered[k].p;
+        let next = predicted[k + 1];
+
+        // C = P_k F^T (P_{k+1|k})^-1
+        let pf = [
+            [p[0][0] + dt * p[0][1], p[0][1]],
+            [p[1][0] + dt * p[1][1], p[1][1]],
+        ];
+        let det = next.p[0][0] * next.p[1][1] - next.p[0][1] * next.p[1][0];
+        if det.abs() < f64::EPSILON {
+            continue;
+        }
+        let inverse = [
+            [next.p[1][1] / det, -next.p[0][1] / det],
+            [-next.p[1][0] / det, next.p[0][0] / det],
+        ];
+        let c = [
+            [
+                pf[0][0] * inverse[0][0] + pf[0][1] * inverse[1][0],
+                pf[0][0] * inverse[0][1] + pf[0][1] * inverse[1][1],
+            ],
+            [
+                pf[1][0] * inverse[0][0] + pf[1][1] * inverse[1][0],
+                pf[1][0] * inverse[0][1] + pf[1][1] * inverse[1][1],
+            ],
+        ];
+        let difference = [
+            smoothed[k + 1][0] - next.x[0],
+            smoothed[k + 1][1] - next.x[1],
+        ];
+        smoothed[k] = [
+            filtered[k].x[0] + c[0][0] * difference[0] + c[0][1] * difference[1],
+            filtered[k].x[1] + c[1][0] * difference[0] + c[1][1] * difference[1],
+        ];
+    }
+
+    smoothed.into_iter().map(|x| x[0]).collect()
+}
+
+/// Smooths the positions of the points in place
+fn kalman(points: &mut [GpsPoint], acceleration: f64, default_accuracy: f64) {
+    let Some(first) = points.first() else {
+        return;
+    };
+    let origin = first.coordinate();
+    let start = first.timestamp;
+
+    let measurements: Vec<(f64, (f64, f64), f64)> = points
+        .iter()
+        .map(|point| {
+            (
+                (point.timestamp - start).num_milliseconds() as f64 / 1000_f64,
+                point.coordinate().to_local(&origin),
+                point.accuracy.unwrap_or(default_accuracy).powi(2),
+            )
+        })
+        .collect();
+    let axis = |select: fn(&(f64, f64)) -> f64| -> Vec<f64> {
+        let measurements: Vec<(f64, f64, f64)> = measurements
+            .iter()
+            .map(|(time, local, variance)| (*time, select(local), *variance))
+            .collect();
+        smooth_axis(&measurements, acceleration)
+    };
+    let xs = axis(|local| local.0);
+    let ys = axis(|local| local.1);
+
+    for ((point, x), y) in points.iter_mut().zip(xs).zip(ys) {
+        let smoothed = Coordinate::from_local((x, y), &origin);
+        (point.lat, point.lon) = (smoothed.lat, smoothed.lon);
+    }
+}
+
+/// Finds the stops in the track. Returns the index ranges of the stops.
+fn dwells(points: &[GpsPoint], config: &CleaningConfig) -> Vec<(usize, usize)> {
+    let mut ranges = Vec::new();
+    let mut start = 0;
+    while start < points.len() {
+        let anchor = (points[start].lat, points[start].lon);
+        let mut end = start;
+        while end + 1 < points.len()
+            && anchor.distance_from((points[end + 1].lat, points[end + 1].lon))
+                <= config.dwell_radius
+        {
+            end += 1;
+        }
+
+        let duration = (points[end].timestamp - points[start].timestamp).num_milliseconds();
+        if end > start && duration >= config.min_dwell {
+            ranges.push((start, end));
+            start = end + 1;
+        } else {
+            start += 1;
+        }
+    }
+    ranges
+}
+
+/// Douglas-Peucker simplification. Marks the points to keep, points with `keep` already set are
+/// never dropped.
+fn simplify(local: &[(f64, f64)], keep: &mut [bool], tolerance: f64) {
+    let mut stack = vec![(0, local.len() - 1)];
+    while let Some((first, last)) = stack.pop() {
+        if last <= first + 1 {
+            continue;
+        }
+        let (a, b) = (local[first], local[last]);
+        let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+        let length = (dx * dx + dy * dy).sqrt();
+        let distance = |p: (f64, f64)| {
+            if length == 0_f64 {
+                ((p.0 - a.0).powi(2) + (p.1 - a.1).powi(2)).sqrt()
+            } else {
+                (dy * p.0 - dx * p.1 + b.0 * a.1 - b.1 * a.0).abs() / length
+            }
+        };
+
+        let (index, max) = (first + 1..last).map(|i| (i, distance(local[i]))).fold(
+            (first, 0_f64),
+            |best, current| {
+                if current.1 > best.1 {
+                    current
+                } else {
+                    best
+                }
+            },
+        );
+        if max > tolerance {
+            keep[index] = true;
+            stack.push((first, index));
+            stack.push((index, last));
+        } else if let Some(index) = (first + 1..last).find(|i| keep[*i]) {
+            // split at points that have to be kept anyway
+            stack.push((first, index));
+            stack.push((index, last));
+        }
+    }
+}
+
+/// Runs the cleaning pipeline on the track, see the module documentation. The track doesn't need
+/// to be sorted.
+pub fn clean(track: &[GpsPoint], config: &CleaningConfig) -> CleaningReport {
+    let mut sorted: Vec<GpsPoint> = track.to_vec();
+    sorted.sort_by_key(|point| point.timestamp);
+
+    let mut report = CleaningReport::default();
+    let remove = |point: GpsPoint, reason: RemovalReason, report: &mut CleaningReport| {
+        report.removed.push(Removal { point, reason });
+    };
+
+    // accuracy
+    let mut accurate: Vec<GpsPoint> = Vec::with_capacity(sorted.len());
+    for point in sorted {
+        if let (Some(max), Some(accuracy)) = (config.max_accuracy, point.accuracy) {
+            if accuracy > max {
+                remove(point, RemovalReason::PoorAccuracy, &mut report);
+                continue;
+            }
+        }
+        accurate.push(point);
+    }
+
+    // duplicates and spikes
+    let mut points: Vec<GpsPoint> = Vec::with_capacity(accurate.len());
+    for (i, point) in accurate.iter().copied().enumerate() {
+        let Some(previous) = points.last() else {
+            points.push(point);
+            continue;
+        };
+        if previous.timestamp == point.timestamp {
+            remove(point, RemovalReason::DuplicateTimestamp, &mut report);
+            continue;
+        }
+        let Some(reason) = spike(previous, &point, config) else {
+            points.push(point);
+            continue;
+        };
+
+        // the last kept point may be the outlier instead, if the next point agrees with this
+        // one but not with it
+        let next = accurate[i + 1..]
+            .iter()
+            .find(|next| next.timestamp != point.timestamp);
+        let jump = next.is_some_and(|next| {
+            spike(&point, next, config).is_none() && spike(previous, next, config).is_some()
+        });
+        if !jump {
+            remove(point, reason, &mut report);
+            continue;
+        }
+        // a first point that was never confirmed by a predecessor is dropped
+        if points.len() == 1 {
+            if let Some(first) = points.pop() {
+                remove(first, reason, &mut report);
+            }
+        }
+        points.push(point);
+    }
+
+    let originals = points.clone();
+    if let Some(acceleration) = config.kalman_acceleration {
+        kalman(&mut points, acceleration, config.default_accuracy);
+    }
+
+    // stops
+    let mut keep = vec![false; points.len()];
+    let mut drop_reason: Vec<Option<RemovalReason>> = vec![None; points.len()];
+    for (start, end) in dwells(&points, config) {
+        let count = (end - start + 1) as f64;
+        report.dwells.push(Dwell {
+            start: points[start].timestamp,
+            end: points[end].timestamp,
+            lat: points[start..=end].iter().map(|p| p.lat).sum::<f64>() / count,
+            lon: points[start..=end].iter().map(|p| p.lon).sum::<f64>() / count,
+            points: end - start + 1,
+        });
+        keep[start] = true;
+        keep[end] = true;
+        if config.collapse_dwells {
+            for reason in &mut drop_reason[start + 1..end] {
+                *reason = Some(RemovalReason::StationaryDrift);
+            }
+        }
+    }
+
+    // simplification over the points that survived so far
+    if let (Some(tolerance), Some(first)) = (config.simplify_tolerance, points.first()) {
+        let origin = first.coordinate();
+        let candidates: Vec<usize> = (0..points.len())
+            .filter(|i| drop_reason[*i].is_none())
+            .collect();
+        if candidates.len() > 2 {
+            let local: Vec<(f64, f64)> = candidates
+                .iter()
+                .map(|i| points[*i].coordinate().to_local(&origin))
+                .collect();
+            let mut keep_candidates: Vec<bool> = candidates.iter().map(|i| keep[*i]).collect();
+            keep_candidates[0] = true;
+            *keep_candidates.last_mut().unwrap() = true;
+            simplify(&local, &mut keep_candidates, tolerance);
+            for (i, kept) in candidates.into_iter().zip(keep_candidates) {
+                if !kept {
+                    drop_reason[i] = Some(RemovalReason::Simplified);
+                }
+            }
+        }
+    }
+
+    for ((point, original), reason) in points.into_iter().zip(originals).zip(drop_reason) {
+        match reason {
+            Some(reason) => remove(original, reason, &mut report),
+            None => report.points.push(point),
+        }
+    }
+
+    report
+}
@@ -1,5 +1,7 @@
 //! This module holds replresentations for geolocation data used all over the TLMS services
 
+pub mod cleaning;
+
 use crate::schema::*;
 use chrono::NaiveDateTime;
 use diesel::{Insertable, Queryable};
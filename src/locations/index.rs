@@ -0,0 +1,186 @@
+//! This module holds an in-memory R-tree over anything with a position (see [`Projectable`]),
+//! e.g. the [`TransmissionLocation`]s and [`Station`]s of a region. Positions are projected into a
+//! local equirectangular plane in meters around an origin, which is precise enough at city scale
+//! and makes all queries cheap: nearest-k, within a radius and inside a bounding box.
+//!
+//! The [`RegionIndex`] bundles both indices of a region and snaps GPS points to reporting points.
+
+use super::projection::{Coordinate, Projectable};
+use super::region::Region;
+use super::TransmissionLocation;
+use crate::management::Station;
+use crate::schema::*;
+
+use diesel::{ExpressionMethods, PgConnection, QueryDsl, RunQueryDsl};
+use rstar::{PointDistance, RTree, RTreeObject, AABB};
+
+/// Single indexed item together with its local position
+#[derive(Debug, Clone)]
+struct Entry<T> {
+    position: [f64; 2],
+    item: T,
+}
+
+impl<T> RTreeObject for Entry<T> {
+    type Envelope = AABB<[f64; 2]>;
+
+    fn envelope(&self) -> Self::Envelope {
+        AABB::from_point(self.position)
+    }
+}
+
+impl<T> PointDistance for Entry<T> {
+    fn distance_2(&self, point: &[f64; 2]) -> f64 {
+        let (dx, dy) = (self.position[0] - point[0], self.position[1] - point[1]);
+        dx * dx + dy * dy
+    }
+}
+
+/// R-tree over items with a position. All distances are in meters.
+#[derive(Debug, Clone)]
+pub struct SpatialIndex<T> {
+    origin: Coordinate,
+    tree: RTree<Entry<T>>,
+}
+
+impl<T: Projectable> SpatialIndex<T> {
+    /// Builds the index around the mean position of the items
+    pub fn new(items: Vec<T>) -> Self {
+        let count = items.len().max(1) as f64;
+        let origin = Coordinate::new(
+            items.iter().map(|item| item.coordinate().lat).sum::<f64>() / count,
+            items.iter().map(|item| item.coordinate().lon).sum::<f64>() / count,
+        );
+        Self::with_origin(origin, items)
+    }
+
+    /// Builds the index around the given origin, e.g. the center of the region
+    pub fn with_origin(origin: Coordinate, items: Vec<T>) -> Self {
+        let mut index = SpatialIndex {
+            origin,
+            tree: RTree::new(),
+        };
+        let entries = items
+            .into_iter()
+            .map(|item| Entry {
+                position: index.to_local(&item.coordinate()),
+                item,
+            })
+            .collect();
+        index.tree = RTree::bulk_load(entries);
+        index
+    }
+
+    /// Local equirectangular position in meters relative to the origin
+    fn to_local(&self, coordinate: &Coordinate) -> [f64; 2] {
+        let (x, y) = coordinate.to_local(&self.origin);
+        [x, y]
+    }
+
+    /// Adds an item to the index
+    pub fn insert(&mut self, item: T) {
+        let position = self.to_local(&item.coordinate());
+        self.tree.insert(Entry { position, item });
+    }
+
+    /// Returns up to `k` items closest to the coordinate, ordered by distance
+    pub fn nearest(&self, coordinate: &Coordinate, k: usize) -> Vec<(&T, f64)> {
+        let point = self.to_local(coordinate);
+        self.tree
+            .nearest_neighbor_iter_with_distance_2(&point)
+            .take(k)
+            .map(|(entry, distance_2)| (&entry.item, distance_2.sqrt()))
+            .collect()
+    }
+
+    /// Returns all items within `radius` meters of the coordinate, ordered by distance
+    pub fn within_radius(&self, coordinate: &Coordinate, radius: f64) -> Vec<(&T, f64)> {
+        let point = self.to_local(coordinate);
+        let mut found: Vec<(&T, f64)> = self
+            .tree
+            .locate_within_distance(point, radius * radius)
+            .map(|entry| (&entry.item, entry.distance_2(&point).sqrt()))
+            .collect();
+        found.sort_by(|a, b| a.1.total_cmp(&b.1));
+        found
+    }
+
+    /// Returns all items inside the bounding box spanned by the south-west and north-east corner
+    pub fn in_bbox(&self, south_west: &Coordinate, north_east: &Coordinate) -> Vec<&T> {
+        let envelope = AABB::from_corners(self.to_local(south_west), self.to_local(north_east));
+        self.tree
+            .locate_in_envelope(&envelope)
+            .map(|entry| &entry.item)
+            .collect()
+    }
+
+    /// Iterates over all items in no particular order
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.tree.iter().map(|entry| &entry.item)
+    }
+
+    /// Number of indexed items
+    pub fn len(&self) -> usize {
+        self.tree.size()
+    }
+
+    /// Returns true if the index holds no items
+    pub fn is_empty(&self) -> bool {
+        self.tree.size() == 0
+    }
+}
+
+/// Spatial indices over the transmission locations and stations of a region
+#[derive(Debug, Clone)]
+pub struct RegionIndex {
+    /// transmission locations of the region
+    pub locations: SpatialIndex<TransmissionLocation>,
+    /// stations of the region
+    pub stations: SpatialIndex<Station>,
+}
+
+impl RegionIndex {
+    /// Builds the indices around the center of the region
+    pub fn new(
+        region: &Region,
+        locations: Vec<TransmissionLocation>,
+        stations: Vec<Station>,
+    ) -> Self {
+        let origin = region.coordinate();
+        RegionIndex {
+            locations: SpatialIndex::with_origin(origin, locations),
+            stations: SpatialIndex::with_origin(origin, stations),
+        }
+    }
+
+    /// Loads the region with its transmission locations and active stations and builds the indices
+    pub fn from_postgres(
+        database_connection: &mut PgConnection,
+        region_id: i64,
+    ) -> Result<Self, diesel::result::Error> {
+        let region: Region = regions::table
+            .filter(regions::id.eq(region_id))
+            .first(database_connection)?;
+        let locations: Vec<TransmissionLocation> = r09_transmission_locations::table
+            .filter(r09_transmission_locations::region.eq(region_id))
+            .load(database_connection)?;
+        let stations: Vec<Station> = stations::table
+            .filter(stations::region.eq(region_id))
+            .filter(stations::deactivated.eq(false))
+            .load(database_connection)?;
+
+        Ok(Self::new(&region, locations, stations))
+    }
+
+    /// Snaps a position to the closest transmission location within `max_distance` meters
+    pub fn snap(
+        &self,
+        position: &impl Projectable,
+        max_distance: f64,
+    ) -> Option<(&TransmissionLocation, f64)> {
+        self.locations
+            .nearest(&position.coordinate(), 1)
+            .into_iter()
+            .find(|(_, distance)| *distance <= max_distance)
+    }
+}
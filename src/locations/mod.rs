@@ -1,9 +1,21 @@
+pub mod boundary;
+pub mod estimation;
+pub mod geojson;
 pub mod gps;
+pub mod index;
+#[cfg(feature = "postgis")]
+pub mod postgis;
+pub mod projection;
+pub mod reconcile;
 pub mod region;
+pub mod route;
+#[cfg(test)]
 mod tests;
 pub mod waypoint;
 
 use crate::schema::*;
+use estimation::{EstimatorConfig, LocationEstimator, WeightedLocation};
+use projection::{Crs, Projectable};
 
 use diesel::{AsChangeset, Identifiable, Insertable, Queryable};
 use serde::{Deserialize, Serialize};
@@ -13,8 +25,9 @@ use utoipa::ToSchema;
 /// Version of the [`LocationsJson`] shcema used.
 pub const SCHEMA: &str = "3"; // INCREMENT ME ON ANY BREAKING CHANGE!!!!11111one
 
-/// maximum distance in meters
-pub const SANE_INTERPOLATION_DISTANCE: i32 = 50;
+/// maximum distance in meters at which a position is considered to belong to a reporting point or
+/// track, also the default [`EstimatorConfig::outlier_distance`]
+pub const SANE_INTERPOLATION_DISTANCE: f64 = 50_f64;
 /// Mean earth radius, required for calcuation of distances between the GPS points
 pub const MEAN_EARTH_RADIUS: u32 = 6_371_000;
 
@@ -126,6 +139,7 @@ pub struct LocationsJson {
 }
 
 /// Error for associated functions and methods over [`TransmissionLocation`] struct
+#[derive(Debug)]
 pub enum TransmissionLocaionError {
     /// Input provided was empty
     EmptyInput,
@@ -136,102 +150,96 @@ pub enum TransmissionLocaionError {
     ReportingPointMismatch,
     /// Filtering produced no valid matches
     NoMatches,
+    /// Too few raw samples, trekkie runs or run owners to satisfy the [`EstimatorConfig`]
+    InsufficientEvidence,
 }
 
 impl ApiTransmissionLocation {
     /// Updates property field with epsg3857 coordinates, calculated from `loc` and `lon` fileds of
     /// the struct. If field doesn't exist, creates it.
     pub fn update_epsg3857(&mut self) {
-        // Convert the coords to pseudo-mercator (epsg3857)
-        const EARTH_RADIUS_M: f64 = 6_378_137_f64;
-        let x = EARTH_RADIUS_M * self.lon.to_radians();
-        let y =
-            ((self.lat.to_radians() / 2. + std::f64::consts::PI / 4.).tan()).ln() * EARTH_RADIUS_M;
-
-        // serialize value
-        if let Ok(epsg_val) = serde_json::from_str(&format!("{{ \"x\":{x}, \"y\":{y} }}")) {
-            self.properties["epsg3857"] = epsg_val;
-        } else {
-            eprintln!("epsg3857 property update skipped: Could not serialize {x} and {y} into json Value!");
-        }
+        let projected = self.project(Crs::WebMercator);
+        self.properties["epsg3857"] = serde_json::json!({ "x": projected.x, "y": projected.y });
     }
 }
 
-type TransmissionLocationResult = Result<InsertTransmissionLocation, TransmissionLocaionError>;
-impl InsertTransmissionLocation {
-    /// Maximum distance at which the raw point is considered to be corresponding to the report
-    /// location cluster
-    // TODO: Should we make this configurable?
-    pub const MAX_SANE_DISTANCE: f64 = 50_f64;
-
-    /// This function filters out outliers for specific reporting_point
-    fn filter_outliers(
-        input: Vec<TransmissionLocationRaw>,
-    ) -> Result<Vec<TransmissionLocationRaw>, TransmissionLocaionError> {
-        // Edge case
-        if input.is_empty() {
-            return Err(TransmissionLocaionError::EmptyInput);
-        }
-
-        let (lats, lons): (Vec<f64>, Vec<f64>) = input.iter().map(|s| (s.lat, s.lon)).unzip();
-
-        let center_point: (f64, f64) = (
-            lats.iter().sum::<f64>() / (lats.len() as f64),
-            lons.iter().sum::<f64>() / (lons.len() as f64),
-        );
-
-        let mut filtered: Vec<TransmissionLocationRaw> = Vec::new();
-
-        for i in input {
-            if i.distance_from(center_point) < SANE_INTERPOLATION_DISTANCE as f64 {
-                filtered.push(i)
-            }
-        }
-
-        if filtered.is_empty() {
-            return Err(TransmissionLocaionError::NoMatches);
-        }
-
-        Ok(filtered)
-    }
+/// Transmission location inferred from raw locations, together with a measure of how much it can
+/// be trusted. See [`InsertTransmissionLocation::try_from_weighted`].
+#[derive(Debug, Clone)]
+pub struct EstimatedTransmissionLocation {
+    /// The location, ready to be inserted into the database
+    pub location: InsertTransmissionLocation,
+    /// Radius in meters of the 1σ confidence circle around the location
+    pub uncertainty: f64,
+    /// Number of raw locations the estimate was calculated from, after outliers were removed
+    pub inliers: usize,
+}
 
+type TransmissionLocationResult = Result<EstimatedTransmissionLocation, TransmissionLocaionError>;
+impl InsertTransmissionLocation {
     /// This function creates the [`InsertTransmissionLocation`] from the vector of raw
-    /// transmission locations. Points are averaged, any outliers further away then
-    /// [`MAX_SANE_DISTANCE`] are discarded. All the [`TransmissionLocationRaw`] should have the
-    /// same region, or the function will fail.
+    /// transmission locations, using the weighted [`GeometricMedian`](estimation::GeometricMedian)
+    /// with all raw locations weighted equally. All the [`TransmissionLocationRaw`] should have
+    /// the same region and reporting point, or the function will fail.
     ///
     /// **This is default way for updating the [`TransmissionLocation`]**. The analysis should be
     /// performed on the whole set of raw locations, to prevent biasing the data.
-    pub fn try_from_raw(raw: Vec<TransmissionLocationRaw>) -> TransmissionLocationResult {
-        if raw.is_empty() {
+    pub fn try_from_raw(
+        raw: Vec<TransmissionLocationRaw>,
+        config: &EstimatorConfig,
+    ) -> TransmissionLocationResult {
+        Self::try_from_weighted(
+            raw.into_iter().map(WeightedLocation::from).collect(),
+            &estimation::GeometricMedian::default(),
+            config,
+        )
+    }
+
+    /// This function creates the [`InsertTransmissionLocation`] from raw transmission locations
+    /// weighted by their GPS accuracy (see [`WeightedLocation::from_postgres`]), using the given
+    /// estimator. All the raw locations should have the same region and reporting point, or the
+    /// function will fail. Returns [`TransmissionLocaionError::InsufficientEvidence`] if the
+    /// samples left after removing outliers don't satisfy the minimums of the `config`.
+    pub fn try_from_weighted(
+        samples: Vec<WeightedLocation>,
+        estimator: &dyn LocationEstimator,
+        config: &EstimatorConfig,
+    ) -> TransmissionLocationResult {
+        let Some(first) = samples.first() else {
             return Err(TransmissionLocaionError::EmptyInput);
-        }
-        let region = raw[0].region;
-        let reporting_point = raw[0].reporting_point;
-        for loc in &raw {
-            if loc.region != region {
+        };
+        let region = first.location.region;
+        let reporting_point = first.location.reporting_point;
+        for sample in &samples {
+            if sample.location.region != region {
                 return Err(TransmissionLocaionError::RegionMismatch);
             }
-            if loc.reporting_point != reporting_point {
+            if sample.location.reporting_point != reporting_point {
                 return Err(TransmissionLocaionError::ReportingPointMismatch);
             }
         }
 
-        let filtered = Self::filter_outliers(raw)?;
+        // outlier removal only drops samples, so this can be rejected before estimating
+        if !config.is_satisfied_by(&samples) {
+            return Err(TransmissionLocaionError::InsufficientEvidence);
+        }
 
-        let (lats, lons): (Vec<f64>, Vec<f64>) = filtered.iter().map(|v| (v.lat, v.lon)).unzip();
-        let (lat, lon): (f64, f64) = (
-            lats.iter().sum::<f64>() / (lats.len() as f64),
-            lons.iter().sum::<f64>() / (lons.len() as f64),
-        );
+        let estimate = estimator.estimate(&samples, config)?;
+        if !config.is_satisfied_by(&estimate.inliers) {
+            return Err(TransmissionLocaionError::InsufficientEvidence);
+        }
 
-        Ok(InsertTransmissionLocation {
-            id: None,
-            region,
-            reporting_point,
-            lat,
-            lon,
-            ground_truth: false,
+        Ok(EstimatedTransmissionLocation {
+            location: InsertTransmissionLocation {
+                id: None,
+                region,
+                reporting_point,
+                lat: estimate.lat,
+                lon: estimate.lon,
+                ground_truth: false,
+            },
+            uncertainty: estimate.uncertainty,
+            inliers: estimate.inliers.len(),
         })
     }
 }
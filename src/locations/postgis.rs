@@ -0,0 +1,139 @@
+//! This module holds diesel helpers for the PostGIS `geography(Point)` columns added by the
+//! migrations in `migrations-postgis`. The columns are generated from `lat` and `lon`, so nothing
+//! changes for writers. They are not part of [`crate::schema`], as loading the regular structs
+//! would break on databases without PostGIS, instead they are referenced by the `*_position`
+//! functions below, which can be used in filters and orderings of the regular tables:
+//!
+//! ```ignore
+//! let center = point(51.05, 13.74);
+//! stations::table
+//!     .filter(st_dwithin(station_position(), center, 500.0))
+//!     .order_by(st_distance(station_position(), center))
+//!     .load::<Station>(connection)?;
+//! ```
+
+use super::projection::Projectable;
+use super::TransmissionLocation;
+use crate::management::Station;
+use crate::schema::*;
+
+use diesel::dsl::sql;
+use diesel::expression::SqlLiteral;
+use diesel::sql_types::{Double, Integer};
+use diesel::{
+    define_sql_function, ExpressionMethods, PgConnection, QueryDsl, QueryId, RunQueryDsl, SqlType,
+};
+
+/// Spatial reference of WGS84, which all positions are stored in
+pub const WGS84_SRID: i32 = 4326;
+
+/// PostGIS `geography` type, positions on the spheroid with distances in meters
+#[derive(Debug, Clone, Copy, Default, SqlType, QueryId)]
+#[diesel(postgres_type(name = "geography"))]
+pub struct Geography;
+
+/// PostGIS `geometry` type, positions in a plane
+#[derive(Debug, Clone, Copy, Default, SqlType, QueryId)]
+#[diesel(postgres_type(name = "geometry"))]
+pub struct Geometry;
+
+define_sql_function! {
+    /// `ST_DWithin`, true if both geographies are within `distance` meters of each other. Uses
+    /// the GiST index of the position columns.
+    #[sql_name = "ST_DWithin"]
+    fn st_dwithin(a: Geography, b: Geography, distance: Double) -> Bool;
+}
+
+define_sql_function! {
+    /// `ST_Distance`, distance in meters between both geographies
+    #[sql_name = "ST_Distance"]
+    fn st_distance(a: Geography, b: Geography) -> Double;
+}
+
+define_sql_function! {
+    /// `ST_MakePoint`, geometry point from x (longitude) and y (latitude)
+    #[sql_name = "ST_MakePoint"]
+    fn st_makepoint(x: Double, y: Double) -> Geometry;
+}
+
+define_sql_function! {
+    /// `ST_SetSRID`, sets the spatial reference of a geometry
+    #[sql_name = "ST_SetSRID"]
+    fn st_setsrid(geometry: Geometry, srid: Integer) -> Geometry;
+}
+
+define_sql_function! {
+    /// `geography`, casts a geometry with SRID 4326 to geography
+    fn geography(geometry: Geometry) -> Geography;
+}
+
+/// Expression type of [`point`]
+pub type GeographyPoint = geography<st_setsrid<st_makepoint<f64, f64>, i32>>;
+
+/// Geography point for the given latitude and longitude
+pub fn point(lat: f64, lon: f64) -> GeographyPoint {
+    geography(st_setsrid(st_makepoint(lon, lat), WGS84_SRID))
+}
+
+/// `geog` column of `stations`
+pub fn station_position() -> SqlLiteral<Geography> {
+    sql("stations.geog")
+}
+
+/// `geog` column of `gps_points`
+pub fn gps_point_position() -> SqlLiteral<Geography> {
+    sql("gps_points.geog")
+}
+
+/// `geog` column of `r09_transmission_locations`
+pub fn transmission_location_position() -> SqlLiteral<Geography> {
+    sql("r09_transmission_locations.geog")
+}
+
+/// `geog` column of `r09_transmission_locations_raw`
+pub fn raw_transmission_location_position() -> SqlLiteral<Geography> {
+    sql("r09_transmission_locations_raw.geog")
+}
+
+/// Loads all active stations within `radius` meters of the position together with their
+/// distance, closest first
+pub fn stations_within(
+    database_connection: &mut PgConnection,
+    position: &impl Projectable,
+    radius: f64,
+) -> Result<Vec<(Station, f64)>, diesel::result::Error> {
+    let coordinate = position.coordinate();
+    let center = point(coordinate.lat, coordinate.lon);
+
+    stations::table
+        .filter(stations::deactivated.eq(false))
+        .filter(st_dwithin(station_position(), center, radius))
+        .select((
+            stations::all_columns,
+            st_distance(station_position(), center),
+        ))
+        .order_by(st_distance(station_position(), center))
+        .load(database_connection)
+}
+
+/// Loads all transmission locations of the region within `radius` meters of the position
+/// together with their distance, closest first
+pub fn transmission_locations_within(
+    database_connection: &mut PgConnection,
+    region_id: i64,
+    position: &impl Projectable,
+    radius: f64,
+) -> Result<Vec<(TransmissionLocation, f64)>, diesel::result::Error> {
+    let coordinate = position.coordinate();
+    let center = point(coordinate.lat, coordinate.lon);
+
+    r09_transmission_locations::table
+        .filter(r09_transmission_locations::region.eq(region_id))
+        .filter(st_dwithin(transmission_location_position(), center, radius))
+        .select((
+            r09_transmission_locations::all_columns,
+            st_distance(transmission_location_position(), center),
+        ))
+        .order_by(st_distance(transmission_location_position(), center))
+        .load(database_connection)
+}
@@ -0,0 +1,438 @@
+//! This module converts WGS84 coordinates into projected coordinate reference systems and back.
+//! Supported are Web Mercator (EPSG:3857), UTM zones on WGS84 (EPSG:326xx/327xx) and ETRS89
+//! (EPSG:258xx, which is treated as identical to WGS84), and Gauss-Krüger zones on DHDN
+//! (EPSG:31466 - 31469), still common in German transit data. The transverse Mercator projection
+//! uses the Krüger series, DHDN is shifted from WGS84 with a 7 parameter Helmert transformation,
+//! which is accurate to about a meter.
+//!
+//! All structs carrying a position implement [`Projectable`].
+
+use super::gps::{GpsPoint, InsertGpsPoint};
+use super::region::Region;
+use super::waypoint::Waypoint;
+use super::{
+    ApiTransmissionLocation, InsertTransmissionLocation, InsertTransmissionLocationRaw,
+    TransmissionLocation, TransmissionLocationRaw, MEAN_EARTH_RADIUS,
+};
+use crate::management::Station;
+
+use serde::{Deserialize, Serialize};
+
+/// Radius of the sphere used by Web Mercator
+const WEB_MERCATOR_RADIUS: f64 = 6_378_137_f64;
+
+/// Reference ellipsoid
+#[derive(Debug, Clone, Copy)]
+struct Ellipsoid {
+    /// semi-major axis in meters
+    a: f64,
+    /// flattening
+    f: f64,
+}
+
+const WGS84: Ellipsoid = Ellipsoid {
+    a: 6_378_137_f64,
+    f: 1_f64 / 298.257_223_563,
+};
+
+const BESSEL: Ellipsoid = Ellipsoid {
+    a: 6_377_397.155,
+    f: 1_f64 / 299.152_812_8,
+};
+
+/// Helmert parameters DHDN -> WGS84 (position vector convention): translation in meters,
+/// rotation in arc seconds and scale in ppm
+const DHDN_TO_WGS84: [f64; 7] = [598.1, 73.7, 418.2, 0.202, 0.045, -2.455, 6.7];
+
+/// Geographic WGS84 coordinate in degrees
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Coordinate {
+    /// latitude
+    pub lat: f64,
+    /// longitude
+    pub lon: f64,
+}
+
+/// Projected coordinate reference system
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Crs {
+    /// Web Mercator as used by web maps, EPSG:3857
+    WebMercator,
+    /// UTM zone on WGS84, EPSG:326xx for the northern and EPSG:327xx for the southern hemisphere
+    Utm {
+        /// zone number from 1 to 60
+        zone: u8,
+        /// if the zone is on the northern hemisphere
+        north: bool,
+    },
+    /// UTM zone on ETRS89, EPSG:258xx, only defined for the northern hemisphere
+    Etrs89Utm {
+        /// zone number from 28 to 38
+        zone: u8,
+    },
+    /// Gauss-Krüger zone on DHDN, EPSG:31464 + zone for the zones 2 to 5 covering Germany. The
+    /// easting is prefixed with the zone number.
+    GaussKrueger {
+        /// zone number, the central meridian is 3° times the zone
+        zone: u8,
+    },
+}
+
+impl Crs {
+    /// EPSG code of the coordinate reference system, `None` for zones without registered code,
+    /// e.g. Gauss-Krüger zones outside of Germany
+    pub fn epsg(&self) -> Option<u32> {
+        match *self {
+            Crs::WebMercator => Some(3857),
+            Crs::Utm { zone, north: true } if (1..=60).contains(&zone) => Some(32600 + zone as u32),
+            Crs::Utm { zone, north: false } if (1..=60).contains(&zone) => {
+                Some(32700 + zone as u32)
+            }
+            Crs::Etrs89Utm { zone } if (28..=38).contains(&zone) => Some(25800 + zone as u32),
+            Crs::GaussKrueger { zone } if (2..=5).contains(&zone) => Some(31464 + zone as u32),
+            _ => None,
+        }
+    }
+
+    /// Looks up a coordinate reference system by its EPSG code
+    pub fn from_epsg(code: u32) -> Option<Self> {
+        match code {
+            3857 => Some(Crs::WebMercator),
+            32601..=32660 => Some(Crs::Utm {
+                zone: (code - 32600) as u8,
+                north: true,
+            }),
+            32701..=32760 => Some(Crs::Utm {
+                zone: (code - 32700) as u8,
+                north: false,
+            }),
+            25828..=25838 => Some(Crs::Etrs89Utm {
+                zone: (code - 25800) as u8,
+            }),
+            31466..=31469 => Some(Crs::GaussKrueger {
+                zone: (code - 31464) as u8,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Coordinate in a projected coordinate reference system, in meters
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ProjectedCoordinate {
+    /// easting
+    pub x: f64,
+    /// northing
+    pub y: f64,
+    /// coordinate reference system of `x` and `y`
+    pub crs: Crs,
+}
+
+/// Parameters of a transverse Mercator projection
+struct TransverseMercator {
+    ellipsoid: Ellipsoid,
+    central_meridian: f64,
+    scale: f64,
+    false_easting: f64,
+    false_northing: f64,
+}
+
+impl TransverseMercator {
+    fn for_crs(crs: Crs) -> Option<Self> {
+        match crs {
+            Crs::WebMercator => None,
+            Crs::Utm { zone, north } => Some(Self::utm(zone, north)),
+            Crs::Etrs89Utm { zone } => Some(Self::utm(zone, true)),
+            Crs::GaussKrueger { zone } => Some(TransverseMercator {
+                ellipsoid: BESSEL,
+                central_meridian: 3_f64 * zone as f64,
+                scale: 1_f64,
+                false_easting: zone as f64 * 1_000_000_f64 + 500_000_f64,
+                false_northing: 0_f64,
+            }),
+        }
+    }
+
+    fn utm(zone: u8, north: bool) -> Self {
+        TransverseMercator {
+            ellipsoid: WGS84,
+            central_meridian: zone as f64 * 6_f64 - 183_f64,
+            scale: 0.9996,
+            false_easting: 500_000_f64,
+            false_northing: if north { 0_f64 } else { 10_000_000_f64 },
+        }
+    }
+
+    /// third flattening, rectifying radius and the Krüger series coefficients (alpha, beta,
+    /// delta) up to third order
+    fn series(&self) -> (f64, f64, [f64; 3], [f64; 3], [f64; 3]) {
+        let n = self.ellipsoid.f / (2_f64 - self.ellipsoid.f);
+        let (n2, n3) = (n * n, n * n * n);
+        let radius = self.ellipsoid.a / (1_f64 + n) * (1_f64 + n2 / 4_f64 + n2 * n2 / 64_f64);
+        let alpha = [
+            n / 2_f64 - 2_f64 * n2 / 3_f64 + 5_f64 * n3 / 16_f64,
+            13_f64 * n2 / 48_f64 - 3_f64 * n3 / 5_f64,
+            61_f64 * n3 / 240_f64,
+        ];
+        let beta = [
+            n / 2_f64 - 2_f64 * n2 / 3_f64 + 37_f64 * n3 / 96_f64,
+            n2 / 48_f64 + n3 / 15_f64,
+            17_f64 * n3 / 480_f64,
+        ];
+        let delta = [
+            2_f64 * n - 2_f64 * n2 / 3_f64 - 2_f64 * n3,
+            7_f64 * n2 / 3_f64 - 8_f64 * n3 / 5_f64,
+            56_f64 * n3 / 15_f64,
+        ];
+        (n, radius, alpha, beta, delta)
+    }
+
+    fn forward(&self, lat: f64, lon: f64) -> (f64, f64) {
+        let (n, radius, alpha, _, _) = self.series();
+        let e = 2_f64 * n.sqrt() / (1_f64 + n);
+        let (phi, lambda) = (lat.to_radians(), (lon - self.central_meridian).to_radians());
+
+        let t = (phi.sin().atanh() - e * (e * phi.sin()).atanh()).sinh();
+        let xi = t.atan2(lambda.cos());
+        let eta = (lambda.sin() / (1_f64 + t * t).sqrt()).atanh();
+
+        let (mut x, mut y) = (eta, xi);
+        for (j, a) in alpha.iter().enumerate() {
+            let k = 2_f64 * (j + 1) as f64;
+            x += a * (k * xi).cos() * (k * eta).sinh();
+            y += a * (k * xi).sin() * (k * eta).cosh();
+        }
+
+        (
+            self.false_easting + self.scale * radius * x,
+            self.false_northing + self.scale * radius * y,
+        )
+    }
+
+    fn inverse(&self, x: f64, y: f64) -> (f64, f64) {
+        let (_, radius, _, beta, delta) = self.series();
+        let xi = (y - self.false_northing) / (self.scale * radius);
+        let eta = (x - self.false_easting) / (self.scale * radius);
+
+        let (mut xi_prime, mut eta_prime) = (xi, eta);
+        for (j, b) in beta.iter().enumerate() {
+            let k = 2_f64 * (j + 1) as f64;
+            xi_prime -= b * (k * xi).sin() * (k * eta).cosh();
+            eta_prime -= b * (k * xi).cos() * (k * eta).sinh();
+        }
+
+        let chi = (xi_prime.sin() / eta_prime.cosh()).asin();
+        let mut phi = chi;
+        for (j, d) in delta.iter().enumerate() {
+            phi += d * (2_f64 * (j + 1) as f64 * chi).sin();
+        }
+        let lambda = eta_prime.sinh().atan2(xi_prime.cos());
+
+        (
+            phi.to_degrees(),
+            self.central_meridian + lambda.to_degrees(),
+        )
+    }
+}
+
+/// Geodetic to geocentric cartesian coordinates on the ellipsoid surface
+fn to_geocentric(lat: f64, lon: f64, ellipsoid: Ellipsoid) -> [f64; 3] {
+    let (phi, lambda) = (lat.to_radians(), lon.to_radians());
+    let e2 = ellipsoid.f * (2_f64 - ellipsoid.f);
+    let normal = ellipsoid.a / (1_f64 - e2 * phi.sin().powi(2)).sqrt();
+    [
+        normal * phi.cos() * lambda.cos(),
+        normal * phi.cos() * lambda.sin(),
+        normal * (1_f64 - e2) * phi.sin(),
+    ]
+}
+
+/// Geocentric cartesian to geodetic coordinates, the height is dropped
+fn from_geocentric(position: [f64; 3], ellipsoid: Ellipsoid) -> (f64, f64) {
+    let [x, y, z] = position;
+    let e2 = ellipsoid.f * (2_f64 - ellipsoid.f);
+    let p = (x * x + y * y).sqrt();
+
+    let mut phi = z.atan2(p * (1_f64 - e2));
+    for _ in 0..10 {
+        let normal = ellipsoid.a / (1_f64 - e2 * phi.sin().powi(2)).sqrt();
+        let height = p / phi.cos() - normal;
+        phi = z.atan2(p * (1_f64 - e2 * normal / (normal + height)));
+    }
+
+    (phi.to_degrees(), y.atan2(x).to_degrees())
+}
+
+/// 7 parameter Helmert transformation, `direction` is `1.0` or `-1.0` for the inverse
+fn helmert(position: [f64; 3], parameters: [f64; 7], direction: f64) -> [f64; 3] {
+    let arc_second = (1_f64 / 3600_f64).to_radians();
+    let [tx, ty, tz] = [parameters[0], parameters[1], parameters[2]].map(|t| direction * t);
+    let [rx, ry, rz] =
+        [parameters[3], parameters[4], parameters[5]].map(|r| direction * r * arc_second);
+    let scale = 1_f64 + direction * parameters[6] * 1e-6;
+    let [x, y, z] = position;
+
+    [
+        tx + scale * (x - rz * y + ry * z),
+        ty + scale * (rz * x + y - rx * z),
+        tz + scale * (-ry * x + rx * y + z),
+    ]
+}
+
+impl Coordinate {
+    /// Creates a coordinate from latitude and longitude in degrees
+    pub fn new(lat: f64, lon: f64) -> Self {
+        Coordinate { lat, lon }
+    }
+
+    /// UTM zone the coordinate is located in, ignoring the Norway and Svalbard exceptions
+    pub fn utm_zone(&self) -> u8 {
+        (((self.lon + 180_f64) / 6_f64).floor() as i64).rem_euclid(60) as u8 + 1
+    }
+
+    /// Local equirectangular position in meters relative to `origin`, x pointing east and y
+    /// north. Good enough for distances within a city.
+    pub fn to_local(&self, origin: &Coordinate) -> (f64, f64) {
+        let radius = MEAN_EARTH_RADIUS as f64;
+        (
+            radius * (self.lon - origin.lon).to_radians() * origin.lat.to_radians().cos(),
+            radius * (self.lat - origin.lat).to_radians(),
+        )
+    }
+
+    /// Inverse of [`Coordinate::to_local`]
+    pub fn from_local(local: (f64, f64), origin: &Coordinate) -> Self {
+        let radius = MEAN_EARTH_RADIUS as f64;
+        Coordinate {
+            lat: origin.lat + (local.1 / radius).to_degrees(),
+            lon: origin.lon + (local.0 / (radius * origin.lat.to_radians().cos())).to_degrees(),
+        }
+    }
+
+    /// Initial bearing in degrees clockwise from north when travelling to `to`
+    pub fn bearing_to(&self, to: &Coordinate) -> f64 {
+        let (from_lat, to_lat) = (self.lat.to_radians(), to.lat.to_radians());
+        let delta_lon = (to.lon - self.lon).to_radians();
+        let y = delta_lon.sin() * to_lat.cos();
+        let x = from_lat.cos() * to_lat.sin() - from_lat.sin() * to_lat.cos() * delta_lon.cos();
+        (y.atan2(x).to_degrees() + 360_f64) % 360_f64
+    }
+
+    /// Gauss-Krüger zone the coordinate is located in
+    pub fn gauss_krueger_zone(&self) -> u8 {
+        (self.lon / 3_f64).round().max(0_f64) as u8
+    }
+
+    /// Projects the coordinate into the given coordinate reference system
+    pub fn project(&self, crs: Crs) -> ProjectedCoordinate {
+        let (x, y) = match crs {
+            Crs::WebMercator => (
+                WEB_MERCATOR_RADIUS * self.lon.to_radians(),
+                WEB_MERCATOR_RADIUS
+                    * (std::f64::consts::FRAC_PI_4 + self.lat.to_radians() / 2_f64)
+                        .tan()
+                        .ln(),
+            ),
+            Crs::GaussKrueger { .. } => {
+                let wgs84 = to_geocentric(self.lat, self.lon, WGS84);
+                let (lat, lon) = from_geocentric(helmert(wgs84, DHDN_TO_WGS84, -1_f64), BESSEL);
+                // for_crs only returns None for Web Mercator
+                TransverseMercator::for_crs(crs)
+                    .map(|projection| projection.forward(lat, lon))
+                    .unwrap_or_default()
+            }
+            _ => TransverseMercator::for_crs(crs)
+                .map(|projection| projection.forward(self.lat, self.lon))
+                .unwrap_or_default(),
+        };
+
+        ProjectedCoordinate { x, y, crs }
+    }
+
+    /// Web Mercator (EPSG:3857) coordinate
+    pub fn to_web_mercator(&self) -> ProjectedCoordinate {
+        self.project(Crs::WebMercator)
+    }
+
+    /// UTM coordinate in the zone the coordinate is located in
+    pub fn to_utm(&self) -> ProjectedCoordinate {
+        self.project(Crs::Utm {
+            zone: self.utm_zone(),
+            north: self.lat >= 0_f64,
+        })
+    }
+
+    /// Gauss-Krüger coordinate in the zone the coordinate is located in
+    pub fn to_gauss_krueger(&self) -> ProjectedCoordinate {
+        self.project(Crs::GaussKrueger {
+            zone: self.gauss_krueger_zone(),
+        })
+    }
+}
+
+impl ProjectedCoordinate {
+    /// Converts the coordinate back into WGS84
+    pub fn to_wgs84(&self) -> Coordinate {
+        let (lat, lon) = match self.crs {
+            Crs::WebMercator => (
+                (2_f64 * (self.y / WEB_MERCATOR_RADIUS).exp().atan() - std::f64::consts::FRAC_PI_2)
+                    .to_degrees(),
+                (self.x / WEB_MERCATOR_RADIUS).to_degrees(),
+            ),
+            Crs::GaussKrueger { .. } => {
+                let (lat, lon) = TransverseMercator::for_crs(self.crs)
+                    .map(|projection| projection.inverse(self.x, self.y))
+                    .unwrap_or_default();
+                let dhdn = to_geocentric(lat, lon, BESSEL);
+                from_geocentric(helmert(dhdn, DHDN_TO_WGS84, 1_f64), WGS84)
+            }
+            _ => TransverseMercator::for_crs(self.crs)
+                .map(|projection| projection.inverse(self.x, self.y))
+                .unwrap_or_default(),
+        };
+
+        Coordinate { lat, lon }
+    }
+
+    /// Converts the coordinate into a different coordinate reference system
+    pub fn reproject(&self, crs: Crs) -> ProjectedCoordinate {
+        self.to_wgs84().project(crs)
+    }
+}
+
+/// Anything with a WGS84 position
+pub trait Projectable {
+    /// WGS84 position
+    fn coordinate(&self) -> Coordinate;
+
+    /// Position in the given coordinate reference system
+    fn project(&self, crs: Crs) -> ProjectedCoordinate {
+        self.coordinate().project(crs)
+    }
+}
+
+macro_rules! impl_projectable {
+    ($($name:ty),*) => {
+        $(
+            impl Projectable for $name {
+                fn coordinate(&self) -> Coordinate {
+                    Coordinate::new(self.lat, self.lon)
+                }
+            }
+        )*
+    };
+}
+
+impl_projectable!(
+    Coordinate,
+    TransmissionLocation,
+    InsertTransmissionLocation,
+    TransmissionLocationRaw,
+    InsertTransmissionLocationRaw,
+    ApiTransmissionLocation,
+    GpsPoint,
+    InsertGpsPoint,
+    Waypoint,
+    Region,
+    Station
+);
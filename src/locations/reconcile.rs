@@ -0,0 +1,231 @@
+//! This module reconciles freshly estimated transmission locations with the ones already stored in
+//! `r09_transmission_locations`. Rows marked as `ground_truth` are never overwritten, estimates
+//! that disagree with them by more than [`ReconcileConfig::conflict_distance`] are flagged as
+//! conflicts. Estimates outside the boundary of their region are dropped. The result is an
+//! [`UpsertPlan`] which can be inspected and then executed against the database.
+
+use super::region::Region;
+use super::{
+    DistanceFrom, InsertTransmissionLocation, TransmissionLocation,
+    REGION_POSITION_UNIQUE_CONSTRAINT,
+};
+
+use diesel::upsert::on_constraint;
+use diesel::{Connection, ExpressionMethods, PgConnection, QueryDsl, RunQueryDsl};
+use std::collections::HashMap;
+
+/// Default distance in meters at which an estimate is considered to disagree with ground truth
+pub const DEFAULT_CONFLICT_DISTANCE: f64 = 100_f64;
+/// Default distance in meters an estimate has to move a stored location before it is updated
+pub const DEFAULT_UPDATE_DISTANCE: f64 = 1_f64;
+
+/// Thresholds used while building the [`UpsertPlan`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReconcileConfig {
+    /// distance in meters between a ground truth location and an estimate, above which the
+    /// estimate is reported as [`UpsertAction::Conflict`]
+    pub conflict_distance: f64,
+    /// minimal distance in meters between a stored location and an estimate for the stored
+    /// location to be updated
+    pub update_distance: f64,
+}
+
+impl Default for ReconcileConfig {
+    fn default() -> Self {
+        ReconcileConfig {
+            conflict_distance: DEFAULT_CONFLICT_DISTANCE,
+            update_distance: DEFAULT_UPDATE_DISTANCE,
+        }
+    }
+}
+
+/// What should happen to a single estimate
+#[derive(Debug, Clone)]
+pub enum UpsertAction {
+    /// There is no location for this region and reporting point yet
+    Insert(InsertTransmissionLocation),
+    /// The stored, inferred location should be moved to the estimate
+    Update {
+        /// location currently stored in the database
+        current: TransmissionLocation,
+        /// the new estimate
+        estimate: InsertTransmissionLocation,
+    },
+    /// Nothing to do, either the stored location is ground truth and agrees with the estimate,
+    /// or the estimate moved less than [`ReconcileConfig::update_distance`]
+    Skip {
+        /// location currently stored in the database
+        current: TransmissionLocation,
+        /// the new estimate
+        estimate: InsertTransmissionLocation,
+    },
+    /// The estimate disagrees with a ground truth location. The ground truth is kept, but either
+    /// the ground truth or the raw data should probably be looked at by a human.
+    Conflict {
+        /// ground truth location stored in the database
+        ground_truth: TransmissionLocation,
+        /// the disagreeing estimate
+        estimate: InsertTransmissionLocation,
+        /// distance in meters between both
+        distance: f64,
+    },
+    /// The estimate lies outside the boundary of its region and is dropped
+    OutsideRegion(InsertTransmissionLocation),
+}
+
+/// Number of rows touched by [`UpsertPlan::execute`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UpsertSummary {
+    /// inserted rows
+    pub inserted: usize,
+    /// updated rows
+    pub updated: usize,
+    /// estimates that were skipped
+    pub skipped: usize,
+    /// estimates conflicting with ground truth
+    pub conflicts: usize,
+    /// estimates outside the boundary of their region
+    pub outside: usize,
+}
+
+/// List of actions that bring `r09_transmission_locations` in line with the estimates
+#[derive(Debug, Clone, Default)]
+pub struct UpsertPlan {
+    /// one action per estimate
+    pub actions: Vec<UpsertAction>,
+}
+
+impl UpsertPlan {
+    /// Compares the estimates to the currently stored locations and decides on an action for
+    /// every estimate. If several estimates exist for the same region and reporting point, only
+    /// the last one is kept.
+    pub fn new(
+        current: &[TransmissionLocation],
+        estimates: Vec<InsertTransmissionLocation>,
+        config: &ReconcileConfig,
+    ) -> Self {
+        let stored: HashMap<(i64, i32), &TransmissionLocation> = current
+            .iter()
+            .map(|location| ((location.region, location.reporting_point), location))
+            .collect();
+
+        let mut deduped: HashMap<(i64, i32), InsertTransmissionLocation> = HashMap::new();
+        for estimate in estimates {
+            deduped.insert((estimate.region, estimate.reporting_point), estimate);
+        }
+        let mut estimates: Vec<InsertTransmissionLocation> = deduped.into_values().collect();
+        estimates.sort_by_key(|estimate| (estimate.region, estimate.reporting_point));
+
+        let actions = estimates
+            .into_iter()
+            .map(|mut estimate| {
+                let Some(current) = stored.get(&(estimate.region, estimate.reporting_point)) else {
+                    estimate.id = None;
+                    return UpsertAction::Insert(estimate);
+                };
+                let current = (*current).clone();
+                let distance =
+                    (current.lat, current.lon).distance_from((estimate.lat, estimate.lon));
+
+                if current.ground_truth && distance > config.conflict_distance {
+                    UpsertAction::Conflict {
+                        ground_truth: current,
+                        estimate,
+                        distance,
+                    }
+                } else if current.ground_truth || distance < config.update_distance {
+                    UpsertAction::Skip { current, estimate }
+                } else {
+                    UpsertAction::Update { current, estimate }
+                }
+            })
+            .collect();
+
+        UpsertPlan { actions }
+    }
+
+    /// Drops inserts and updates whose estimate lies outside the boundary of the region, see
+    /// [`Region::contains`]. Conflicts are kept, as the ground truth deserves a look anyway.
+    pub fn restrict_to(&mut self, region: &Region) {
+        for action in &mut self.actions {
+            let estimate = match action {
+                UpsertAction::Insert(estimate) | UpsertAction::Update { estimate, .. } => estimate,
+                _ => continue,
+            };
+            if estimate.region == region.id && !region.contains(estimate) {
+                *action = UpsertAction::OutsideRegion(estimate.clone());
+            }
+        }
+    }
+
+    /// Loads the region and its stored locations and builds the plan, see [`UpsertPlan::new`]
+    /// and [`UpsertPlan::restrict_to`]. Estimates from other regions are ignored.
+    pub fn from_postgres(
+        database_connection: &mut PgConnection,
+        region_id: i64,
+        estimates: Vec<InsertTransmissionLocation>,
+        config: &ReconcileConfig,
+    ) -> Result<Self, diesel::result::Error> {
+        use crate::schema::{r09_transmission_locations, regions};
+
+        let region: Region = regions::table
+            .filter(regions::id.eq(region_id))
+            .first(database_connection)?;
+        let current: Vec<TransmissionLocation> = r09_transmission_locations::table
+            .filter(r09_transmission_locations::region.eq(region_id))
+            .load(database_connection)?;
+        let estimates = estimates
+            .into_iter()
+            .filter(|estimate| estimate.region == region_id)
+            .collect();
+
+        let mut plan = Self::new(&current, estimates, config);
+        plan.restrict_to(&region);
+        Ok(plan)
+    }
+
+    /// Returns all conflicts with ground truth locations
+    pub fn conflicts(&self) -> impl Iterator<Item = &UpsertAction> {
+        self.actions
+            .iter()
+            .filter(|action| matches!(action, UpsertAction::Conflict { .. }))
+    }
+
+    /// Runs the plan in a single transaction. Inserts do nothing if a location for the region and
+    /// reporting point was added in the meantime, updates never touch rows that became ground
+    /// truth in the meantime.
+    pub fn execute(
+        &self,
+        database_connection: &mut PgConnection,
+    ) -> Result<UpsertSummary, diesel::result::Error> {
+        use crate::schema::r09_transmission_locations::dsl::*;
+
+        database_connection.transaction(|connection| {
+            let mut summary = UpsertSummary::default();
+            for action in &self.actions {
+                match action {
+                    UpsertAction::Insert(estimate) => {
+                        summary.inserted += diesel::insert_into(r09_transmission_locations)
+                            .values(estimate)
+                            .on_conflict(on_constraint(REGION_POSITION_UNIQUE_CONSTRAINT))
+                            .do_nothing()
+                            .execute(connection)?;
+                    }
+                    UpsertAction::Update { current, estimate } => {
+                        summary.updated += diesel::update(
+                            r09_transmission_locations
+                                .filter(id.eq(current.id))
+                                .filter(ground_truth.eq(false)),
+                        )
+                        .set((lat.eq(estimate.lat), lon.eq(estimate.lon)))
+                        .execute(connection)?;
+                    }
+                    UpsertAction::Skip { .. } => summary.skipped += 1,
+                    UpsertAction::Conflict { .. } => summary.conflicts += 1,
+                    UpsertAction::OutsideRegion(_) => summary.outside += 1,
+                }
+            }
+            Ok(summary)
+        })
+    }
+}
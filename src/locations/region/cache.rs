@@ -0,0 +1,407 @@
+//! This module holds the [`RegionCache`], a local copy of all regions stored in
+//! [`REGION_CACHE_FILE`]. It is filled from a [`RegionSource`] and refreshed once it is older than
+//! the configured TTL, if refreshing fails the stale cache is used. The cache file is replaced
+//! atomically, so concurrent readers never see a half written file.
+//!
+//! Refreshing is a conditional request where the source supports it: the `ETag` and
+//! `Last-Modified` headers of the last response are stored in the cache file and sent along, so
+//! an unchanged region list isn't downloaded again. The file is versioned
+//! ([`REGION_CACHE_VERSION`]) and tagged with the [`SCHEMA`] of the regions, caches written by an
+//! older version of this crate are migrated, incompatible ones are discarded and fetched again.
+//!
+//! The blocking functions talk to the datacare API directly and must not be used inside tokio,
+//! services should use [`RegionCache::load`] instead.
+
+use super::source::{
+    conditional_headers, response_validators, FetchResult, RegionSource, REGION_API_ENDPOINT,
+};
+use super::Region;
+use crate::locations::SCHEMA;
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use chrono::prelude::{DateTime, Utc};
+use chrono::Duration;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// Default region cache lifetime in seconds (24h)
+pub const REGION_CACHE_EXPIRATION: i64 = 24 * 60 * 60;
+/// Name for a cache file
+pub const REGION_CACHE_FILE: &str = "region_cache.json";
+/// Version of the cache file format, files without version are version `1`
+pub const REGION_CACHE_VERSION: u64 = 2;
+
+/// HTTP validators of the cached response, sent along when revalidating the cache
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct CacheValidators {
+    /// `ETag` header of the response
+    pub etag: Option<String>,
+    /// `Last-Modified` header of the response
+    pub last_modified: Option<String>,
+}
+
+/// The struct that deserializes into json containing cache for region meta information. See
+/// [`Region`] for details.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RegionCache {
+    /// Hashmap of region ID to corresponding metadata
+    pub metadata: HashMap<i64, Region>,
+    /// Timestamp when the cache was last refreshed
+    pub modified: DateTime<Utc>,
+    /// validators of the response the regions were read from
+    #[serde(default)]
+    pub validators: CacheValidators,
+}
+
+/// Layout of the cache file
+#[derive(Serialize)]
+struct CacheFile<'a> {
+    version: u64,
+    schema: &'a str,
+    #[serde(flatten)]
+    cache: &'a RegionCache,
+}
+
+/// Error enum for [`RegionCache`] methods and [`RegionSource`]s. Right now it just returns
+/// original error wrapped into an enum variant.
+#[derive(Debug)]
+pub enum RegionCacheError {
+    /// See [`serde_json::Error`]
+    SerdeJsonError(serde_json::Error),
+    /// See [`reqwest::Error`]
+    ReqwestError(reqwest::Error),
+    /// See [`std::io::Error`]
+    IOError(std::io::Error),
+    /// See [`diesel::result::Error`]
+    DieselError(diesel::result::Error),
+    /// See [`diesel::r2d2::PoolError`]
+    PoolError(diesel::r2d2::PoolError),
+    /// See [`tokio::task::JoinError`]
+    JoinError(tokio::task::JoinError),
+    /// The cache file was written with an unknown format version or region schema
+    IncompatibleCache {
+        /// format version of the file
+        version: u64,
+        /// schema of the regions in the file
+        schema: String,
+    },
+}
+
+impl From<reqwest::Error> for RegionCacheError {
+    fn from(e: reqwest::Error) -> RegionCacheError {
+        RegionCacheError::ReqwestError(e)
+    }
+}
+impl From<serde_json::Error> for RegionCacheError {
+    fn from(e: serde_json::Error) -> RegionCacheError {
+        RegionCacheError::SerdeJsonError(e)
+    }
+}
+impl From<std::io::Error> for RegionCacheError {
+    fn from(e: std::io::Error) -> RegionCacheError {
+        RegionCacheError::IOError(e)
+    }
+}
+impl From<diesel::result::Error> for RegionCacheError {
+    fn from(e: diesel::result::Error) -> RegionCacheError {
+        RegionCacheError::DieselError(e)
+    }
+}
+impl From<diesel::r2d2::PoolError> for RegionCacheError {
+    fn from(e: diesel::r2d2::PoolError) -> RegionCacheError {
+        RegionCacheError::PoolError(e)
+    }
+}
+impl From<tokio::task::JoinError> for RegionCacheError {
+    fn from(e: tokio::task::JoinError) -> RegionCacheError {
+        RegionCacheError::JoinError(e)
+    }
+}
+
+/// Where the cache lives and how long it stays fresh
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegionCacheConfig {
+    /// directory holding [`REGION_CACHE_FILE`], created if missing
+    pub cache_dir: PathBuf,
+    /// age after which the cache is refreshed
+    pub ttl: Duration,
+}
+
+impl RegionCacheConfig {
+    /// Config for the given directory with the default lifetime of [`REGION_CACHE_EXPIRATION`]
+    pub fn new(cache_dir: PathBuf) -> Self {
+        RegionCacheConfig {
+            cache_dir,
+            ttl: Duration::seconds(REGION_CACHE_EXPIRATION),
+        }
+    }
+}
+
+/// Writes the file by writing a temporary file next to it and renaming it afterwards. Every
+/// write uses its own temporary file, so concurrent writers in the same process don't interfere.
+pub(crate) fn write_atomic(path: &Path, contents: &[u8]) -> Result<(), std::io::Error> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(format!(".{}.tmp", Uuid::new_v4()));
+    let temporary = PathBuf::from(temporary);
+
+    let mut file = fs::File::create_new(&temporary)?;
+    file.write_all(contents)
+        .and_then(|_| file.sync_all())
+        .and_then(|_| fs::rename(&temporary, path))
+        .inspect_err(|_| {
+            let _ = fs::remove_file(&temporary);
+        })
+}
+
+impl RegionCache {
+    /// refreshes the region data cache from the datacare API unconditionaly.
+    pub fn get_region_cache(
+        datacare_api: &str,
+        cache_dir: PathBuf,
+    ) -> Result<Self, RegionCacheError> {
+        Self::revalidate_region_cache(None, datacare_api, cache_dir)
+    }
+
+    /// Refreshes the region data cache from the datacare API with a conditional request. If the
+    /// regions didn't change, the given cache is kept and only its timestamp is updated.
+    fn revalidate_region_cache(
+        cache: Option<Self>,
+        datacare_api: &str,
+        cache_dir: PathBuf,
+    ) -> Result<Self, RegionCacheError> {
+        let api_url = format!("{datacare_api}{REGION_API_ENDPOINT}");
+        let validators = cache
+            .as_ref()
+            .map(|cache| cache.validators.clone())
+            .unwrap_or_default();
+        let response = reqwest::blocking::Client::new()
+            .get(api_url)
+            .headers(conditional_headers(&validators))
+            .send()?
+            .error_for_status()?;
+
+        let fetched = if response.status() == reqwest::StatusCode::NOT_MODIFIED {
+            FetchResult::NotModified
+        } else {
+            let validators = response_validators(response.headers());
+            FetchResult::Modified {
+                metadata: serde_json::from_str(&response.text()?)?,
+                validators,
+            }
+        };
+
+        let timestamped_region_cache = Self::apply(cache, fetched)?;
+
+        // try to write out the cache
+        let mut cache_file = cache_dir;
+        cache_file.push(REGION_CACHE_FILE);
+        write_atomic(&cache_file, &timestamped_region_cache.to_file_contents()?)?;
+
+        Ok(timestamped_region_cache)
+    }
+
+    /// Cache after a fetch, [`FetchResult::NotModified`] requires a previous cache
+    fn apply(cache: Option<Self>, fetched: FetchResult) -> Result<Self, RegionCacheError> {
+        match (fetched, cache) {
+            (
+                FetchResult::Modified {
+                    metadata,
+                    validators,
+                },
+                _,
+            ) => Ok(Self {
+                metadata,
+                modified: Utc::now(),
+                validators,
+            }),
+            (FetchResult::NotModified, Some(cache)) => Ok(Self {
+                modified: Utc::now(),
+                ..cache
+            }),
+            (FetchResult::NotModified, None) => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "source reported unchanged regions without a cached copy",
+            )
+            .into()),
+        }
+    }
+
+    /// Serializes the cache in the current file format
+    fn to_file_contents(&self) -> Result<Vec<u8>, serde_json::Error> {
+        serde_json::to_vec(&CacheFile {
+            version: REGION_CACHE_VERSION,
+            schema: SCHEMA,
+            cache: self,
+        })
+    }
+
+    /// Parses a cache file of any known format version. Files without version predate the
+    /// validators, which are left empty.
+    fn from_file_contents(contents: &str) -> Result<Self, RegionCacheError> {
+        let value: serde_json::Value = serde_json::from_str(contents)?;
+        let version = value.get("version").and_then(|v| v.as_u64()).unwrap_or(1);
+        let schema = value
+            .get("schema")
+            .and_then(|s| s.as_str())
+            .unwrap_or(SCHEMA);
+        if version > REGION_CACHE_VERSION || schema != SCHEMA {
+            return Err(RegionCacheError::IncompatibleCache {
+                version,
+                schema: schema.to_string(),
+            });
+        }
+        Ok(serde_json::from_value(value)?)
+    }
+
+    /// Read region cache from local cache path.
+    pub fn read_region_cache(cache_dir: PathBuf) -> Result<Self, RegionCacheError> {
+        let mut cache_file_path = cache_dir;
+        cache_file_path.push(REGION_CACHE_FILE);
+        let cache_file_string = fs::read_to_string(cache_file_path)?;
+
+        Self::from_file_contents(&cache_file_string)
+    }
+
+    /// Gets the cache for the region data. First looks if it exists already, if not (or if it is
+    /// older than [`REGION_CACHE_EXPIRATION`]) tries to update it. Any Errs are propagated up.
+    pub fn update_region_cache(
+        datacare_api: &str,
+        cache_dir: PathBuf,
+    ) -> Result<Self, RegionCacheError> {
+        Self::update_region_cache_with(datacare_api, &RegionCacheConfig::new(cache_dir))
+    }
+
+    /// Like [`RegionCache::update_region_cache`], but with the cache directory and lifetime taken
+    /// from the given config.
+    pub fn update_region_cache_with(
+        datacare_api: &str,
+        config: &RegionCacheConfig,
+    ) -> Result<Self, RegionCacheError> {
+        let cache_dir = config.cache_dir.clone();
+        let mut cache_file_path = cache_dir.clone();
+        cache_file_path.push(REGION_CACHE_FILE);
+
+        // make sure that the dir exists
+        fs::create_dir_all(cache_dir.clone())?;
+
+        // try to read the cache
+        let cache_to_return = match Self::read_region_cache(cache_dir.clone()) {
+            Ok(read_cache) => {
+                // check that cache is fresh enough
+                if read_cache.is_fresh(config.ttl) {
+                    read_cache
+                } else {
+                    // try to update the cache
+                    match Self::revalidate_region_cache(
+                        Some(read_cache.clone()),
+                        datacare_api,
+                        cache_dir,
+                    ) {
+                        Ok(new_cache) => new_cache,
+                        Err(e) => {
+                            warn!("While trying to get the cache from datacare API: {e:?}");
+                            warn!("Using stale cache from {}", read_cache.modified);
+                            read_cache
+                        }
+                    }
+                }
+            }
+            Err(e) => {
+                warn!("While trying to get local region metadata cache: {e:?}");
+                info!("Trying to refresh region metadata cache");
+                Self::discard_incompatible(&e, &cache_file_path);
+                Self::get_region_cache(datacare_api, cache_dir)?
+            }
+        };
+
+        Ok(cache_to_return)
+    }
+
+    /// Returns true if the cache is younger than `ttl`
+    pub fn is_fresh(&self, ttl: Duration) -> bool {
+        Utc::now() - self.modified < ttl
+    }
+
+    /// Removes cache files that can't be read by this version of the crate
+    fn discard_incompatible(error: &RegionCacheError, cache_file: &Path) {
+        if matches!(
+            error,
+            RegionCacheError::IncompatibleCache { .. } | RegionCacheError::SerdeJsonError(_)
+        ) {
+            warn!(
+                "Discarding incompatible region cache {}",
+                cache_file.display()
+            );
+            let _ = fs::remove_file(cache_file);
+        }
+    }
+
+    /// Refreshes the cache from the source unconditionally and writes it to `cache_dir`
+    pub async fn fetch(
+        source: &impl RegionSource,
+        cache_dir: &Path,
+    ) -> Result<Self, RegionCacheError> {
+        Self::revalidate(None, source, cache_dir).await
+    }
+
+    /// Refreshes the cache from the source with the validators of the given cache and writes it
+    /// to `cache_dir`. If the regions didn't change, the given cache is kept and only its
+    /// timestamp is updated.
+    pub async fn revalidate(
+        cache: Option<Self>,
+        source: &impl RegionSource,
+        cache_dir: &Path,
+    ) -> Result<Self, RegionCacheError> {
+        let validators = cache
+            .as_ref()
+            .map(|cache| cache.validators.clone())
+            .unwrap_or_default();
+        let cache = Self::apply(cache, source.fetch(&validators).await?)?;
+
+        tokio::fs::create_dir_all(cache_dir).await?;
+        let cache_file = cache_dir.join(REGION_CACHE_FILE);
+        let contents = cache.to_file_contents()?;
+        tokio::task::spawn_blocking(move || write_atomic(&cache_file, &contents)).await??;
+
+        Ok(cache)
+    }
+
+    /// Reads the cache from `cache_dir`
+    pub async fn read(cache_dir: &Path) -> Result<Self, RegionCacheError> {
+        let contents = tokio::fs::read_to_string(cache_dir.join(REGION_CACHE_FILE)).await?;
+        Self::from_file_contents(&contents)
+    }
+
+    /// Async variant of [`RegionCache::update_region_cache`]. Reads the cache and revalidates it
+    /// against the source if it is older than the configured TTL, if refreshing fails the stale
+    /// cache is returned. Missing or incompatible caches are fetched again.
+    pub async fn load(
+        source: &impl RegionSource,
+        config: &RegionCacheConfig,
+    ) -> Result<Self, RegionCacheError> {
+        match Self::read(&config.cache_dir).await {
+            Ok(cache) if cache.is_fresh(config.ttl) => Ok(cache),
+            Ok(cache) => {
+                match Self::revalidate(Some(cache.clone()), source, &config.cache_dir).await {
+                    Ok(new_cache) => Ok(new_cache),
+                    Err(e) => {
+                        warn!("While trying to refresh the region cache: {e:?}");
+                        warn!("Using stale cache from {}", cache.modified);
+                        Ok(cache)
+                    }
+                }
+            }
+            Err(e) => {
+                warn!("While trying to read local region metadata cache: {e:?}");
+                info!("Trying to refresh region metadata cache");
+                Self::discard_incompatible(&e, &config.cache_dir.join(REGION_CACHE_FILE));
+                Self::fetch(source, &config.cache_dir).await
+            }
+        }
+    }
+}
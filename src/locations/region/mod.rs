@@ -0,0 +1,98 @@
+//! This module holds structs and associated stuff for storing the region metadata ([`Region`] and
+//! [`InsertRegion`]), as well as the [`RegionCache`] which keeps a local copy of all regions and
+//! the [`RegionSource`]s it is filled from. Services share the regions through a
+//! [`RegionRegistry`], which keeps them up to date in the background.
+
+pub mod cache;
+pub mod registry;
+pub mod source;
+
+pub use cache::{
+    CacheValidators, RegionCache, RegionCacheConfig, RegionCacheError, REGION_CACHE_EXPIRATION,
+    REGION_CACHE_FILE, REGION_CACHE_VERSION,
+};
+pub use registry::{RegionChange, RegionRegistry, RegistryMetrics};
+pub use source::{FetchResult, FileSource, HttpSource, PostgresSource, RegionSource};
+
+use super::geojson::Geometry;
+use crate::schema::*;
+use crate::telegrams::r09::R09Type;
+
+use diesel::{Insertable, Queryable};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+/// Struct holding the information for a region.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Queryable, ToSchema)]
+pub struct Region {
+    /// Unique region identifier this is really just an arbitrery number.
+    #[diesel(deserialize_as = i64)]
+    pub id: i64,
+    /// Name of the region / city
+    pub name: String,
+    /// Name of the operator in the city e.g DVB.
+    pub transport_company: String,
+    /// Name of the Regional operator e.g. VVO (Verkehrs Verbund Oberelbe)
+    /// which encompasses the transport_companty
+    pub regional_company: Option<String>,
+    /// The frequency the operator sends it VDV 420 traffic.
+    pub frequency: Option<i64>,
+    /// Which R09 types are used look at [`R09Type`][crate::telegrams::r09::R09Type] for possible
+    /// values
+    pub r09_type: Option<R09Type>,
+    /// Which encoding this regions uses. Look at [`Encoding`] for possible values.
+    pub encoding: Option<i32>,
+    /// This value is set to true if the region is deleted.
+    pub deactivated: bool,
+    /// latitude of the city center
+    pub lat: f64,
+    /// longtitude of the city center
+    pub lon: f64,
+    /// zoom level
+    pub zoom: f64,
+    /// if the region is work in progress or not
+    pub work_in_progress: bool,
+    /// optional outline of the region as `Polygon` or `MultiPolygon`, see
+    /// [`boundary`](crate::locations::boundary)
+    #[serde(default)]
+    #[schema(value_type = Option<Object>)]
+    pub boundary: Option<Geometry>,
+}
+
+/// This struct is the same as [`Region`] but with the difference that id is optional
+/// this is required to use the auto increment function from postgres
+#[derive(Debug, Clone, Serialize, Deserialize, Insertable)]
+#[diesel(table_name = regions)]
+pub struct InsertRegion {
+    /// Unqiue region identifier which is nullable to let postgres set a value for us.
+    #[diesel(deserialize_as = i64)]
+    pub id: Option<i64>,
+    /// Name of the region / city
+    pub name: String,
+    /// Name of the operator in the city e.g DVB.
+    pub transport_company: String,
+    /// Name of the Regional operator e.g. VVO (Verkehrs Verbund Oberelbe)
+    /// which encompasses the transport_companty
+    pub regional_company: Option<String>,
+    /// The frequency the operator sends it VDV 420 traffic.
+    pub frequency: Option<i64>,
+    /// Which R09 types are used look at [`R09Type`][crate::telegrams::r09::R09Type] for possible
+    /// values
+    pub r09_type: Option<R09Type>,
+    /// Which encoding this regions used look at [`Encoding`] for possible values.
+    pub encoding: Option<i32>,
+    /// This value is set to true if the region is deleted.
+    pub deactivated: bool,
+    /// latitude of the city center
+    pub lat: f64,
+    /// longtitude of the city center
+    pub lon: f64,
+    /// zoom level
+    pub zoom: f64,
+    /// if the region is work in progress or not
+    pub work_in_progress: bool,
+    /// optional outline of the region as `Polygon` or `MultiPolygon`, see
+    /// [`boundary`](crate::locations::boundary)
+    #[serde(default)]
+    pub boundary: Option<Geometry>,
+}
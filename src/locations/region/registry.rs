@@ -0,0 +1,248 @@
+//! This module holds the [`RegionRegistry`], a cheap to clone handle to the current
+//! [`RegionCache`] for services that look up regions on every telegram. It is refreshed from a
+//! [`RegionSource`] in the background, keeps serving the last known regions if refreshing fails
+//! and reports what changed between two refreshes as [`RegionChange`]s.
+
+use super::cache::{RegionCache, RegionCacheConfig, RegionCacheError};
+use super::source::RegionSource;
+use super::Region;
+
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use chrono::prelude::{DateTime, Utc};
+use chrono::Duration;
+use log::{info, warn};
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+
+/// Seconds to wait before retrying a failed background refresh
+pub const REGION_REFRESH_RETRY: i64 = 60;
+/// Number of changes buffered per subscriber, slower subscribers miss changes
+pub const REGION_CHANGE_CAPACITY: usize = 64;
+
+/// Difference between two versions of the region cache
+#[derive(Debug, Clone, PartialEq)]
+pub enum RegionChange {
+    /// region appeared
+    Added(Region),
+    /// region with the given id disappeared
+    Removed(i64),
+    /// region was deactivated
+    Deactivated(Region),
+    /// deactivated region was activated again
+    Activated(Region),
+    /// frequency of the region changed
+    FrequencyChanged {
+        /// the updated region
+        region: Region,
+        /// frequency before the change
+        previous: Option<i64>,
+    },
+    /// anything else about the region changed
+    Updated(Region),
+}
+
+impl RegionChange {
+    /// Compares two versions of the regions, ordered by region id. A region that was deactivated
+    /// and changed its frequency at the same time is only reported as deactivated.
+    pub fn between(old: &HashMap<i64, Region>, new: &HashMap<i64, Region>) -> Vec<RegionChange> {
+        let mut ids: Vec<i64> = old.keys().chain(new.keys()).copied().collect();
+        ids.sort_unstable();
+        ids.dedup();
+
+        ids.into_iter()
+            .filter_map(|id| match (old.get(&id), new.get(&id)) {
+                (None, Some(region)) => Some(RegionChange::Added(region.clone())),
+                (Some(_), None) => Some(RegionChange::Removed(id)),
+                (Some(before), Some(after)) if before != after => {
+                    Some(match (before.deactivated, after.deactivated) {
+                        (false, true) => RegionChange::Deactivated(after.clone()),
+                        (true, false) => RegionChange::Activated(after.clone()),
+                        _ if before.frequency != after.frequency => {
+                            RegionChange::FrequencyChanged {
+                                region: after.clone(),
+                                previous: before.frequency,
+                            }
+                        }
+                        _ => RegionChange::Updated(after.clone()),
+                    })
+                }
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+/// Health of the registry
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RegistryMetrics {
+    /// successful refreshes
+    pub refreshes: u64,
+    /// failed refreshes
+    pub failed_refreshes: u64,
+    /// failed refreshes since the last successful one
+    pub consecutive_failures: u64,
+    /// time of the last successful refresh
+    pub last_refresh: Option<DateTime<Utc>>,
+    /// error of the last failed refresh
+    pub last_error: Option<String>,
+}
+
+/// Shared state behind the handle
+struct State {
+    cache: Arc<RegionCache>,
+    names: HashMap<String, i64>,
+    metrics: RegistryMetrics,
+}
+
+impl State {
+    fn new(cache: RegionCache) -> Self {
+        State {
+            names: Self::index_names(&cache),
+            cache: Arc::new(cache),
+            metrics: RegistryMetrics::default(),
+        }
+    }
+
+    /// Lowercase name of every region, active regions win over deactivated ones of the same name
+    fn index_names(cache: &RegionCache) -> HashMap<String, i64> {
+        let mut regions: Vec<&Region> = cache.metadata.values().collect();
+        regions.sort_by_key(|region| (!region.deactivated, region.id));
+        regions
+            .into_iter()
+            .map(|region| (region.name.to_lowercase(), region.id))
+            .collect()
+    }
+}
+
+/// Handle to the shared regions, clones refer to the same regions
+#[derive(Clone)]
+pub struct RegionRegistry {
+    state: Arc<RwLock<State>>,
+    changes: broadcast::Sender<RegionChange>,
+}
+
+impl RegionRegistry {
+    /// Creates a registry serving the given cache
+    pub fn new(cache: RegionCache) -> Self {
+        let (changes, _) = broadcast::channel(REGION_CHANGE_CAPACITY);
+        RegionRegistry {
+            state: Arc::new(RwLock::new(State::new(cache))),
+            changes,
+        }
+    }
+
+    /// Creates a registry from the local cache, see [`RegionCache::load`]
+    pub async fn load(
+        source: &impl RegionSource,
+        config: &RegionCacheConfig,
+    ) -> Result<Self, RegionCacheError> {
+        Ok(Self::new(RegionCache::load(source, config).await?))
+    }
+
+    fn read(&self) -> std::sync::RwLockReadGuard<'_, State> {
+        // the lock is only held for plain assignments, so it can't be poisoned in a harmful way
+        self.state.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn write(&self) -> std::sync::RwLockWriteGuard<'_, State> {
+        self.state.write().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Looks up a region by id
+    pub fn get(&self, id: i64) -> Option<Region> {
+        self.read().cache.metadata.get(&id).cloned()
+    }
+
+    /// Looks up a region by name, ignoring case
+    pub fn by_name(&self, name: &str) -> Option<Region> {
+        let state = self.read();
+        let id = state.names.get(&name.to_lowercase())?;
+        state.cache.metadata.get(id).cloned()
+    }
+
+    /// Returns the current cache, which stays unchanged while the registry is refreshed
+    pub fn snapshot(&self) -> Arc<RegionCache> {
+        self.read().cache.clone()
+    }
+
+    /// Returns the current metrics
+    pub fn metrics(&self) -> RegistryMetrics {
+        self.read().metrics.clone()
+    }
+
+    /// Returns true if the served regions are older than `ttl`
+    pub fn is_stale(&self, ttl: Duration) -> bool {
+        !self.read().cache.is_fresh(ttl)
+    }
+
+    /// Subscribes to the changes of future refreshes
+    pub fn subscribe(&self) -> broadcast::Receiver<RegionChange> {
+        self.changes.subscribe()
+    }
+
+    /// Replaces the served regions and notifies subscribers about the changes
+    pub fn replace(&self, cache: RegionCache) -> Vec<RegionChange> {
+        let changes = {
+            let mut state = self.write();
+            let changes = RegionChange::between(&state.cache.metadata, &cache.metadata);
+            state.names = State::index_names(&cache);
+            state.cache = Arc::new(cache);
+            state.metrics.refreshes += 1;
+            state.metrics.consecutive_failures = 0;
+            state.metrics.last_refresh = Some(Utc::now());
+            changes
+        };
+
+        for change in &changes {
+            // no subscribers is fine
+            let _ = self.changes.send(change.clone());
+        }
+        changes
+    }
+
+    /// Revalidates the regions against the source, see [`RegionCache::revalidate`]. If that
+    /// fails, the current regions are kept and the failure is recorded in the metrics.
+    pub async fn refresh(
+        &self,
+        source: &impl RegionSource,
+        config: &RegionCacheConfig,
+    ) -> Result<Vec<RegionChange>, RegionCacheError> {
+        let current = RegionCache::clone(&self.snapshot());
+        match RegionCache::revalidate(Some(current), source, &config.cache_dir).await {
+            Ok(cache) => Ok(self.replace(cache)),
+            Err(e) => {
+                let mut state = self.write();
+                state.metrics.failed_refreshes += 1;
+                state.metrics.consecutive_failures += 1;
+                state.metrics.last_error = Some(format!("{e:?}"));
+                Err(e)
+            }
+        }
+    }
+
+    /// Spawns a task refreshing the regions whenever they are older than the TTL of the config.
+    /// Failed refreshes are retried after [`REGION_REFRESH_RETRY`] seconds.
+    pub fn spawn_refresh<S>(&self, source: S, config: RegionCacheConfig) -> JoinHandle<()>
+    where
+        S: RegionSource + Send + Sync + 'static,
+    {
+        let registry = self.clone();
+        tokio::spawn(async move {
+            loop {
+                let wait = if registry.read().metrics.consecutive_failures > 0 {
+                    Duration::seconds(REGION_REFRESH_RETRY)
+                } else {
+                    config.ttl - (Utc::now() - registry.read().cache.modified)
+                };
+                tokio::time::sleep(wait.to_std().unwrap_or_default()).await;
+
+                match registry.refresh(&source, &config).await {
+                    Ok(changes) => info!("Refreshed regions, {} changes", changes.len()),
+                    Err(e) => warn!("While refreshing regions, serving stale data: {e:?}"),
+                }
+            }
+        })
+    }
+}
@@ -0,0 +1,167 @@
+//! This module holds the [`RegionSource`] trait and the sources the
+//! [`RegionCache`](super::RegionCache) can be filled from: the datacare API ([`HttpSource`]), the
+//! database ([`PostgresSource`]) and a static file ([`FileSource`]), e.g. for tests or air-gapped
+//! deployments. Only the [`HttpSource`] supports conditional requests, the others always return
+//! the full region list.
+
+use super::cache::{CacheValidators, RegionCacheError};
+use super::Region;
+use crate::schema::regions;
+
+use diesel::r2d2::{ConnectionManager, Pool};
+use diesel::{PgConnection, RunQueryDsl};
+use reqwest::header::{HeaderMap, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+use std::collections::HashMap;
+use std::future::Future;
+use std::path::PathBuf;
+
+/// Endpoint of the datacare API listing all regions
+pub const REGION_API_ENDPOINT: &str = "/region";
+
+/// Result of [`RegionSource::fetch`]
+#[derive(Debug, Clone)]
+pub enum FetchResult {
+    /// the regions changed or the source doesn't support conditional requests
+    Modified {
+        /// all regions, keyed by their id
+        metadata: HashMap<i64, Region>,
+        /// validators to send along with the next request
+        validators: CacheValidators,
+    },
+    /// the regions didn't change since the response the validators belong to
+    NotModified,
+}
+
+/// Something all regions can be fetched from
+pub trait RegionSource {
+    /// Fetches all regions, unless they didn't change since the response the validators belong
+    /// to
+    fn fetch(
+        &self,
+        validators: &CacheValidators,
+    ) -> impl Future<Output = Result<FetchResult, RegionCacheError>> + Send;
+}
+
+/// Request headers of a conditional request
+pub(super) fn conditional_headers(validators: &CacheValidators) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    let mut insert = |name, value: &Option<String>| {
+        if let Some(value) = value.as_ref().and_then(|value| value.parse().ok()) {
+            headers.insert(name, value);
+        }
+    };
+    insert(IF_NONE_MATCH, &validators.etag);
+    insert(IF_MODIFIED_SINCE, &validators.last_modified);
+    headers
+}
+
+/// Validators of a response
+pub(super) fn response_validators(headers: &HeaderMap) -> CacheValidators {
+    let header = |name| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(String::from)
+    };
+    CacheValidators {
+        etag: header(ETAG),
+        last_modified: header(LAST_MODIFIED),
+    }
+}
+
+/// Fetches the regions from the datacare API
+#[derive(Debug, Clone)]
+pub struct HttpSource {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl HttpSource {
+    /// Source for the [`REGION_API_ENDPOINT`] of the datacare API at the given base url
+    pub fn new(datacare_api: &str) -> Self {
+        Self::with_endpoint(datacare_api, REGION_API_ENDPOINT)
+    }
+
+    /// Source for a custom endpoint of the datacare API at the given base url
+    pub fn with_endpoint(datacare_api: &str, endpoint: &str) -> Self {
+        HttpSource {
+            client: reqwest::Client::new(),
+            url: format!("{datacare_api}{endpoint}"),
+        }
+    }
+}
+
+impl RegionSource for HttpSource {
+    async fn fetch(&self, validators: &CacheValidators) -> Result<FetchResult, RegionCacheError> {
+        let response = self
+            .client
+            .get(&self.url)
+            .headers(conditional_headers(validators))
+            .send()
+            .await?
+            .error_for_status()?;
+        if response.status() == reqwest::StatusCode::NOT_MODIFIED {
+            return Ok(FetchResult::NotModified);
+        }
+
+        let validators = response_validators(response.headers());
+        Ok(FetchResult::Modified {
+            metadata: serde_json::from_str(&response.text().await?)?,
+            validators,
+        })
+    }
+}
+
+/// Loads the regions from the `regions` table, including deactivated ones
+#[derive(Clone)]
+pub struct PostgresSource {
+    pool: Pool<ConnectionManager<PgConnection>>,
+}
+
+impl PostgresSource {
+    /// Source using connections from the pool
+    pub fn new(pool: Pool<ConnectionManager<PgConnection>>) -> Self {
+        PostgresSource { pool }
+    }
+}
+
+impl RegionSource for PostgresSource {
+    async fn fetch(&self, _: &CacheValidators) -> Result<FetchResult, RegionCacheError> {
+        let pool = self.pool.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut database_connection = pool.get()?;
+            let regions: Vec<Region> = regions::table.load(&mut database_connection)?;
+            Ok(FetchResult::Modified {
+                metadata: regions
+                    .into_iter()
+                    .map(|region| (region.id, region))
+                    .collect(),
+                validators: CacheValidators::default(),
+            })
+        })
+        .await?
+    }
+}
+
+/// Reads the regions from a json file in the format of the datacare API
+#[derive(Debug, Clone)]
+pub struct FileSource {
+    path: PathBuf,
+}
+
+impl FileSource {
+    /// Source reading the given file
+    pub fn new(path: PathBuf) -> Self {
+        FileSource { path }
+    }
+}
+
+impl RegionSource for FileSource {
+    async fn fetch(&self, _: &CacheValidators) -> Result<FetchResult, RegionCacheError> {
+        let contents = tokio::fs::read_to_string(&self.path).await?;
+        Ok(FetchResult::Modified {
+            metadata: serde_json::from_str(&contents)?,
+            validators: CacheValidators::default(),
+        })
+    }
+}
@@ -0,0 +1,468 @@
+//! This module infers the order in which a line passes reporting points. The telegrams of every
+//! vehicle are split into trips, and the transitions between consecutive reporting points are
+//! counted per region, line and destination number. From these counts the [`RouteLearner`]
+//! derives the most likely sequence of reporting points (the main branch) and alternative
+//! branches, each point annotated with the share of trips that took this way.
+//!
+//! Learned sequences are persisted in the `r09_reporting_point_sequences` table, see
+//! [`SequencePoint`] and [`InsertSequencePoint`].
+
+use crate::schema::*;
+use crate::telegrams::r09::R09SaveTelegram;
+
+use chrono::{Duration, NaiveDateTime};
+use diesel::{
+    Connection, ExpressionMethods, Identifiable, Insertable, PgConnection, QueryDsl, Queryable,
+    RunQueryDsl,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use utoipa::ToSchema;
+
+/// Default maximum gap in milliseconds between two telegrams of the same trip (15 minutes)
+pub const DEFAULT_MAX_TRIP_GAP: i64 = 15 * 60 * 1000;
+/// Default minimal share of trips that have to take a different way, so it becomes a branch
+pub const DEFAULT_BRANCH_THRESHOLD: f64 = 0.2;
+
+/// This struct is used to query the learned reporting point sequences from the database. Every
+/// entry is a single reporting point at a specific position of a branch. Branch `0` is the main
+/// sequence, other branches start with the reporting point at which they diverge from the main
+/// sequence and, if they rejoin it, end with the reporting point where they rejoin.
+#[derive(Debug, Clone, Serialize, Deserialize, Queryable, Identifiable, ToSchema)]
+#[diesel(table_name = r09_reporting_point_sequences)]
+pub struct SequencePoint {
+    /// Primary key
+    pub id: i64,
+    /// ID of the region
+    pub region: i64,
+    /// line (ger. linie) of the vehicles
+    pub line: i32,
+    /// destination number of the vehicles
+    pub destination_number: i32,
+    /// branch of the sequence, `0` is the main sequence
+    pub branch: i32,
+    /// position inside the branch, starting at `0`
+    pub position: i32,
+    /// Reporting Point inside the r09 telegram (*meldepunkt*) ID
+    pub reporting_point: i32,
+    /// share of observed trips that went to this reporting point from the previous one
+    pub confidence: f64,
+}
+
+/// This struct is used to insert learned reporting point sequences, see [`SequencePoint`]
+#[derive(Debug, Clone, Serialize, Deserialize, Insertable)]
+#[diesel(table_name = r09_reporting_point_sequences)]
+pub struct InsertSequencePoint {
+    /// Primary key. During INSERT should be [`None`] so DB can auto-increment it
+    pub id: Option<i64>,
+    /// ID of the region
+    pub region: i64,
+    /// line (ger. linie) of the vehicles
+    pub line: i32,
+    /// destination number of the vehicles
+    pub destination_number: i32,
+    /// branch of the sequence, `0` is the main sequence
+    pub branch: i32,
+    /// position inside the branch, starting at `0`
+    pub position: i32,
+    /// Reporting Point inside the r09 telegram (*meldepunkt*) ID
+    pub reporting_point: i32,
+    /// share of observed trips that went to this reporting point from the previous one
+    pub confidence: f64,
+}
+
+/// Identifies the route of a line in one direction
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, ToSchema)]
+pub struct RouteKey {
+    /// ID of the region
+    pub region: i64,
+    /// line (ger. linie) of the vehicles
+    pub line: i32,
+    /// destination number of the vehicles
+    pub destination_number: i32,
+}
+
+/// Reporting point of a learned route
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct RoutePoint {
+    /// Reporting Point inside the r09 telegram (*meldepunkt*) ID
+    pub reporting_point: i32,
+    /// share of observed trips that went to this reporting point from the previous one
+    pub confidence: f64,
+}
+
+/// Learned sequence of reporting points of a line in one direction
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct LearnedRoute {
+    /// route identifier
+    pub key: RouteKey,
+    /// number of trips the route was learned from
+    pub trips: u64,
+    /// the main sequence is at index `0`, see [`SequencePoint`] for the layout of other branches
+    pub branches: Vec<Vec<RoutePoint>>,
+}
+
+/// Tuning knobs for the [`RouteLearner`]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct RouteLearnerConfig {
+    /// maximum gap in milliseconds between two telegrams of the same trip
+    pub max_trip_gap: i64,
+    /// minimal share of trips that have to take a different way, so it becomes a branch
+    pub branch_threshold: f64,
+}
+
+impl Default for RouteLearnerConfig {
+    fn default() -> Self {
+        RouteLearnerConfig {
+            max_trip_gap: DEFAULT_MAX_TRIP_GAP,
+            branch_threshold: DEFAULT_BRANCH_THRESHOLD,
+        }
+    }
+}
+
+/// Transition counts of a single route
+#[derive(Debug, Default)]
+struct TransitionCounts {
+    trips: u64,
+    starts: HashMap<i32, u64>,
+    transitions: HashMap<i32, HashMap<i32, u64>>,
+}
+
+impl TransitionCounts {
+    fn outgoing_total(&self, from: i32) -> u64 {
+        self.transitions
+            .get(&from)
+            .map_or(0, |successors| successors.values().sum())
+    }
+
+    /// Successors of `from`, most frequent first
+    fn successors(&self, from: i32) -> Vec<(i32, u64)> {
+        let mut successors: Vec<(i32, u64)> = self
+            .transitions
+            .get(&from)
+            .map(|successors| successors.iter().map(|(to, count)| (*to, *count)).collect())
+            .unwrap_or_default();
+        successors.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        successors
+    }
+
+    fn confidence(&self, from: i32, count: u64) -> f64 {
+        count as f64 / self.outgoing_total(from).max(1) as f64
+    }
+
+    /// Follows the most frequent transitions starting at `start`, until a dead end, a visited
+    /// point or a point in `stop_at` is reached. The point in `stop_at` is included.
+    fn walk(
+        &self,
+        start: RoutePoint,
+        visited: &mut HashSet<i32>,
+        stop_at: &HashSet<i32>,
+    ) -> Vec<RoutePoint> {
+        let mut path = vec![start];
+        visited.insert(start.reporting_point);
+        let mut current = start.reporting_point;
+
+        while let Some((next, count)) = self
+            .successors(current)
+            .into_iter()
+            .find(|(next, _)| !visited.contains(next) || stop_at.contains(next))
+        {
+            path.push(RoutePoint {
+                reporting_point: next,
+                confidence: self.confidence(current, count),
+            });
+            if stop_at.contains(&next) {
+                break;
+            }
+            visited.insert(next);
+            current = next;
+        }
+
+        path
+    }
+
+    fn learn(&self, key: RouteKey, branch_threshold: f64) -> Option<LearnedRoute> {
+        let (&start, &start_count) = self
+            .starts
+            .iter()
+            .max_by(|a, b| a.1.cmp(b.1).then(b.0.cmp(a.0)))?;
+
+        let mut visited: HashSet<i32> = HashSet::new();
+        let main = self.walk(
+            RoutePoint {
+                reporting_point: start,
+                confidence: start_count as f64 / self.trips.max(1) as f64,
+            },
+            &mut visited,
+            &HashSet::new(),
+        );
+
+        let main_points: HashSet<i32> = main.iter().map(|p| p.reporting_point).collect();
+        let mut branches = vec![main.clone()];
+
+        for point in &main {
+            for (next, count) in self.successors(point.reporting_point) {
+                let confidence = self.confidence(point.reporting_point, count);
+                // transitions to points of the main sequence are missed telegrams, not branches
+                if main_points.contains(&next)
+                    || visited.contains(&next)
+                    || confidence < branch_threshold
+                {
+                    continue;
+                }
+
+                let mut branch = vec![*point];
+                branch.extend(self.walk(
+                    RoutePoint {
+                        reporting_point: next,
+                        confidence,
+                    },
+                    &mut visited,
+                    &main_points,
+                ));
+                branches.push(branch);
+            }
+        }
+
+        Some(LearnedRoute {
+            key,
+            trips: self.trips,
+            branches,
+        })
+    }
+}
+
+/// Ongoing trip of a vehicle while learning
+struct Trip {
+    key: RouteKey,
+    last_time: NaiveDateTime,
+    points: Vec<i32>,
+}
+
+/// Incrementally learns [`LearnedRoute`]s from telegrams. Telegrams have to be added in
+/// chronological order.
+pub struct RouteLearner {
+    config: RouteLearnerConfig,
+    routes: HashMap<RouteKey, TransitionCounts>,
+    trips: HashMap<(i64, i32, i32), Trip>,
+}
+
+impl RouteLearner {
+    /// Creates a new learner
+    pub fn new(config: RouteLearnerConfig) -> Self {
+        RouteLearner {
+            config,
+            routes: HashMap::new(),
+            trips: HashMap::new(),
+        }
+    }
+
+    fn record(&mut self, trip: Trip) {
+        if trip.points.len() < 2 {
+            return;
+        }
+
+        let counts = self.routes.entry(trip.key).or_default();
+        counts.trips += 1;
+        *counts.starts.entry(trip.points[0]).or_default() += 1;
+        for pair in trip.points.windows(2) {
+            *counts
+                .transitions
+                .entry(pair[0])
+                .or_default()
+                .entry(pair[1])
+                .or_default() += 1;
+        }
+    }
+
+    /// Adds a telegram. Telegrams without line, run or destination number are ignored.
+    pub fn add(&mut self, telegram: &R09SaveTelegram) {
+        let (Some(line), Some(run), Some(destination_number)) = (
+            telegram.line,
+            telegram.run_number,
+            telegram.destination_number,
+        ) else {
+            return;
+        };
+        let key = RouteKey {
+            region: telegram.region,
+            line,
+            destination_number,
+        };
+        let vehicle = (telegram.region, line, run);
+
+        let continues = self.trips.get(&vehicle).is_some_and(|trip| {
+            trip.key == key
+                && (telegram.time - trip.last_time).num_milliseconds() <= self.config.max_trip_gap
+        });
+
+        if !continues {
+            let trip = self.trips.insert(
+                vehicle,
+                Trip {
+                    key,
+                    last_time: telegram.time,
+                    points: Vec::new(),
+                },
+            );
+            if let Some(trip) = trip {
+                self.record(trip);
+            }
+        }
+
+        if let Some(trip) = self.trips.get_mut(&vehicle) {
+            trip.last_time = telegram.time;
+            // the same telegram is often received by several stations
+            if trip.points.last() != Some(&telegram.reporting_point) {
+                trip.points.push(telegram.reporting_point);
+            }
+        }
+    }
+
+    /// Finishes all ongoing trips and returns the learned routes
+    pub fn finish(mut self) -> Vec<LearnedRoute> {
+        let trips: Vec<Trip> = self.trips.drain().map(|(_, trip)| trip).collect();
+        for trip in trips {
+            self.record(trip);
+        }
+
+        self.routes
+            .iter()
+            .filter_map(|(key, counts)| counts.learn(*key, self.config.branch_threshold))
+            .collect()
+    }
+
+    /// Learns the routes of `region` from the telegrams between `from` and `to`. Telegrams are
+    /// loaded day by day, to keep the memory footprint low.
+    pub fn from_postgres(
+        database_connection: &mut PgConnection,
+        config: RouteLearnerConfig,
+        region: i64,
+        from: NaiveDateTime,
+        to: NaiveDateTime,
+    ) -> Result<Vec<LearnedRoute>, diesel::result::Error> {
+        let mut learner = RouteLearner::new(config);
+
+        let mut window_start = from;
+        while window_start < to {
+            let window_end = std::cmp::min(window_start + Duration::days(1), to);
+            let telegrams = r09_telegrams::table
+                .filter(r09_telegrams::region.eq(region))
+                .filter(r09_telegrams::time.ge(window_start))
+                .filter(r09_telegrams::time.lt(window_end))
+                .order(r09_telegrams::time.asc())
+                .load::<R09SaveTelegram>(database_connection)?;
+
+            for telegram in &telegrams {
+                learner.add(telegram);
+            }
+            window_start = window_end;
+        }
+
+        Ok(learner.finish())
+    }
+}
+
+impl LearnedRoute {
+    /// The main sequence of reporting points
+    pub fn main_sequence(&self) -> &[RoutePoint] {
+        self.branches
+            .first()
+            .map_or(&[], |branch| branch.as_slice())
+    }
+
+    /// Converts the route into rows of the `r09_reporting_point_sequences` table
+    pub fn to_insertable(&self) -> Vec<InsertSequencePoint> {
+        self.branches
+            .iter()
+            .enumerate()
+            .flat_map(|(branch, points)| {
+                points
+                    .iter()
+                    .enumerate()
+                    .map(move |(position, point)| InsertSequencePoint {
+                        id: None,
+                        region: self.key.region,
+                        line: self.key.line,
+                        destination_number: self.key.destination_number,
+                        branch: branch as i32,
+                        position: position as i32,
+                        reporting_point: point.reporting_point,
+                        confidence: point.confidence,
+                    })
+            })
+            .collect()
+    }
+
+    /// Assembles a route from its rows in the `r09_reporting_point_sequences` table. The number
+    /// of trips is not stored in the database and set to `0`.
+    pub fn from_rows(key: RouteKey, mut rows: Vec<SequencePoint>) -> Self {
+        rows.sort_by_key(|row| (row.branch, row.position));
+
+        let mut branches: Vec<Vec<RoutePoint>> = Vec::new();
+        let mut current_branch = None;
+        for row in rows {
+            if current_branch != Some(row.branch) {
+                branches.push(Vec::new());
+                current_branch = Some(row.branch);
+            }
+            if let Some(branch) = branches.last_mut() {
+                branch.push(RoutePoint {
+                    reporting_point: row.reporting_point,
+                    confidence: row.confidence,
+                });
+            }
+        }
+
+        LearnedRoute {
+            key,
+            trips: 0,
+            branches,
+        }
+    }
+
+    /// Replaces the stored sequence of this route in the database
+    pub fn store(
+        &self,
+        database_connection: &mut PgConnection,
+    ) -> Result<(), diesel::result::Error> {
+        use crate::schema::r09_reporting_point_sequences::dsl::*;
+
+        let rows = self.to_insertable();
+        database_connection.transaction(|connection| {
+            diesel::delete(
+                r09_reporting_point_sequences
+                    .filter(region.eq(self.key.region))
+                    .filter(line.eq(self.key.line))
+                    .filter(destination_number.eq(self.key.destination_number)),
+            )
+            .execute(connection)?;
+
+            diesel::insert_into(r09_reporting_point_sequences)
+                .values(&rows)
+                .execute(connection)?;
+
+            Ok(())
+        })
+    }
+
+    /// Loads the stored sequence of a route from the database
+    pub fn from_postgres(
+        database_connection: &mut PgConnection,
+        key: RouteKey,
+    ) -> Result<Option<Self>, diesel::result::Error> {
+        use crate::schema::r09_reporting_point_sequences::dsl::*;
+
+        let rows = r09_reporting_point_sequences
+            .filter(region.eq(key.region))
+            .filter(line.eq(key.line))
+            .filter(destination_number.eq(key.destination_number))
+            .load::<SequencePoint>(database_connection)?;
+
+        if rows.is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some(Self::from_rows(key, rows)))
+    }
+}
@@ -1,25 +1,903 @@
+use super::*;
+
+/// Active region without boundary
+fn region_fixture(id: i64, name: &str) -> region::Region {
+    region::Region {
+        id,
+        name: name.to_string(),
+        transport_company: String::new(),
+        regional_company: None,
+        frequency: Some(170_795_000),
+        r09_type: None,
+        encoding: None,
+        deactivated: false,
+        lat: 51.0,
+        lon: 13.7,
+        zoom: 12.0,
+        work_in_progress: false,
+        boundary: None,
+    }
+}
+
 #[test]
 fn test_serialization() {
-    let data = TransmissionPosition {
-        dhid: Some("dhid".to_string()),
-        name: Some("name".to_string()),
-        telegram_type: TelegramType::DoorClosed,
-        direction: 0,
+    let data = ApiTransmissionLocation {
         lat: 0.0,
         lon: 0.0,
+        properties: serde_json::json!({ "reporting_point": 3 }),
     };
 
     let reference = String::from(
         "{
-  \"dhid\": \"dhid\",
-  \"name\": \"name\",
-  \"telegram_type\": \"3\",
-  \"direction\": 0,
   \"lat\": 0.0,
-  \"lon\": 0.0
+  \"lon\": 0.0,
+  \"properties\": {
+    \"reporting_point\": 3
+  }
 }",
     );
     let json_data = serde_json::to_string_pretty(&data).expect("cannot serialize structs!");
 
     assert_eq!(json_data, reference);
 }
+
+#[test]
+fn test_route_learning() {
+    use crate::telegrams::r09::{R09SaveTelegram, R09Type};
+    use route::{RouteLearner, RouteLearnerConfig};
+
+    let start = chrono::NaiveDate::from_ymd_opt(2023, 5, 1)
+        .unwrap()
+        .and_hms_opt(8, 0, 0)
+        .unwrap();
+    let telegram = |run: i32, reporting_point: i32, seconds: i64| R09SaveTelegram {
+        id: None,
+        time: start + chrono::Duration::seconds(seconds),
+        station: uuid::Uuid::nil(),
+        r09_type: R09Type::R16,
+        delay: None,
+        reporting_point,
+        junction: 0,
+        direction: 0,
+        request_status: 0,
+        priority: None,
+        direction_request: None,
+        line: Some(11),
+        run_number: Some(run),
+        destination_number: Some(7),
+        train_length: None,
+        vehicle_number: None,
+        operator: None,
+        region: 0,
+    };
+
+    // three trips along 1-2-3-4, one of them misses 3, one trip takes the branch 2-5-4
+    let trips: [&[i32]; 4] = [&[1, 2, 3, 4], &[1, 2, 3, 4], &[1, 2, 4], &[1, 2, 5, 4]];
+    let mut learner = RouteLearner::new(RouteLearnerConfig::default());
+    for (run, trip) in trips.iter().enumerate() {
+        for (i, reporting_point) in trip.iter().enumerate() {
+            learner.add(&telegram(run as i32, *reporting_point, 60 * i as i64));
+        }
+    }
+
+    let routes = learner.finish();
+    assert_eq!(routes.len(), 1);
+    let route = &routes[0];
+    assert_eq!(route.trips, 4);
+
+    let main: Vec<i32> = route
+        .main_sequence()
+        .iter()
+        .map(|p| p.reporting_point)
+        .collect();
+    assert_eq!(main, vec![1, 2, 3, 4]);
+    assert_eq!(route.main_sequence()[2].confidence, 0.5);
+
+    assert_eq!(route.branches.len(), 2);
+    let branch: Vec<i32> = route.branches[1]
+        .iter()
+        .map(|p| p.reporting_point)
+        .collect();
+    assert_eq!(branch, vec![2, 5, 4]);
+
+    assert_eq!(route.to_insertable().len(), 7);
+}
+
+#[test]
+fn test_estimators_resist_outliers() {
+    use estimation::{Dbscan, EstimatorConfig, GeometricMedian, LocationEstimator, SigmaClipping};
+
+    let raw = |lat: f64, lon: f64, accuracy: Option<f64>| WeightedLocation {
+        location: TransmissionLocationRaw {
+            id: 0,
+            region: 0,
+            reporting_point: 1,
+            lat,
+            lon,
+            trekkie_run: uuid::Uuid::nil(),
+            run_owner: uuid::Uuid::nil(),
+        },
+        accuracy,
+    };
+
+    // four good points within a few meters, one point ~1.1 km away, which drags the arithmetic
+    // mean far enough to reject every good point
+    let samples = vec![
+        raw(51.05000, 13.74000, Some(5.0)),
+        raw(51.05002, 13.74000, Some(5.0)),
+        raw(51.05000, 13.74003, Some(5.0)),
+        raw(51.04998, 13.73998, Some(20.0)),
+        raw(51.06000, 13.74000, Some(5.0)),
+    ];
+
+    let estimators: Vec<Box<dyn LocationEstimator>> = vec![
+        Box::new(GeometricMedian::default()),
+        Box::new(SigmaClipping::default()),
+        Box::new(Dbscan::default()),
+    ];
+    for estimator in estimators {
+        let estimate = estimator
+            .estimate(&samples, &EstimatorConfig::default())
+            .unwrap();
+        assert_eq!(estimate.inliers.len(), 4);
+        assert!((51.05, 13.74).distance_from((estimate.lat, estimate.lon)) < 3.0);
+        assert!(estimate.uncertainty > 0.0 && estimate.uncertainty < 10.0);
+    }
+
+    // two clusters of equal weight and size, the first one found always wins
+    let tied = vec![
+        raw(51.06000, 13.74000, Some(5.0)),
+        raw(51.06002, 13.74000, Some(5.0)),
+        raw(51.05000, 13.74000, Some(5.0)),
+        raw(51.05002, 13.74000, Some(5.0)),
+    ];
+    for _ in 0..10 {
+        let estimate = Dbscan::default()
+            .estimate(&tied, &EstimatorConfig::default())
+            .unwrap();
+        assert!((51.06001, 13.74).distance_from((estimate.lat, estimate.lon)) < 1.0);
+    }
+
+    let mut raw: Vec<TransmissionLocationRaw> =
+        samples.into_iter().map(|sample| sample.location).collect();
+    let estimate =
+        InsertTransmissionLocation::try_from_raw(raw.clone(), &EstimatorConfig::default()).unwrap();
+    assert_eq!(estimate.inliers, 4);
+    assert_eq!(estimate.location.reporting_point, 1);
+
+    // all samples come from the same run of the same user
+    let strict = EstimatorConfig {
+        min_trekkie_runs: 2,
+        ..Default::default()
+    };
+    assert!(matches!(
+        InsertTransmissionLocation::try_from_raw(raw.clone(), &strict),
+        Err(TransmissionLocaionError::InsufficientEvidence)
+    ));
+
+    // only the outlier comes from a second run of a second user
+    raw[4].trekkie_run = uuid::Uuid::from_u128(1);
+    raw[4].run_owner = uuid::Uuid::from_u128(1);
+    let strict = EstimatorConfig {
+        min_trekkie_runs: 2,
+        min_run_owners: 2,
+        ..Default::default()
+    };
+    assert!(matches!(
+        InsertTransmissionLocation::try_from_raw(raw, &strict),
+        Err(TransmissionLocaionError::InsufficientEvidence)
+    ));
+}
+
+#[test]
+fn test_reconcile_respects_ground_truth() {
+    use reconcile::{ReconcileConfig, UpsertAction, UpsertPlan};
+
+    let stored =
+        |id: i64, reporting_point: i32, lat: f64, ground_truth: bool| TransmissionLocation {
+            id,
+            region: 0,
+            reporting_point,
+            lat,
+            lon: 13.74,
+            ground_truth,
+        };
+    let estimate = |reporting_point: i32, lat: f64| InsertTransmissionLocation {
+        id: None,
+        region: 0,
+        reporting_point,
+        lat,
+        lon: 13.74,
+        ground_truth: false,
+    };
+
+    let current = vec![
+        stored(1, 1, 51.05, true),
+        stored(2, 2, 51.05, true),
+        stored(3, 3, 51.05, false),
+        stored(4, 4, 51.05, false),
+    ];
+    let estimates = vec![
+        // ~11 m off the ground truth
+        estimate(1, 51.0501),
+        // ~1.1 km off the ground truth
+        estimate(2, 51.06),
+        estimate(3, 51.0501),
+        // moved less than a meter
+        estimate(4, 51.050001),
+        estimate(5, 51.05),
+    ];
+
+    let plan = UpsertPlan::new(&current, estimates, &ReconcileConfig::default());
+    assert!(matches!(
+        plan.actions.as_slice(),
+        [
+            UpsertAction::Skip { .. },
+            UpsertAction::Conflict { .. },
+            UpsertAction::Update { .. },
+            UpsertAction::Skip { .. },
+            UpsertAction::Insert(_),
+        ]
+    ));
+    let conflict = plan.conflicts().next().cloned();
+    match conflict {
+        Some(UpsertAction::Conflict {
+            ground_truth,
+            distance,
+            ..
+        }) => {
+            assert_eq!(ground_truth.id, 2);
+            assert!((distance - 1112.0).abs() < 5.0);
+        }
+        _ => panic!("expected a conflict"),
+    }
+}
+
+#[test]
+fn test_gps_cleaning() {
+    use gps::cleaning::{clean, CleaningConfig, RemovalReason};
+    use gps::GpsPoint;
+
+    let start = chrono::NaiveDate::from_ymd_opt(2023, 5, 1)
+        .unwrap()
+        .and_hms_opt(12, 0, 0)
+        .unwrap();
+    let point = |seconds: i64, lat: f64, accuracy: f64| GpsPoint {
+        id: seconds,
+        trekkie_run: uuid::Uuid::nil(),
+        timestamp: start + chrono::Duration::seconds(seconds),
+        lat,
+        lon: 13.0,
+        elevation: None,
+        accuracy: Some(accuracy),
+        vertical_accuracy: None,
+        bearing: None,
+        speed: None,
+    };
+
+    // driving north at ~11 m/s, standing for 60 s, driving on
+    let mut track: Vec<GpsPoint> = (0..=10)
+        .map(|i| point(i, 51.0 + 0.0001 * i as f64, 5.0))
+        .collect();
+    track.extend((11..=70).map(|i| point(i, 51.001, 5.0)));
+    track.extend((71..=80).map(|i| point(i, 51.001 + 0.0001 * (i - 70) as f64, 5.0)));
+    // a fix from a tunnel and a jump of ~1 km
+    track.push(point(5, 51.0005, 80.0));
+    track[3].lat += 0.01;
+
+    let config = CleaningConfig {
+        collapse_dwells: true,
+        ..Default::default()
+    };
+    let report = clean(&track, &config);
+
+    assert_eq!(report.count(RemovalReason::PoorAccuracy), 1);
+    assert_eq!(report.count(RemovalReason::SpeedSpike), 1);
+    assert_eq!(report.dwells.len(), 1);
+    let dwell = &report.dwells[0];
+    assert!((dwell.end - dwell.start).num_seconds() >= 59);
+    assert!(report.count(RemovalReason::StationaryDrift) >= 55);
+    // straight driving collapses to few points
+    assert!(report.points.len() < 10);
+    assert_eq!(report.points.len() + report.removed.len(), track.len());
+    assert!(report
+        .points
+        .windows(2)
+        .all(|pair| pair[0].timestamp < pair[1].timestamp));
+
+    // a first fix ~5 km off only drops itself, not the following minutes of the track
+    let mut track: Vec<GpsPoint> = (0..=300)
+        .map(|i| point(i, 51.0 + 0.0001 * i as f64, 5.0))
+        .collect();
+    track[0].lat -= 0.045;
+    let config = CleaningConfig {
+        simplify_tolerance: None,
+        ..Default::default()
+    };
+    let report = clean(&track, &config);
+    assert_eq!(report.count(RemovalReason::SpeedSpike), 1);
+    assert_eq!(report.removed[0].point.id, 0);
+    assert_eq!(report.points.len(), 300);
+}
+
+#[test]
+fn test_region_geojson_roundtrip() {
+    use geojson::{Geometry, RegionFeature, RegionFeatureCollection};
+    use route::{LearnedRoute, RouteKey, RoutePoint};
+
+    let locations: Vec<TransmissionLocation> = (1..=3)
+        .map(|reporting_point| TransmissionLocation {
+            id: reporting_point as i64,
+            region: 0,
+            reporting_point,
+            lat: 51.0 + 0.001 * reporting_point as f64,
+            lon: 13.74,
+            ground_truth: reporting_point == 1,
+        })
+        .collect();
+    let route = LearnedRoute {
+        key: RouteKey {
+            region: 0,
+            line: 11,
+            destination_number: 7,
+        },
+        trips: 3,
+        branches: vec![[1, 2, 4, 3]
+            .into_iter()
+            .map(|reporting_point| RoutePoint {
+                reporting_point,
+                confidence: 1.0,
+            })
+            .collect()],
+    };
+
+    let mut collection = RegionFeatureCollection::new(None);
+    collection.add_transmission_locations(&locations, &HashMap::from([(2, 12)]));
+    collection.add_sequences(&[route], &locations);
+
+    let json = serde_json::to_value(&collection).unwrap();
+    assert_eq!(json["type"], "FeatureCollection");
+    assert_eq!(json["features"][0]["type"], "Feature");
+    assert_eq!(json["features"][0]["geometry"]["type"], "Point");
+    assert_eq!(json["features"][0]["geometry"]["coordinates"][0], 13.74);
+    assert_eq!(
+        json["features"][1]["properties"]["kind"],
+        "transmission_location"
+    );
+    assert_eq!(json["features"][1]["properties"]["samples"], 12);
+    assert_eq!(json["features"][3]["geometry"]["type"], "LineString");
+    assert_eq!(
+        json["features"][3]["geometry"]["coordinates"]
+            .as_array()
+            .unwrap()
+            .len(),
+        3
+    );
+
+    // move a location while editing and mark it as ground truth
+    let mut edited: RegionFeatureCollection = serde_json::from_str(
+        &json
+            .to_string()
+            .replace("\"ground_truth\":false", "\"ground_truth\":true"),
+    )
+    .unwrap();
+    edited.features[1].geometry = Geometry::point(51.5, 13.5);
+    assert!(matches!(
+        edited.features[3].properties,
+        RegionFeature::ReportingPointSequence(_)
+    ));
+
+    let reimported = edited.transmission_locations();
+    assert_eq!(reimported.len(), 3);
+    assert!(reimported.iter().all(|location| location.ground_truth));
+    assert_eq!((reimported[1].lat, reimported[1].lon), (51.5, 13.5));
+    assert_eq!(reimported[1].id, Some(2));
+
+    // GIS editors may save the geometries as multi geometries
+    let mut json = serde_json::to_value(&edited).unwrap();
+    json["features"][0]["geometry"] =
+        serde_json::json!({"type": "MultiPoint", "coordinates": [[13.6, 51.6]]});
+    json["features"][1]["geometry"] =
+        serde_json::json!({"type": "MultiPoint", "coordinates": [[13.6, 51.6], [13.7, 51.7]]});
+    json["features"][3]["geometry"] = serde_json::json!({
+        "type": "MultiLineString",
+        "coordinates": [[[13.6, 51.6], [13.7, 51.7]]]
+    });
+    let edited: RegionFeatureCollection = serde_json::from_value(json).unwrap();
+    assert!(matches!(
+        edited.features[3].geometry,
+        Geometry::MultiLineString { .. }
+    ));
+    let reimported = edited.transmission_locations();
+    assert_eq!(reimported.len(), 2);
+    assert_eq!((reimported[0].lat, reimported[0].lon), (51.6, 13.6));
+
+    let polygon: Geometry = serde_json::from_str(
+        r#"{"type": "MultiPolygon", "coordinates": [[[[0, 0], [1, 0], [1, 1], [0, 0]]]]}"#,
+    )
+    .unwrap();
+    assert!(
+        matches!(polygon, Geometry::MultiPolygon { ref coordinates } if coordinates.len() == 1)
+    );
+}
+
+#[test]
+fn test_projections() {
+    use projection::{Coordinate, Crs, Projectable};
+
+    let origin = Coordinate::new(0.0, 0.0).to_utm();
+    assert_eq!(origin.crs.epsg(), Some(32631));
+    assert!((origin.x - 166_021.443).abs() < 0.01);
+    assert!(origin.y.abs() < 0.01);
+
+    let mercator = Coordinate::new(0.0, 180.0).to_web_mercator();
+    assert!((mercator.x - 20_037_508.343).abs() < 0.01);
+
+    let dresden = Coordinate::new(51.0504, 13.7373);
+    for crs in [
+        Crs::WebMercator,
+        Crs::Utm {
+            zone: 33,
+            north: true,
+        },
+        Crs::Utm {
+            zone: 33,
+            north: false,
+        },
+        Crs::Etrs89Utm { zone: 32 },
+        Crs::GaussKrueger { zone: 5 },
+    ] {
+        assert_eq!(crs.epsg().and_then(Crs::from_epsg), Some(crs));
+        let back = dresden.project(crs).to_wgs84();
+        assert!((dresden.lat, dresden.lon).distance_from((back.lat, back.lon)) < 0.01);
+    }
+
+    // the easting is prefixed with the zone, DHDN is shifted by ~100 m against WGS84
+    let gauss_krueger = dresden.to_gauss_krueger();
+    assert_eq!(gauss_krueger.crs.epsg(), Some(31469));
+    assert!((5_400_000.0..5_450_000.0).contains(&gauss_krueger.x));
+    let utm = dresden.project(Crs::Etrs89Utm { zone: 33 });
+    assert!((gauss_krueger.y - utm.y).abs() > 50.0);
+
+    // DHDN Gauss-Krüger zones only have EPSG codes within Germany
+    let lisbon = Coordinate::new(38.7223, -9.1393);
+    assert_eq!(lisbon.gauss_krueger_zone(), 0);
+    assert_eq!(lisbon.to_gauss_krueger().crs.epsg(), None);
+    assert_eq!(Crs::GaussKrueger { zone: 6 }.epsg(), None);
+
+    // local projection for short distances
+    let north_east = Coordinate::new(51.0514, 13.7383);
+    let (x, y) = north_east.to_local(&dresden);
+    assert!((x - 70.0).abs() < 1.0 && (y - 111.2).abs() < 1.0);
+    let back = Coordinate::from_local((x, y), &dresden);
+    assert!((back.lat - north_east.lat).abs() < 1e-9 && (back.lon - north_east.lon).abs() < 1e-9);
+    assert!((dresden.bearing_to(&north_east) - 32.2).abs() < 0.5);
+    assert!((north_east.bearing_to(&dresden) - 212.2).abs() < 0.5);
+    assert_eq!(
+        Crs::Utm {
+            zone: 0,
+            north: true
+        }
+        .epsg(),
+        None
+    );
+
+    let mut location = ApiTransmissionLocation {
+        lat: 51.0504,
+        lon: 13.7373,
+        properties: serde_json::json!({}),
+    };
+    location.update_epsg3857();
+    assert_eq!(
+        location.properties["epsg3857"]["x"],
+        location.project(Crs::WebMercator).x
+    );
+}
+
+#[test]
+fn test_spatial_index() {
+    use index::SpatialIndex;
+    use projection::Coordinate;
+
+    // reporting points every ~100 m east of the origin
+    let locations: Vec<TransmissionLocation> = (0..20)
+        .map(|i| TransmissionLocation {
+            id: i,
+            region: 0,
+            reporting_point: i as i32,
+            lat: 51.05,
+            lon: 13.7 + i as f64 * 0.00143,
+            ground_truth: false,
+        })
+        .collect();
+    let index = SpatialIndex::new(locations.clone());
+    assert_eq!(index.len(), 20);
+
+    let query = Coordinate::new(51.05, 13.7 + 5.2 * 0.00143);
+    let nearest = index.nearest(&query, 3);
+    let reporting_points: Vec<i32> = nearest.iter().map(|(l, _)| l.reporting_point).collect();
+    assert_eq!(reporting_points, vec![5, 6, 4]);
+
+    // the local plane agrees with the haversine distance
+    for (location, distance) in &nearest {
+        let haversine = (query.lat, query.lon).distance_from((location.lat, location.lon));
+        assert!((distance - haversine).abs() < 0.5);
+    }
+
+    let within = index.within_radius(&query, 250.0);
+    assert_eq!(within.len(), 5);
+    assert!(within.windows(2).all(|w| w[0].1 <= w[1].1));
+
+    let in_bbox = index.in_bbox(
+        &Coordinate::new(51.04, 13.7 + 9.5 * 0.00143),
+        &Coordinate::new(51.06, 13.7 + 12.5 * 0.00143),
+    );
+    assert_eq!(in_bbox.len(), 3);
+
+    let brute_force = locations
+        .iter()
+        .min_by(|a, b| {
+            let distance =
+                |l: &TransmissionLocation| (query.lat, query.lon).distance_from((l.lat, l.lon));
+            distance(a).total_cmp(&distance(b))
+        })
+        .unwrap();
+    assert_eq!(brute_force.id, nearest[0].0.id);
+}
+
+#[test]
+fn test_region_boundary() {
+    use boundary::{assign_region, BoundaryError};
+    use geojson::Geometry;
+    use projection::Coordinate;
+    use reconcile::{ReconcileConfig, UpsertAction, UpsertPlan};
+    use region::Region;
+
+    let square = |lat: f64, lon: f64, size: f64| {
+        vec![
+            vec![lon, lat],
+            vec![lon + size, lat],
+            vec![lon + size, lat + size],
+            vec![lon, lat + size],
+            vec![lon, lat],
+        ]
+    };
+    // a square around Dresden with a hole in the middle
+    let boundary: Geometry = serde_json::from_value(serde_json::json!({
+        "type": "Polygon",
+        "coordinates": [square(50.9, 13.6, 0.3), square(51.0, 13.7, 0.1)],
+    }))
+    .unwrap();
+    assert_eq!(boundary.validate_boundary(), Ok(()));
+    assert_eq!(
+        Geometry::point(51.0, 13.7).validate_boundary(),
+        Err(BoundaryError::NotPolygonal)
+    );
+    assert!(!Geometry::point(51.0, 13.7).contains(&Coordinate::new(51.0, 13.7)));
+    assert!(boundary.contains(&Coordinate::new(50.95, 13.65)));
+    assert!(!boundary.contains(&Coordinate::new(51.05, 13.75)));
+    assert!(!boundary.contains(&Coordinate::new(51.3, 13.65)));
+    let (south_west, north_east) = boundary.bbox().unwrap();
+    assert_eq!((south_west.lat, north_east.lon), (50.9, 13.6 + 0.3));
+
+    let mut open = square(50.9, 13.6, 0.3);
+    open.pop();
+    open.push(vec![13.6, 51.0]);
+    let invalid = Geometry::Polygon {
+        coordinates: vec![open],
+    };
+    assert_eq!(
+        invalid.validate_boundary(),
+        Err(BoundaryError::RingNotClosed {
+            polygon: 0,
+            ring: 0
+        })
+    );
+
+    let region = |id: i64, boundary: Option<Geometry>| Region {
+        id,
+        name: format!("region {id}"),
+        transport_company: String::new(),
+        regional_company: None,
+        frequency: None,
+        r09_type: None,
+        encoding: None,
+        deactivated: false,
+        lat: 51.0,
+        lon: 13.7,
+        zoom: 12.0,
+        work_in_progress: false,
+        boundary,
+    };
+    let regions = vec![
+        region(0, None),
+        region(1, Some(boundary)),
+        region(
+            2,
+            Some(Geometry::MultiPolygon {
+                coordinates: vec![vec![square(51.2, 13.6, 0.2)]],
+            }),
+        ),
+    ];
+    assert!(regions[0].contains(&Coordinate::new(0.0, 0.0)));
+
+    let track = [
+        Coordinate::new(50.95, 13.65),
+        Coordinate::new(51.25, 13.65),
+        Coordinate::new(51.3, 13.7),
+    ];
+    assert_eq!(assign_region(&regions, &track).map(|r| r.id), Some(2));
+    assert!(assign_region(&regions, &track[..2]).is_none());
+
+    let estimate = |reporting_point: i32, lat: f64, lon: f64| InsertTransmissionLocation {
+        id: None,
+        region: 1,
+        reporting_point,
+        lat,
+        lon,
+        ground_truth: false,
+    };
+    let mut plan = UpsertPlan::new(
+        &[],
+        vec![estimate(1, 50.95, 13.65), estimate(2, 51.05, 13.75)],
+        &ReconcileConfig::default(),
+    );
+    plan.restrict_to(&regions[1]);
+    assert!(matches!(plan.actions[0], UpsertAction::Insert(_)));
+    assert!(matches!(plan.actions[1], UpsertAction::OutsideRegion(_)));
+}
+
+#[cfg(feature = "postgis")]
+#[test]
+fn test_postgis_queries() {
+    use crate::schema::stations;
+    use diesel::pg::Pg;
+    use diesel::{debug_query, QueryDsl};
+    use postgis::{point, st_distance, st_dwithin, station_position};
+
+    let center = point(51.05, 13.74);
+    let query = stations::table
+        .filter(st_dwithin(station_position(), center, 500.0))
+        .order_by(st_distance(station_position(), center))
+        .select(stations::id);
+    let sql = debug_query::<Pg, _>(&query).to_string();
+
+    assert!(sql.contains(
+        "ST_DWithin(stations.geog, geography(ST_SetSRID(ST_MakePoint($1, $2), $3)), $4)"
+    ));
+    assert!(sql.contains("ORDER BY ST_Distance(stations.geog, geography("));
+}
+
+#[test]
+fn test_region_cache() {
+    use region::{FileSource, Region, RegionCache, RegionCacheConfig, REGION_CACHE_FILE};
+
+    let directory =
+        std::env::temp_dir().join(format!("tlms-region-cache-{}", uuid::Uuid::new_v4()));
+    let regions = directory.join("regions.json");
+    let config = RegionCacheConfig::new(directory.join("cache"));
+    let write_regions = |names: &[&str]| {
+        let metadata: std::collections::HashMap<i64, Region> = names
+            .iter()
+            .enumerate()
+            .map(|(id, name)| (id as i64, region_fixture(id as i64, name)))
+            .collect();
+        std::fs::write(&regions, serde_json::to_string(&metadata).unwrap()).unwrap();
+    };
+
+    std::fs::create_dir_all(&directory).unwrap();
+    write_regions(&["Dresden"]);
+    let source = FileSource::new(regions.clone());
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    runtime.block_on(async {
+        // no cache yet, fetched from the source and written out
+        let cache = RegionCache::load(&source, &config).await.unwrap();
+        assert_eq!(cache.metadata[&0].name, "Dresden");
+        assert!(config.cache_dir.join(REGION_CACHE_FILE).exists());
+
+        // fresh cache is used as is
+        write_regions(&["Dresden", "Chemnitz"]);
+        let cache = RegionCache::load(&source, &config).await.unwrap();
+        assert_eq!(cache.metadata.len(), 1);
+
+        // expired cache is refreshed
+        let expired = RegionCacheConfig {
+            ttl: chrono::Duration::zero(),
+            ..config.clone()
+        };
+        let cache = RegionCache::load(&source, &expired).await.unwrap();
+        assert_eq!(cache.metadata.len(), 2);
+
+        // stale cache is used if the source fails
+        std::fs::remove_file(&regions).unwrap();
+        let cache = RegionCache::load(&source, &expired).await.unwrap();
+        assert_eq!(cache.metadata.len(), 2);
+    });
+
+    // concurrent writes of the same file don't share a temporary file
+    let target = directory.join("concurrent.json");
+    std::thread::scope(|scope| {
+        let writers: Vec<_> = (0..8)
+            .map(|i| {
+                let target = &target;
+                scope.spawn(move || region::cache::write_atomic(target, &[i; 1024]))
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap().unwrap();
+        }
+    });
+    let contents = std::fs::read(&target).unwrap();
+    assert_eq!(contents.len(), 1024);
+    assert!(contents.iter().all(|byte| *byte == contents[0]));
+    assert!(std::fs::read_dir(&directory).unwrap().all(|entry| !entry
+        .unwrap()
+        .path()
+        .to_string_lossy()
+        .ends_with(".tmp")));
+
+    std::fs::remove_dir_all(&directory).unwrap();
+}
+
+#[test]
+fn test_region_registry() {
+    use region::{FileSource, RegionCache, RegionCacheConfig, RegionChange, RegionRegistry};
+    use std::collections::HashMap;
+
+    let cache = |regions: Vec<region::Region>| RegionCache {
+        metadata: regions.into_iter().map(|r| (r.id, r)).collect(),
+        modified: chrono::Utc::now(),
+        validators: Default::default(),
+    };
+    let dresden = region_fixture(0, "Dresden");
+    let chemnitz = region_fixture(1, "Chemnitz");
+    let registry = RegionRegistry::new(cache(vec![dresden.clone(), chemnitz.clone()]));
+    assert_eq!(registry.by_name("dresden").map(|r| r.id), Some(0));
+    assert_eq!(
+        registry.get(1).map(|r| r.name),
+        Some(String::from("Chemnitz"))
+    );
+
+    let mut changes = registry.subscribe();
+    let mut deactivated = dresden.clone();
+    deactivated.deactivated = true;
+    let mut retuned = chemnitz.clone();
+    retuned.frequency = Some(153_850_000);
+    let leipzig = region_fixture(2, "Leipzig");
+    registry.replace(cache(vec![
+        deactivated.clone(),
+        retuned.clone(),
+        leipzig.clone(),
+    ]));
+
+    assert_eq!(
+        changes.try_recv().unwrap(),
+        RegionChange::Deactivated(deactivated)
+    );
+    assert_eq!(
+        changes.try_recv().unwrap(),
+        RegionChange::FrequencyChanged {
+            region: retuned,
+            previous: Some(170_795_000),
+        }
+    );
+    assert_eq!(changes.try_recv().unwrap(), RegionChange::Added(leipzig));
+    assert!(changes.try_recv().is_err());
+    assert_eq!(
+        RegionChange::between(&registry.snapshot().metadata, &HashMap::new()).len(),
+        3
+    );
+
+    // failing refreshes keep serving the old regions
+    let directory =
+        std::env::temp_dir().join(format!("tlms-region-registry-{}", uuid::Uuid::new_v4()));
+    let source = FileSource::new(directory.join("missing.json"));
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let result = runtime.block_on(registry.refresh(&source, &RegionCacheConfig::new(directory)));
+    assert!(result.is_err());
+    let metrics = registry.metrics();
+    assert_eq!((metrics.refreshes, metrics.failed_refreshes), (1, 1));
+    assert_eq!(registry.snapshot().metadata.len(), 3);
+}
+
+#[test]
+fn test_region_cache_revalidation() {
+    use region::{
+        HttpSource, RegionCache, RegionCacheConfig, RegionCacheError, REGION_CACHE_FILE,
+        REGION_CACHE_VERSION,
+    };
+    use std::io::{BufRead, BufReader, Write};
+
+    let directory = std::env::temp_dir().join(format!("tlms-region-etag-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&directory).unwrap();
+    let cache_file = directory.join(REGION_CACHE_FILE);
+    let metadata = serde_json::json!({ "0": region_fixture(0, "Dresden") });
+
+    // caches without version are migrated, unknown schemas are rejected
+    let legacy = serde_json::json!({ "metadata": metadata, "modified": chrono::Utc::now() });
+    std::fs::write(&cache_file, legacy.to_string()).unwrap();
+    let cache = RegionCache::read_region_cache(directory.clone()).unwrap();
+    assert_eq!(cache.metadata[&0].name, "Dresden");
+    assert_eq!(cache.validators, Default::default());
+    let incompatible = serde_json::json!({
+        "version": REGION_CACHE_VERSION,
+        "schema": "0",
+        "metadata": metadata,
+        "modified": chrono::Utc::now(),
+    });
+    std::fs::write(&cache_file, incompatible.to_string()).unwrap();
+    assert!(matches!(
+        RegionCache::read_region_cache(directory.clone()),
+        Err(RegionCacheError::IncompatibleCache { .. })
+    ));
+
+    // answers with the regions and an etag, then with 304 if the etag is sent along
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let body = metadata.to_string();
+    let server = std::thread::spawn(move || {
+        let mut conditional = Vec::new();
+        for stream in listener.incoming().take(3) {
+            let mut stream = stream.unwrap();
+            let headers: Vec<String> = BufReader::new(&stream)
+                .lines()
+                .map(|line| line.unwrap())
+                .take_while(|line| !line.is_empty())
+                .collect();
+            let revalidating = headers
+                .iter()
+                .any(|h| h.to_lowercase() == "if-none-match: \"v1\"");
+            conditional.push(revalidating);
+            let response = if revalidating {
+                String::from("HTTP/1.1 304 Not Modified\r\nConnection: close\r\n\r\n")
+            } else {
+                format!(
+                    "HTTP/1.1 200 OK\r\nETag: \"v1\"\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                )
+            };
+            stream.write_all(response.as_bytes()).unwrap();
+        }
+        conditional
+    });
+
+    let source = HttpSource::new(&format!("http://{address}"));
+    let expired = RegionCacheConfig {
+        ttl: chrono::Duration::zero(),
+        ..RegionCacheConfig::new(directory.clone())
+    };
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        // the incompatible cache is discarded and fetched again
+        let cache = RegionCache::load(&source, &expired).await.unwrap();
+        assert_eq!(cache.validators.etag.as_deref(), Some("\"v1\""));
+
+        let revalidated = RegionCache::load(&source, &expired).await.unwrap();
+        assert_eq!(revalidated.metadata, cache.metadata);
+        assert!(revalidated.modified > cache.modified);
+    });
+
+    // the blocking path honours the configured lifetime as well
+    let fresh = RegionCache::read_region_cache(directory.clone()).unwrap();
+    let blocking =
+        RegionCache::update_region_cache_with(&format!("http://{address}"), &expired).unwrap();
+    assert_eq!(blocking.metadata, fresh.metadata);
+    assert!(blocking.modified > fresh.modified);
+
+    assert_eq!(server.join().unwrap(), vec![false, true, true]);
+    let written: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&cache_file).unwrap()).unwrap();
+    assert_eq!(written["version"], REGION_CACHE_VERSION);
+    assert_eq!(written["schema"], SCHEMA);
+    std::fs::remove_dir_all(&directory).unwrap();
+}
@@ -4,7 +4,7 @@ use crate::grpc::GrpcWaypoint;
 use serde::{Deserialize, Serialize};
 
 /// this enum tell the waypoint which source they came from
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum WayPointType {
     /// Source is unknown
     UnknownSource = 0,
@@ -12,6 +12,8 @@ pub enum WayPointType {
     R09Telegram = 1,
     /// position submitted via trekkie service
     TrekkieGPS = 2,
+    /// position interpolated along the track between two reporting points
+    Interpolated = 3,
 }
 
 impl From<i32> for WayPointType {
@@ -19,6 +21,7 @@ impl From<i32> for WayPointType {
         match number {
             x if x == WayPointType::R09Telegram as i32 => WayPointType::R09Telegram,
             x if x == WayPointType::TrekkieGPS as i32 => WayPointType::TrekkieGPS,
+            x if x == WayPointType::Interpolated as i32 => WayPointType::Interpolated,
             _ => WayPointType::UnknownSource,
         }
     }
@@ -0,0 +1,372 @@
+//! This module estimates the offset between the clock of the phone that recorded a trekkie run
+//! and the clocks of the receiving stations. Every candidate offset is tried by correlating the
+//! run (see [`correlate`]) and scoring the resulting raw transmission locations: if ground truth
+//! locations are known for the reporting points, by the mean distance to them, otherwise by the
+//! scatter of positions derived from repeated telegrams of the same reporting point. The offset
+//! with the lowest score wins, but only among the offsets that keep (almost) as many raw locations
+//! as the best one, so an offset can't win by pushing telegrams off the track.
+//!
+//! Offsets are estimated once per run over all telegrams, and once per station relative to the
+//! run offset, by minimizing the disagreement with the other stations. Estimated offsets are
+//! stored in the `trekkie_clock_offsets` table and can be fed back into the correlation with
+//! [`CorrelationConfig::with_offsets`].
+
+use super::correlation::{correlate, CorrelationConfig, CorrelationError};
+use crate::locations::gps::GpsPoint;
+use crate::locations::{DistanceFrom, InsertTransmissionLocationRaw, TransmissionLocation};
+use crate::schema::*;
+use crate::telegrams::r09::R09SaveTelegram;
+use crate::trekkie::TrekkieRun;
+
+use chrono::Duration;
+use diesel::{
+    Connection, ExpressionMethods, Identifiable, Insertable, PgConnection, QueryDsl, Queryable,
+    RunQueryDsl,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+/// Default largest clock offset in milliseconds that is searched for (2 minutes)
+pub const DEFAULT_MAX_OFFSET: i64 = 2 * 60 * 1000;
+/// Default step in milliseconds of the coarse offset search
+pub const DEFAULT_OFFSET_STEP: i64 = 1000;
+/// Default minimal number of scored raw locations for an offset to be accepted
+pub const DEFAULT_MIN_SAMPLES: usize = 4;
+/// Default share of the most scored raw locations of any candidate offset that an offset needs
+/// to keep to be ranked
+pub const DEFAULT_MIN_SAMPLE_SHARE: f64 = 0.9;
+
+/// This struct is used to query estimated clock offsets from the database. If `station` is
+/// [`None`], the offset applies to all telegrams correlated with the trekkie run.
+#[derive(Debug, Clone, Serialize, Deserialize, Queryable, Identifiable, ToSchema)]
+#[diesel(table_name = trekkie_clock_offsets)]
+pub struct ClockOffset {
+    /// Primary key
+    pub id: i64,
+    /// Trekkie run the offset was estimated for
+    pub trekkie_run: Uuid,
+    /// Station the offset was estimated for
+    pub station: Option<Uuid>,
+    /// Offset in milliseconds that is added to the telegram time to get the time of the GPS track
+    pub offset_ms: i64,
+    /// Mean distance in meters to ground truth, or scatter of repeated reporting points
+    pub score: f64,
+    /// Number of raw transmission locations the score was calculated from
+    pub samples: i32,
+    /// If the score is the distance to ground truth locations
+    pub ground_truth: bool,
+}
+
+/// This struct inserts into the table corresponding to [`ClockOffset`]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Insertable)]
+#[diesel(table_name = trekkie_clock_offsets)]
+pub struct InsertClockOffset {
+    /// Primary key. During INSERT should be [`None`] so DB can auto-increment it
+    pub id: Option<i64>,
+    /// Trekkie run the offset was estimated for
+    pub trekkie_run: Uuid,
+    /// Station the offset was estimated for
+    pub station: Option<Uuid>,
+    /// Offset in milliseconds that is added to the telegram time to get the time of the GPS track
+    pub offset_ms: i64,
+    /// Mean distance in meters to ground truth, or scatter of repeated reporting points
+    pub score: f64,
+    /// Number of raw transmission locations the score was calculated from
+    pub samples: i32,
+    /// If the score is the distance to ground truth locations
+    pub ground_truth: bool,
+}
+
+/// Score of a single candidate offset
+#[derive(Debug, Clone, Copy)]
+struct Score {
+    value: f64,
+    samples: usize,
+    ground_truth: bool,
+}
+
+/// Grid search for the clock offset of trekkie runs and stations
+#[derive(Debug, Clone)]
+pub struct ClockOffsetEstimator {
+    /// largest offset in milliseconds in both directions that is searched for
+    pub max_offset: i64,
+    /// step in milliseconds of the coarse search, the best offset is refined with a tenth of it
+    pub step: i64,
+    /// minimal number of scored raw locations for an offset to be accepted
+    pub min_samples: usize,
+    /// share of the most scored raw locations of any candidate that an offset needs to keep to
+    /// be ranked by its score
+    pub min_sample_share: f64,
+    /// correlation parameters, clock skews and deduplication are overwritten during the search
+    pub correlation: CorrelationConfig,
+}
+
+impl Default for ClockOffsetEstimator {
+    fn default() -> Self {
+        ClockOffsetEstimator {
+            max_offset: DEFAULT_MAX_OFFSET,
+            step: DEFAULT_OFFSET_STEP,
+            min_samples: DEFAULT_MIN_SAMPLES,
+            min_sample_share: DEFAULT_MIN_SAMPLE_SHARE,
+            correlation: CorrelationConfig::default(),
+        }
+    }
+}
+
+impl ClockOffsetEstimator {
+    /// Scores the raw locations, lower is better
+    fn score(
+        &self,
+        locations: &[InsertTransmissionLocationRaw],
+        ground_truth: &HashMap<i32, (f64, f64)>,
+    ) -> Option<Score> {
+        let distances: Vec<f64> = locations
+            .iter()
+            .filter_map(|location| {
+                ground_truth
+                    .get(&location.reporting_point)
+                    .map(|truth| (location.lat, location.lon).distance_from(*truth))
+            })
+            .collect();
+        if !distances.is_empty() {
+            return (distances.len() >= self.min_samples).then(|| Score {
+                value: distances.iter().sum::<f64>() / distances.len() as f64,
+                samples: distances.len(),
+                ground_truth: true,
+            });
+        }
+
+        let mut groups: HashMap<i32, Vec<(f64, f64)>> = HashMap::new();
+        for location in locations {
+            groups
+                .entry(location.reporting_point)
+                .or_default()
+                .push((location.lat, location.lon));
+        }
+        let mut squares = 0_f64;
+        let mut samples = 0;
+        for positions in groups.values().filter(|positions| positions.len() > 1) {
+            let count = positions.len() as f64;
+            let center = (
+                positions.iter().map(|p| p.0).sum::<f64>() / count,
+                positions.iter().map(|p| p.1).sum::<f64>() / count,
+            );
+            squares += positions
+                .iter()
+                .map(|p| p.distance_from(center).powi(2))
+                .sum::<f64>();
+            samples += positions.len();
+        }
+
+        (samples >= self.min_samples && samples > 0).then(|| Score {
+            value: (squares / samples as f64).sqrt(),
+            samples,
+            ground_truth: false,
+        })
+    }
+
+    /// Searches the offset with the lowest score among the candidates that keep at least
+    /// `min_sample_share` of the most scored raw locations. `configure` turns a candidate offset
+    /// into the correlation config to score.
+    fn search(
+        &self,
+        run: &TrekkieRun,
+        track: &[GpsPoint],
+        telegrams: &[R09SaveTelegram],
+        ground_truth: &HashMap<i32, (f64, f64)>,
+        configure: impl Fn(i64) -> CorrelationConfig,
+    ) -> Option<(i64, Score)> {
+        let evaluate = |offset: i64| {
+            let locations = correlate(run, track, telegrams, &configure(offset));
+            self.score(&locations, ground_truth)
+                .map(|score| (offset, score))
+        };
+        let best = |candidates: Vec<i64>| {
+            let scored: Vec<(i64, Score)> = candidates.into_iter().filter_map(evaluate).collect();
+            let most = scored.iter().map(|(_, score)| score.samples).max()?;
+            scored
+                .into_iter()
+                .filter(|(_, score)| score.samples as f64 >= self.min_sample_share * most as f64)
+                .min_by(|(a_offset, a), (b_offset, b)| {
+                    a.value
+                        .total_cmp(&b.value)
+                        .then(a_offset.abs().cmp(&b_offset.abs()))
+                })
+        };
+
+        let step = self.step.max(1);
+        let coarse = best(
+            (-self.max_offset..=self.max_offset)
+                .step_by(step as usize)
+                .collect(),
+        )?;
+        let fine_step = (step / 10).max(1);
+        let fine = best(
+            (coarse.0 - step..=coarse.0 + step)
+                .step_by(fine_step as usize)
+                .collect(),
+        );
+
+        fine.or(Some(coarse))
+    }
+
+    /// Estimates the offset of the trekkie run, followed by one offset per station that received
+    /// telegrams of the run. Station offsets are searched relative to the run offset and include
+    /// it. Runs or stations without enough repeated or ground truth reporting points are left out.
+    pub fn estimate(
+        &self,
+        run: &TrekkieRun,
+        track: &[GpsPoint],
+        telegrams: &[R09SaveTelegram],
+        ground_truth: &[TransmissionLocation],
+    ) -> Vec<InsertClockOffset> {
+        let ground_truth: HashMap<i32, (f64, f64)> = ground_truth
+            .iter()
+            .filter(|location| location.ground_truth && location.region == run.region)
+            .map(|location| (location.reporting_point, (location.lat, location.lon)))
+            .collect();
+        let base = CorrelationConfig {
+            clock_skew: 0,
+            station_skew: HashMap::new(),
+            deduplication_window: 0,
+            ..self.correlation.clone()
+        };
+        let insertable = |station: Option<Uuid>, offset: i64, score: Score| InsertClockOffset {
+            id: None,
+            trekkie_run: run.id,
+            station,
+            offset_ms: offset,
+            score: score.value,
+            samples: score.samples as i32,
+            ground_truth: score.ground_truth,
+        };
+
+        let mut offsets = Vec::new();
+        let run_offset = match self.search(run, track, telegrams, &ground_truth, |offset| {
+            CorrelationConfig {
+                clock_skew: offset,
+                ..base.clone()
+            }
+        }) {
+            Some((offset, score)) => {
+                offsets.push(insertable(None, offset, score));
+                offset
+            }
+            None => 0,
+        };
+
+        let mut stations: Vec<Uuid> = telegrams
+            .iter()
+            .map(|telegram| telegram.station)
+            .collect::<HashSet<Uuid>>()
+            .into_iter()
+            .collect();
+        stations.sort();
+        for station in stations {
+            let found = self.search(run, track, telegrams, &ground_truth, |offset| {
+                CorrelationConfig {
+                    clock_skew: run_offset,
+                    station_skew: HashMap::from([(station, offset)]),
+                    ..base.clone()
+                }
+            });
+            if let Some((offset, score)) = found {
+                offsets.push(insertable(Some(station), run_offset + offset, score));
+            }
+        }
+
+        offsets
+    }
+
+    /// Loads the trekkie run, its GPS track, matching telegrams and the ground truth locations of
+    /// the region, estimates the offsets and replaces the stored offsets of the run.
+    pub fn estimate_run(
+        &self,
+        database_connection: &mut PgConnection,
+        run_id: Uuid,
+    ) -> Result<Vec<InsertClockOffset>, CorrelationError> {
+        let run: TrekkieRun = trekkie_runs::table
+            .filter(trekkie_runs::id.eq(run_id))
+            .first(database_connection)?;
+        if !run.finished {
+            return Err(CorrelationError::RunNotFinished);
+        }
+
+        let track: Vec<GpsPoint> = gps_points::table
+            .filter(gps_points::trekkie_run.eq(run_id))
+            .order(gps_points::timestamp.asc())
+            .load(database_connection)?;
+
+        let margin = Duration::milliseconds(self.max_offset + self.step);
+        let telegrams: Vec<R09SaveTelegram> = r09_telegrams::table
+            .filter(r09_telegrams::region.eq(run.region))
+            .filter(r09_telegrams::line.eq(run.line))
+            .filter(r09_telegrams::run_number.eq(run.run))
+            .filter(r09_telegrams::time.ge(run.start_time - margin))
+            .filter(r09_telegrams::time.le(run.end_time + margin))
+            .load(database_connection)?;
+
+        let ground_truth: Vec<TransmissionLocation> = r09_transmission_locations::table
+            .filter(r09_transmission_locations::region.eq(run.region))
+            .filter(r09_transmission_locations::ground_truth.eq(true))
+            .load(database_connection)?;
+
+        let offsets = self.estimate(&run, &track, &telegrams, &ground_truth);
+        ClockOffset::store(database_connection, run_id, &offsets)?;
+
+        Ok(offsets)
+    }
+}
+
+impl ClockOffset {
+    /// Replaces all stored offsets of the trekkie run
+    pub fn store(
+        database_connection: &mut PgConnection,
+        run_id: Uuid,
+        offsets: &[InsertClockOffset],
+    ) -> Result<(), diesel::result::Error> {
+        use crate::schema::trekkie_clock_offsets::dsl::*;
+
+        database_connection.transaction(|connection| {
+            diesel::delete(trekkie_clock_offsets.filter(trekkie_run.eq(run_id)))
+                .execute(connection)?;
+            diesel::insert_into(trekkie_clock_offsets)
+                .values(offsets)
+                .execute(connection)?;
+
+            Ok(())
+        })
+    }
+
+    /// Loads the stored offsets of the trekkie run
+    pub fn from_postgres(
+        database_connection: &mut PgConnection,
+        run_id: Uuid,
+    ) -> Result<Vec<Self>, diesel::result::Error> {
+        use crate::schema::trekkie_clock_offsets::dsl::*;
+
+        trekkie_clock_offsets
+            .filter(trekkie_run.eq(run_id))
+            .load(database_connection)
+    }
+}
+
+impl CorrelationConfig {
+    /// Applies estimated offsets of a single trekkie run: the run offset becomes the global
+    /// clock skew, station offsets the per-station skews on top of it.
+    pub fn with_offsets(mut self, offsets: &[ClockOffset]) -> Self {
+        if let Some(run_offset) = offsets.iter().find(|offset| offset.station.is_none()) {
+            self.clock_skew = run_offset.offset_ms;
+        }
+        for offset in offsets {
+            if let Some(station) = offset.station {
+                self.station_skew
+                    .insert(station, offset.offset_ms - self.clock_skew);
+            }
+        }
+
+        self
+    }
+}
@@ -0,0 +1,229 @@
+//! This module correlates finished trekkie runs with the R09 telegrams sent by the measured
+//! vehicle. For every matching telegram the position of the vehicle at the time of transmission
+//! is interpolated from the GPS track of the run, which yields one
+//! [`InsertTransmissionLocationRaw`] per telegram. The clock of the phone recording the track and
+//! the clocks of the receiving stations are rarely in sync, so a global and a per-station clock
+//! skew can be configured in the [`CorrelationConfig`].
+
+use super::FinishedMeasurementInterval;
+use crate::locations::gps::GpsPoint;
+use crate::locations::InsertTransmissionLocationRaw;
+use crate::schema::{gps_points, r09_telegrams, r09_transmission_locations_raw, trekkie_runs};
+use crate::telegrams::r09::R09SaveTelegram;
+use crate::trekkie::TrekkieRun;
+
+use chrono::{Duration, NaiveDateTime};
+use diesel::{Connection, ExpressionMethods, PgConnection, QueryDsl, RunQueryDsl};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+/// Default maximum time in milliseconds between two GPS points to interpolate between them
+pub const DEFAULT_MAX_GPS_GAP: i64 = 30 * 1000;
+/// Default time window in milliseconds in which repeated telegrams for the same reporting point
+/// are considered to be the same transmission
+pub const DEFAULT_DEDUPLICATION_WINDOW: i64 = 30 * 1000;
+
+/// Parameters of the correlation. Clock skews are given in milliseconds and are added to the time
+/// of a telegram to get the corresponding time on the clock of the GPS track, e.g. a skew of
+/// `2000` means the phone clock is two seconds ahead of the station clock.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CorrelationConfig {
+    /// clock skew applied to telegrams of all stations
+    pub clock_skew: i64,
+    /// additional clock skew for specific stations
+    pub station_skew: HashMap<Uuid, i64>,
+    /// maximum time in milliseconds between two GPS points to interpolate between them
+    pub max_gps_gap: i64,
+    /// time window in milliseconds in which repeated telegrams for the same reporting point
+    /// are considered to be the same transmission, `0` disables the deduplication
+    pub deduplication_window: i64,
+}
+
+impl Default for CorrelationConfig {
+    fn default() -> Self {
+        CorrelationConfig {
+            clock_skew: 0,
+            station_skew: HashMap::new(),
+            max_gps_gap: DEFAULT_MAX_GPS_GAP,
+            deduplication_window: DEFAULT_DEDUPLICATION_WINDOW,
+        }
+    }
+}
+
+impl CorrelationConfig {
+    /// Returns the total clock skew in milliseconds for telegrams received by `station`
+    pub fn skew(&self, station: &Uuid) -> i64 {
+        self.clock_skew + self.station_skew.get(station).copied().unwrap_or(0)
+    }
+
+    /// Largest absolute clock skew over all stations, used to widen database queries
+    fn max_abs_skew(&self) -> i64 {
+        self.station_skew
+            .values()
+            .map(|skew| (self.clock_skew + skew).abs())
+            .max()
+            .unwrap_or(0)
+            .max(self.clock_skew.abs())
+    }
+}
+
+/// Error returned while correlating a trekkie run
+#[derive(Debug)]
+pub enum CorrelationError {
+    /// database query failed
+    DieselError(diesel::result::Error),
+    /// the trekkie run is still in progress
+    RunNotFinished,
+}
+
+impl From<diesel::result::Error> for CorrelationError {
+    fn from(value: diesel::result::Error) -> Self {
+        CorrelationError::DieselError(value)
+    }
+}
+
+impl From<&TrekkieRun> for FinishedMeasurementInterval {
+    fn from(run: &TrekkieRun) -> Self {
+        FinishedMeasurementInterval {
+            start: run.start_time,
+            stop: run.end_time,
+            line: run.line,
+            run: run.run,
+            region: run.region,
+        }
+    }
+}
+
+/// Linearly interpolates the position of the track at `time`. Returns [`None`] if `time` is
+/// outside of the track or the surrounding GPS points are more than `max_gap` milliseconds apart.
+/// `track` has to be sorted by timestamp.
+pub fn interpolate_track(
+    track: &[GpsPoint],
+    time: NaiveDateTime,
+    max_gap: i64,
+) -> Option<(f64, f64)> {
+    let next = track.partition_point(|point| point.timestamp < time);
+    let after = track.get(next)?;
+    if after.timestamp == time {
+        return Some((after.lat, after.lon));
+    }
+    let before = track.get(next.checked_sub(1)?)?;
+
+    let gap = (after.timestamp - before.timestamp).num_milliseconds();
+    if gap > max_gap {
+        return None;
+    }
+    let fraction = (time - before.timestamp).num_milliseconds() as f64 / gap as f64;
+
+    Some((
+        before.lat + fraction * (after.lat - before.lat),
+        before.lon + fraction * (after.lon - before.lon),
+    ))
+}
+
+/// Correlates a single trekkie run. Telegrams that don't belong to the run, that fall into a
+/// gap of the track, or that repeat a reporting point within the deduplication window are
+/// skipped. The track doesn't need to be sorted.
+pub fn correlate(
+    run: &TrekkieRun,
+    track: &[GpsPoint],
+    telegrams: &[R09SaveTelegram],
+    config: &CorrelationConfig,
+) -> Vec<InsertTransmissionLocationRaw> {
+    let interval = FinishedMeasurementInterval::from(run);
+
+    let mut track: Vec<GpsPoint> = track
+        .iter()
+        .filter(|point| point.trekkie_run == run.id)
+        .copied()
+        .collect();
+    track.sort_by_key(|point| point.timestamp);
+
+    // telegrams moved onto the clock of the gps track
+    let mut shifted: Vec<R09SaveTelegram> = telegrams
+        .iter()
+        .map(|telegram| {
+            let mut telegram = telegram.clone();
+            telegram.time += Duration::milliseconds(config.skew(&telegram.station));
+            telegram
+        })
+        .filter(|telegram| interval.fits(telegram))
+        .collect();
+    shifted.sort_by_key(|telegram| telegram.time);
+
+    let mut last_seen: HashMap<i32, NaiveDateTime> = HashMap::new();
+    let mut locations = Vec::new();
+    for telegram in shifted {
+        if let Some(last) = last_seen.get(&telegram.reporting_point) {
+            if (telegram.time - *last).num_milliseconds() < config.deduplication_window {
+                continue;
+            }
+        }
+
+        let Some((lat, lon)) = interpolate_track(&track, telegram.time, config.max_gps_gap) else {
+            continue;
+        };
+        last_seen.insert(telegram.reporting_point, telegram.time);
+        locations.push(InsertTransmissionLocationRaw {
+            id: None,
+            region: run.region,
+            reporting_point: telegram.reporting_point,
+            lat,
+            lon,
+            trekkie_run: run.id,
+            run_owner: run.owner,
+        });
+    }
+
+    locations
+}
+
+/// Loads the trekkie run with its GPS track and matching telegrams from the database, replaces
+/// the raw transmission locations of the run with freshly correlated ones and marks the run as
+/// correlated. Everything happens in a single transaction.
+pub fn correlate_run(
+    database_connection: &mut PgConnection,
+    run_id: Uuid,
+    config: &CorrelationConfig,
+) -> Result<Vec<InsertTransmissionLocationRaw>, CorrelationError> {
+    database_connection.transaction(|connection| {
+        let run: TrekkieRun = trekkie_runs::table
+            .filter(trekkie_runs::id.eq(run_id))
+            .first(connection)?;
+        if !run.finished {
+            return Err(CorrelationError::RunNotFinished);
+        }
+
+        let track: Vec<GpsPoint> = gps_points::table
+            .filter(gps_points::trekkie_run.eq(run_id))
+            .order(gps_points::timestamp.asc())
+            .load(connection)?;
+
+        let margin = Duration::milliseconds(config.max_abs_skew());
+        let telegrams: Vec<R09SaveTelegram> = r09_telegrams::table
+            .filter(r09_telegrams::region.eq(run.region))
+            .filter(r09_telegrams::line.eq(run.line))
+            .filter(r09_telegrams::run_number.eq(run.run))
+            .filter(r09_telegrams::time.ge(run.start_time - margin))
+            .filter(r09_telegrams::time.le(run.end_time + margin))
+            .load(connection)?;
+
+        let locations = correlate(&run, &track, &telegrams, config);
+
+        diesel::delete(
+            r09_transmission_locations_raw::table
+                .filter(r09_transmission_locations_raw::trekkie_run.eq(run_id)),
+        )
+        .execute(connection)?;
+        diesel::insert_into(r09_transmission_locations_raw::table)
+            .values(&locations)
+            .execute(connection)?;
+        diesel::update(trekkie_runs::table.filter(trekkie_runs::id.eq(run_id)))
+            .set(trekkie_runs::correlated.eq(true))
+            .execute(connection)?;
+
+        Ok(locations)
+    })
+}
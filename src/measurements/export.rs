@@ -0,0 +1,214 @@
+//! This module exports trekkie runs for inspection in tools like JOSM or QGIS. The GPS track
+//! becomes a GPX track or a GeoJSON `LineString`, and every telegram that fits the run becomes a
+//! waypoint at the interpolated position of the vehicle at the time of transmission (see
+//! [`correlation`](super::correlation)), annotated with reporting point, line, run and delay.
+//!
+//! The GeoJSON track carries its timestamps in the `coordTimes` property, so exported runs can be
+//! imported again with [`import_geojson`](super::import::import_geojson).
+
+use super::correlation::{interpolate_track, CorrelationConfig};
+use super::FinishedMeasurementInterval;
+use crate::locations::gps::GpsPoint;
+use crate::telegrams::r09::R09SaveTelegram;
+use crate::trekkie::TrekkieRun;
+
+use chrono::{Duration, NaiveDateTime, SecondsFormat};
+use serde_json::json;
+use std::fmt::Write;
+
+/// Telegram placed on the track
+struct TelegramWaypoint<'a> {
+    telegram: &'a R09SaveTelegram,
+    /// time of the telegram on the clock of the GPS track
+    time: NaiveDateTime,
+    lat: f64,
+    lon: f64,
+}
+
+/// RFC 3339 timestamp in UTC, as used by GPX and GeoJSON
+fn format_time(time: &NaiveDateTime) -> String {
+    time.and_utc().to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+/// Escapes text for use in xml elements and attributes
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Points of the run ordered by time
+fn sorted_track(run: &TrekkieRun, track: &[GpsPoint]) -> Vec<GpsPoint> {
+    let mut track: Vec<GpsPoint> = track
+        .iter()
+        .filter(|point| point.trekkie_run == run.id)
+        .copied()
+        .collect();
+    track.sort_by_key(|point| point.timestamp);
+    track
+}
+
+/// Places all telegrams that fit the run on the track. Telegrams that fall into a gap of the
+/// track are left out.
+fn waypoints<'a>(
+    run: &TrekkieRun,
+    track: &[GpsPoint],
+    telegrams: &'a [R09SaveTelegram],
+    config: &CorrelationConfig,
+) -> Vec<TelegramWaypoint<'a>> {
+    let interval = FinishedMeasurementInterval::from(run);
+    let mut waypoints: Vec<TelegramWaypoint> = telegrams
+        .iter()
+        .filter_map(|telegram| {
+            let mut shifted = telegram.clone();
+            shifted.time += Duration::milliseconds(config.skew(&telegram.station));
+            if !interval.fits(&shifted) {
+                return None;
+            }
+            let (lat, lon) = interpolate_track(track, shifted.time, config.max_gps_gap)?;
+            Some(TelegramWaypoint {
+                telegram,
+                time: shifted.time,
+                lat,
+                lon,
+            })
+        })
+        .collect();
+    waypoints.sort_by_key(|waypoint| waypoint.time);
+    waypoints
+}
+
+/// Human readable summary of a telegram
+fn describe(telegram: &R09SaveTelegram) -> String {
+    let optional = |value: Option<i32>| value.map_or(String::from("?"), |v| v.to_string());
+    format!(
+        "line {}, run {}, destination {}, delay {}, station {}",
+        optional(telegram.line),
+        optional(telegram.run_number),
+        optional(telegram.destination_number),
+        optional(telegram.delay),
+        telegram.station
+    )
+}
+
+/// Exports the run as GPX 1.1 document. Telegrams become waypoints named after their reporting
+/// point, the track is a single segment.
+pub fn to_gpx(
+    run: &TrekkieRun,
+    track: &[GpsPoint],
+    telegrams: &[R09SaveTelegram],
+    config: &CorrelationConfig,
+) -> String {
+    let track = sorted_track(run, track);
+    let name = escape_xml(&format!("line {} run {} ({})", run.line, run.run, run.id));
+
+    // writing into a String never fails
+    let mut gpx = String::new();
+    let _ = writeln!(gpx, r#"<?xml version="1.0" encoding="UTF-8"?>"#);
+    let _ = writeln!(
+        gpx,
+        r#"<gpx version="1.1" creator="tlms {}" xmlns="http://www.topografix.com/GPX/1/1">"#,
+        env!("CARGO_PKG_VERSION")
+    );
+    let _ = writeln!(
+        gpx,
+        "  <metadata><name>{name}</name><time>{}</time></metadata>",
+        format_time(&run.start_time)
+    );
+
+    for waypoint in waypoints(run, &track, telegrams, config) {
+        let _ = writeln!(
+            gpx,
+            r#"  <wpt lat="{}" lon="{}"><time>{}</time><name>{}</name><desc>{}</desc><type>{}</type></wpt>"#,
+            waypoint.lat,
+            waypoint.lon,
+            format_time(&waypoint.time),
+            waypoint.telegram.reporting_point,
+            escape_xml(&describe(waypoint.telegram)),
+            waypoint.telegram.r09_type,
+        );
+    }
+
+    let _ = writeln!(gpx, "  <trk><name>{name}</name><trkseg>");
+    for point in &track {
+        let elevation = point
+            .elevation
+            .map(|elevation| format!("<ele>{elevation}</ele>"))
+            .unwrap_or_default();
+        let _ = writeln!(
+            gpx,
+            r#"    <trkpt lat="{}" lon="{}">{elevation}<time>{}</time></trkpt>"#,
+            point.lat,
+            point.lon,
+            format_time(&point.timestamp)
+        );
+    }
+    let _ = writeln!(gpx, "  </trkseg></trk>");
+    let _ = writeln!(gpx, "</gpx>");
+
+    gpx
+}
+
+/// Exports the run as GeoJSON `FeatureCollection`. The first feature is the track as
+/// `LineString`, followed by one `Point` feature per telegram.
+pub fn to_geojson(
+    run: &TrekkieRun,
+    track: &[GpsPoint],
+    telegrams: &[R09SaveTelegram],
+    config: &CorrelationConfig,
+) -> serde_json::Value {
+    let track = sorted_track(run, track);
+
+    let coordinates: Vec<serde_json::Value> = track
+        .iter()
+        .map(|point| match point.elevation {
+            Some(elevation) => json!([point.lon, point.lat, elevation]),
+            None => json!([point.lon, point.lat]),
+        })
+        .collect();
+    let times: Vec<String> = track
+        .iter()
+        .map(|point| format_time(&point.timestamp))
+        .collect();
+
+    let mut features = vec![json!({
+        "type": "Feature",
+        "geometry": {"type": "LineString", "coordinates": coordinates},
+        "properties": {
+            "trekkie_run": run.id,
+            "line": run.line,
+            "run": run.run,
+            "region": run.region,
+            "start_time": format_time(&run.start_time),
+            "end_time": format_time(&run.end_time),
+            "app_name": run.app_name,
+            "correlated": run.correlated,
+            "coordTimes": times,
+        },
+    })];
+
+    features.extend(
+        waypoints(run, &track, telegrams, config)
+            .into_iter()
+            .map(|waypoint| {
+                let telegram = waypoint.telegram;
+                json!({
+                    "type": "Feature",
+                    "geometry": {"type": "Point", "coordinates": [waypoint.lon, waypoint.lat]},
+                    "properties": {
+                        "reporting_point": telegram.reporting_point,
+                        "line": telegram.line,
+                        "run": telegram.run_number,
+                        "destination_number": telegram.destination_number,
+                        "delay": telegram.delay,
+                        "r09_type": telegram.r09_type,
+                        "station": telegram.station,
+                        "time": format_time(&waypoint.time),
+                    },
+                })
+            }),
+    );
+
+    json!({"type": "FeatureCollection", "features": features})
+}
@@ -0,0 +1,303 @@
+//! This module imports GPS recordings from other loggers as trekkie runs, so volunteers aren't
+//! tied to the trekkie app. Supported are GPX tracks, KML `gx:Track`s and GeoJSON `LineString`s
+//! with timestamps in the `coordTimes` (or `coordinateProperties.times`) property. All tracks and
+//! segments in a file are joined into a single run, every point has to carry a timestamp and the
+//! timestamps have to be strictly increasing.
+//!
+//! The importers return an [`ImportedRun`], which holds the [`TrekkieRun`] with `app_name` set to
+//! the importer, and the [`InsertGpsPoint`]s referencing it. Line, run, region and owner have to be
+//! provided by the caller, as none of the formats knows about them. If the caller doesn't know
+//! the region, [`ImportedRun::assign_region`] picks it from the region boundaries.
+
+use crate::locations::boundary::assign_region;
+use crate::locations::gps::InsertGpsPoint;
+use crate::locations::region::Region;
+use crate::trekkie::TrekkieRun;
+
+use chrono::{DateTime, NaiveDateTime};
+use uuid::Uuid;
+
+/// `app_name` of runs imported from GPX
+pub const GPX_IMPORTER: &str = "tlms-gpx-import";
+/// `app_name` of runs imported from KML
+pub const KML_IMPORTER: &str = "tlms-kml-import";
+/// `app_name` of runs imported from GeoJSON
+pub const GEOJSON_IMPORTER: &str = "tlms-geojson-import";
+
+/// Error returned by the importers. Indices count the points in document order, starting at `0`.
+#[derive(Debug)]
+pub enum ImportError {
+    /// document isn't valid xml
+    XmlError(roxmltree::Error),
+    /// document isn't valid json
+    SerdeJsonError(serde_json::Error),
+    /// the document contains no track points
+    EmptyTrack,
+    /// point has no timestamp
+    MissingTimestamp(usize),
+    /// timestamp of the point isn't a valid RFC 3339 date
+    InvalidTimestamp(usize),
+    /// point has missing or invalid coordinates
+    InvalidCoordinate(usize),
+    /// timestamp of the point isn't after the timestamp of the previous point
+    NotMonotonic(usize),
+    /// the document is valid, but doesn't have the expected structure
+    InvalidStructure(String),
+}
+
+impl From<roxmltree::Error> for ImportError {
+    fn from(value: roxmltree::Error) -> Self {
+        ImportError::XmlError(value)
+    }
+}
+
+impl From<serde_json::Error> for ImportError {
+    fn from(value: serde_json::Error) -> Self {
+        ImportError::SerdeJsonError(value)
+    }
+}
+
+/// Information about the run that isn't part of the imported file
+#[derive(Debug, Clone)]
+pub struct RunMetadata {
+    /// line (ger. linie) of the vehicle
+    pub line: i32,
+    /// run (ger. kurs) of the vehicle
+    pub run: i32,
+    /// region the run was recorded in
+    pub region: i64,
+    /// UUID of the user that contributed the recording
+    pub owner: Uuid,
+}
+
+/// Trekkie run created from an imported file, ready to be inserted into the database
+#[derive(Debug, Clone)]
+pub struct ImportedRun {
+    /// the run, spanning from the first to the last point
+    pub run: TrekkieRun,
+    /// points of the run, ordered by time
+    pub points: Vec<InsertGpsPoint>,
+}
+
+impl ImportedRun {
+    /// Sets the region of the run to the region whose boundary contains most of its points, see
+    /// [`assign_region`]. Returns the assigned region, the run is left as is if no region fits.
+    pub fn assign_region(&mut self, regions: &[Region]) -> Option<i64> {
+        let region = assign_region(regions, &self.points)?.id;
+        self.run.region = region;
+        Some(region)
+    }
+}
+
+/// Single point as read from a file, before validation
+#[derive(Debug, Clone, Default)]
+struct ParsedPoint {
+    lat: Option<f64>,
+    lon: Option<f64>,
+    time: Option<String>,
+    elevation: Option<f64>,
+    bearing: Option<f64>,
+    speed: Option<f64>,
+}
+
+/// Validates the parsed points and builds the run
+fn build_run(
+    parsed: Vec<ParsedPoint>,
+    metadata: &RunMetadata,
+    app_name: &str,
+) -> Result<ImportedRun, ImportError> {
+    let id = Uuid::new_v4();
+    let mut points: Vec<InsertGpsPoint> = Vec::with_capacity(parsed.len());
+
+    for (index, point) in parsed.into_iter().enumerate() {
+        let (Some(lat), Some(lon)) = (point.lat, point.lon) else {
+            return Err(ImportError::InvalidCoordinate(index));
+        };
+        if !(-90_f64..=90_f64).contains(&lat) || !(-180_f64..=180_f64).contains(&lon) {
+            return Err(ImportError::InvalidCoordinate(index));
+        }
+        let time = point.time.ok_or(ImportError::MissingTimestamp(index))?;
+        let timestamp: NaiveDateTime = DateTime::parse_from_rfc3339(time.trim())
+            .map_err(|_| ImportError::InvalidTimestamp(index))?
+            .naive_utc();
+        if let Some(previous) = points.last() {
+            if timestamp <= previous.timestamp {
+                return Err(ImportError::NotMonotonic(index));
+            }
+        }
+
+        points.push(InsertGpsPoint {
+            id: None,
+            trekkie_run: id,
+            timestamp,
+            lat,
+            lon,
+            elevation: point.elevation,
+            accuracy: None,
+            vertical_accuracy: None,
+            bearing: point.bearing,
+            speed: point.speed,
+        });
+    }
+
+    let (Some(first), Some(last)) = (points.first(), points.last()) else {
+        return Err(ImportError::EmptyTrack);
+    };
+
+    Ok(ImportedRun {
+        run: TrekkieRun {
+            start_time: first.timestamp,
+            end_time: last.timestamp,
+            line: metadata.line,
+            run: metadata.run,
+            region: metadata.region,
+            owner: metadata.owner,
+            finished: true,
+            id,
+            correlated: false,
+            app_commit: env!("CARGO_PKG_VERSION").to_string(),
+            app_name: app_name.to_string(),
+        },
+        points,
+    })
+}
+
+/// Text of the first child element with the given local name
+fn child_text<'a>(node: roxmltree::Node<'a, '_>, name: &str) -> Option<&'a str> {
+    node.children()
+        .find(|child| child.is_element() && child.tag_name().name() == name)
+        .and_then(|child| child.text())
+}
+
+/// Imports all `trkpt`s of a GPX 1.0 or 1.1 file. Elevation is read from `ele`, bearing and
+/// speed from the GPX 1.0 `course` and `speed` elements.
+pub fn import_gpx(document: &str, metadata: &RunMetadata) -> Result<ImportedRun, ImportError> {
+    let document = roxmltree::Document::parse(document)?;
+    if document.root_element().tag_name().name() != "gpx" {
+        return Err(ImportError::InvalidStructure(String::from(
+            "root element is not <gpx>",
+        )));
+    }
+
+    let parsed = document
+        .descendants()
+        .filter(|node| node.is_element() && node.tag_name().name() == "trkpt")
+        .map(|node| ParsedPoint {
+            lat: node.attribute("lat").and_then(|v| v.trim().parse().ok()),
+            lon: node.attribute("lon").and_then(|v| v.trim().parse().ok()),
+            time: child_text(node, "time").map(str::to_string),
+            elevation: child_text(node, "ele").and_then(|v| v.trim().parse().ok()),
+            bearing: child_text(node, "course").and_then(|v| v.trim().parse().ok()),
+            speed: child_text(node, "speed").and_then(|v| v.trim().parse().ok()),
+        })
+        .collect();
+
+    build_run(parsed, metadata, GPX_IMPORTER)
+}
+
+/// Imports all `gx:Track`s of a KML file. Every `when` element is paired with the `gx:coord`
+/// element at the same position, so both have to be present equally often.
+pub fn import_kml(document: &str, metadata: &RunMetadata) -> Result<ImportedRun, ImportError> {
+    let document = roxmltree::Document::parse(document)?;
+    let mut parsed = Vec::new();
+
+    for track in document
+        .descendants()
+        .filter(|node| node.is_element() && node.tag_name().name() == "Track")
+    {
+        let elements = |name: &'static str| {
+            track
+                .children()
+                .filter(move |child| child.is_element() && child.tag_name().name() == name)
+                .map(|child| child.text().unwrap_or_default())
+        };
+        let whens: Vec<&str> = elements("when").collect();
+        let coords: Vec<&str> = elements("coord").collect();
+        if whens.len() != coords.len() {
+            return Err(ImportError::InvalidStructure(format!(
+                "gx:Track has {} <when> but {} <gx:coord> elements",
+                whens.len(),
+                coords.len()
+            )));
+        }
+
+        for (when, coord) in whens.into_iter().zip(coords) {
+            // gx:coord is "longitude latitude altitude", separated by whitespace
+            let values: Vec<Option<f64>> = coord
+                .split_whitespace()
+                .map(|value| value.parse().ok())
+                .collect();
+            parsed.push(ParsedPoint {
+                lat: values.get(1).copied().flatten(),
+                lon: values.first().copied().flatten(),
+                time: (!when.trim().is_empty()).then(|| when.to_string()),
+                elevation: values.get(2).copied().flatten(),
+                ..Default::default()
+            });
+        }
+    }
+
+    build_run(parsed, metadata, KML_IMPORTER)
+}
+
+/// Parses a single GeoJSON `LineString` feature
+fn parse_geojson_feature(
+    feature: &serde_json::Value,
+    parsed: &mut Vec<ParsedPoint>,
+) -> Result<(), ImportError> {
+    let geometry = &feature["geometry"];
+    if geometry["type"] != "LineString" {
+        return Ok(());
+    }
+    let coordinates = geometry["coordinates"].as_array().ok_or_else(|| {
+        ImportError::InvalidStructure(String::from("LineString without coordinates"))
+    })?;
+
+    let properties = &feature["properties"];
+    let times = properties["coordTimes"]
+        .as_array()
+        .or_else(|| properties["coordinateProperties"]["times"].as_array());
+
+    for (index, coordinate) in coordinates.iter().enumerate() {
+        // positions are [longitude, latitude, elevation]
+        parsed.push(ParsedPoint {
+            lat: coordinate[1].as_f64(),
+            lon: coordinate[0].as_f64(),
+            time: times
+                .and_then(|times| times.get(index))
+                .and_then(|time| time.as_str())
+                .map(str::to_string),
+            elevation: coordinate.get(2).and_then(|elevation| elevation.as_f64()),
+            ..Default::default()
+        });
+    }
+
+    Ok(())
+}
+
+/// Imports all `LineString` features of a GeoJSON `Feature` or `FeatureCollection`. Timestamps
+/// are read from the `coordTimes` property (as written by most converters) or from
+/// `coordinateProperties.times`.
+pub fn import_geojson(document: &str, metadata: &RunMetadata) -> Result<ImportedRun, ImportError> {
+    let document: serde_json::Value = serde_json::from_str(document)?;
+    let mut parsed = Vec::new();
+
+    match document["type"].as_str() {
+        Some("FeatureCollection") => {
+            let features = document["features"].as_array().ok_or_else(|| {
+                ImportError::InvalidStructure(String::from("FeatureCollection without features"))
+            })?;
+            for feature in features {
+                parse_geojson_feature(feature, &mut parsed)?;
+            }
+        }
+        Some("Feature") => parse_geojson_feature(&document, &mut parsed)?,
+        _ => {
+            return Err(ImportError::InvalidStructure(String::from(
+                "expected a Feature or FeatureCollection",
+            )))
+        }
+    }
+
+    build_run(parsed, metadata, GEOJSON_IMPORTER)
+}
@@ -1,3 +1,10 @@
+pub mod clock;
+pub mod correlation;
+pub mod export;
+pub mod import;
+#[cfg(test)]
+mod tests;
+
 use crate::telegrams::r09::R09SaveTelegram;
 
 use chrono::NaiveDateTime;
@@ -0,0 +1,314 @@
+use super::clock::*;
+use super::correlation::*;
+use crate::locations::gps::GpsPoint;
+use crate::telegrams::r09::{R09SaveTelegram, R09Type};
+use crate::trekkie::TrekkieRun;
+
+use chrono::{Duration, NaiveDate, NaiveDateTime};
+use uuid::Uuid;
+
+fn start() -> NaiveDateTime {
+    NaiveDate::from_ymd_opt(2023, 5, 1)
+        .unwrap()
+        .and_hms_opt(12, 0, 0)
+        .unwrap()
+}
+
+fn telegram(station: Uuid, reporting_point: i32, seconds: i64) -> R09SaveTelegram {
+    R09SaveTelegram {
+        id: None,
+        time: start() + Duration::seconds(seconds),
+        station,
+        r09_type: R09Type::R16,
+        delay: Some(0),
+        reporting_point,
+        junction: 0,
+        direction: 0,
+        request_status: 0,
+        priority: None,
+        direction_request: None,
+        line: Some(11),
+        run_number: Some(4),
+        destination_number: Some(7),
+        train_length: None,
+        vehicle_number: None,
+        operator: None,
+        region: 0,
+    }
+}
+
+fn trekkie_run(seconds: i64) -> TrekkieRun {
+    TrekkieRun {
+        start_time: start(),
+        end_time: start() + Duration::seconds(seconds),
+        line: 11,
+        run: 4,
+        region: 0,
+        owner: Uuid::nil(),
+        finished: true,
+        id: Uuid::nil(),
+        correlated: false,
+        app_commit: String::new(),
+        app_name: String::from("test"),
+    }
+}
+
+/// straight track going north, one point every 10 s, ~11 m/s
+fn track(seconds: impl IntoIterator<Item = i64>) -> Vec<GpsPoint> {
+    seconds
+        .into_iter()
+        .map(|seconds| GpsPoint {
+            id: seconds,
+            trekkie_run: Uuid::nil(),
+            timestamp: start() + Duration::seconds(seconds),
+            lat: 51.0 + 0.0001 * seconds as f64,
+            lon: 13.0,
+            elevation: None,
+            accuracy: None,
+            vertical_accuracy: None,
+            bearing: None,
+            speed: None,
+        })
+        .collect()
+}
+
+#[test]
+fn test_correlation_with_clock_skew() {
+    let run = trekkie_run(100);
+    // with a gap between 60 s and 100 s
+    let track = track([0, 10, 20, 30, 40, 50, 60, 100]);
+
+    let skewed = Uuid::from_u128(1);
+    let telegrams = vec![
+        telegram(Uuid::nil(), 100, 15),
+        // same transmission received by a second station whose clock is 5 s behind
+        telegram(skewed, 100, 10),
+        telegram(skewed, 200, 30),
+        // falls into the gap of the track
+        telegram(Uuid::nil(), 300, 75),
+        // after the end of the run
+        telegram(Uuid::nil(), 400, 120),
+    ];
+
+    let mut config = CorrelationConfig::default();
+    config.station_skew.insert(skewed, 5_000);
+
+    let locations = correlate(&run, &track, &telegrams, &config);
+    let points: Vec<(i32, f64)> = locations
+        .iter()
+        .map(|location| (location.reporting_point, location.lat))
+        .collect();
+    assert_eq!(points.len(), 2);
+    assert_eq!(points[0].0, 100);
+    assert!((points[0].1 - 51.0015).abs() < 1e-9);
+    assert_eq!(points[1].0, 200);
+    assert!((points[1].1 - 51.0035).abs() < 1e-9);
+}
+
+#[test]
+fn test_deduplication_window() {
+    let run = trekkie_run(60);
+    let track = track([0, 30, 60]);
+    let telegrams = vec![
+        telegram(Uuid::nil(), 100, 10),
+        telegram(Uuid::from_u128(1), 100, 10),
+        telegram(Uuid::nil(), 100, 12),
+        telegram(Uuid::nil(), 100, 15),
+    ];
+
+    // telegrams exactly one window apart are separate transmissions
+    let config = CorrelationConfig {
+        deduplication_window: 5_000,
+        ..Default::default()
+    };
+    assert_eq!(correlate(&run, &track, &telegrams, &config).len(), 2);
+
+    // a window of 0 keeps every telegram, even ones received at the same time
+    let config = CorrelationConfig {
+        deduplication_window: 0,
+        ..Default::default()
+    };
+    assert_eq!(correlate(&run, &track, &telegrams, &config).len(), 4);
+}
+
+#[test]
+fn test_clock_offset_estimation() {
+    use crate::locations::TransmissionLocation;
+
+    let run = trekkie_run(200);
+    let track = track((0..=20).map(|i| 10 * i));
+
+    // the phone clock is 3 s ahead of station a and 5 s ahead of station b
+    let (a, b) = (Uuid::from_u128(1), Uuid::from_u128(2));
+    let passes = [(1, 20), (2, 50), (3, 80), (4, 110), (5, 140)];
+    let mut telegrams: Vec<R09SaveTelegram> = passes
+        .iter()
+        .map(|(reporting_point, seconds)| telegram(a, *reporting_point, seconds - 3))
+        .collect();
+    telegrams.push(telegram(b, 2, 45));
+    telegrams.push(telegram(b, 4, 105));
+
+    let ground_truth: Vec<TransmissionLocation> = passes
+        .iter()
+        .map(|(reporting_point, seconds)| TransmissionLocation {
+            id: *reporting_point as i64,
+            region: 0,
+            reporting_point: *reporting_point,
+            lat: 51.0 + 0.0001 * *seconds as f64,
+            lon: 13.0,
+            ground_truth: true,
+        })
+        .collect();
+
+    let offsets = ClockOffsetEstimator::default().estimate(&run, &track, &telegrams, &ground_truth);
+    let found: Vec<(Option<Uuid>, i64)> = offsets
+        .iter()
+        .map(|offset| (offset.station, offset.offset_ms))
+        .collect();
+    assert_eq!(found, vec![(None, 3000), (Some(a), 3000), (Some(b), 5000)]);
+    assert!(offsets.iter().all(|offset| offset.ground_truth));
+    assert_eq!(offsets[0].samples, 7);
+    assert!(offsets[0].score < 20.0);
+}
+
+#[test]
+fn test_clock_offset_keeps_samples() {
+    use crate::locations::TransmissionLocation;
+
+    // the track ends at 80 s, the last point after the gap is too far away to interpolate
+    let run = trekkie_run(200);
+    let track = track((0..=8).map(|i| 10 * i).chain([200]));
+    let passes = [(1, 20), (2, 40), (3, 60), (4, 80)];
+    let telegrams: Vec<R09SaveTelegram> = passes
+        .iter()
+        .map(|(reporting_point, seconds)| telegram(Uuid::nil(), *reporting_point, *seconds))
+        .collect();
+
+    // the ground truth of the last reporting point is ~150 m off, any offset pushing its
+    // telegram into the gap has a lower mean distance for the remaining three
+    let ground_truth: Vec<TransmissionLocation> = passes
+        .iter()
+        .map(|(reporting_point, seconds)| TransmissionLocation {
+            id: *reporting_point as i64,
+            region: 0,
+            reporting_point: *reporting_point,
+            lat: 51.0
+                + 0.0001 * *seconds as f64
+                + if *reporting_point == 4 { 0.00135 } else { 0.0 },
+            lon: 13.0,
+            ground_truth: true,
+        })
+        .collect();
+
+    let offsets = ClockOffsetEstimator::default().estimate(&run, &track, &telegrams, &ground_truth);
+    assert_eq!(offsets[0].station, None);
+    assert_eq!(offsets[0].offset_ms, 0);
+    assert_eq!(offsets[0].samples, 4);
+
+    // without the sample requirement the offset dropping the last telegram wins
+    let estimator = ClockOffsetEstimator {
+        min_samples: 2,
+        min_sample_share: 0.0,
+        ..Default::default()
+    };
+    let offsets = estimator.estimate(&run, &track, &telegrams, &ground_truth);
+    assert_eq!(offsets[0].samples, 3);
+}
+
+#[test]
+fn test_import_formats() {
+    use super::import::*;
+
+    let metadata = RunMetadata {
+        line: 11,
+        run: 4,
+        region: 0,
+        owner: Uuid::nil(),
+    };
+
+    let gpx = r#"<?xml version="1.0" encoding="UTF-8"?>
+<gpx version="1.1" creator="logger" xmlns="http://www.topografix.com/GPX/1/1">
+  <trk><trkseg>
+    <trkpt lat="51.0" lon="13.0"><ele>110.5</ele><time>2023-05-01T12:00:00Z</time></trkpt>
+    <trkpt lat="51.001" lon="13.0"><time>2023-05-01T14:00:10+02:00</time></trkpt>
+  </trkseg></trk>
+</gpx>"#;
+    let imported = import_gpx(gpx, &metadata).unwrap();
+    assert_eq!(imported.run.app_name, GPX_IMPORTER);
+    assert_eq!(imported.run.start_time, start());
+    assert_eq!(imported.run.end_time, start() + Duration::seconds(10));
+    assert_eq!(imported.points.len(), 2);
+    assert_eq!(imported.points[0].elevation, Some(110.5));
+    assert!(imported
+        .points
+        .iter()
+        .all(|point| point.trekkie_run == imported.run.id));
+
+    let kml = r#"<kml xmlns="http://www.opengis.net/kml/2.2" xmlns:gx="http://www.google.com/kml/ext/2.2">
+  <Placemark><gx:Track>
+    <when>2023-05-01T12:00:00Z</when>
+    <when>2023-05-01T12:00:10Z</when>
+    <gx:coord>13.0 51.0 110</gx:coord>
+    <gx:coord>13.0 51.001 111</gx:coord>
+  </gx:Track></Placemark>
+</kml>"#;
+    let imported = import_kml(kml, &metadata).unwrap();
+    assert_eq!(imported.run.app_name, KML_IMPORTER);
+    assert_eq!(imported.points[1].lat, 51.001);
+    assert_eq!(imported.points[1].lon, 13.0);
+
+    let geojson = r#"{"type": "FeatureCollection", "features": [{
+        "type": "Feature",
+        "geometry": {"type": "LineString", "coordinates": [[13.0, 51.0], [13.0, 51.001]]},
+        "properties": {"coordTimes": ["2023-05-01T12:00:10Z", "2023-05-01T12:00:00Z"]}
+    }]}"#;
+    assert!(matches!(
+        import_geojson(geojson, &metadata),
+        Err(ImportError::NotMonotonic(1))
+    ));
+    let geojson = geojson.replace("\"2023-05-01T12:00:00Z\"]", "null]");
+    assert!(matches!(
+        import_geojson(&geojson, &metadata),
+        Err(ImportError::MissingTimestamp(1))
+    ));
+}
+
+#[test]
+fn test_export_roundtrip() {
+    use super::export::*;
+    use super::import::*;
+
+    let run = trekkie_run(100);
+    let track = track((0..=10).map(|i| 10 * i));
+    let telegrams = vec![
+        telegram(Uuid::nil(), 100, 15),
+        // different run
+        R09SaveTelegram {
+            run_number: Some(5),
+            ..telegram(Uuid::nil(), 200, 25)
+        },
+    ];
+    let config = CorrelationConfig::default();
+    let metadata = RunMetadata {
+        line: run.line,
+        run: run.run,
+        region: run.region,
+        owner: run.owner,
+    };
+
+    let gpx = to_gpx(&run, &track, &telegrams, &config);
+    assert_eq!(gpx.matches("<wpt ").count(), 1);
+    assert!(gpx.contains("<name>100</name>"));
+    let imported = import_gpx(&gpx, &metadata).unwrap();
+    assert_eq!(imported.points.len(), track.len());
+    assert_eq!(imported.run.start_time, track[0].timestamp);
+
+    let geojson = to_geojson(&run, &track, &telegrams, &config);
+    let features = geojson["features"].as_array().unwrap();
+    assert_eq!(features.len(), 2);
+    assert_eq!(features[1]["properties"]["reporting_point"], 100);
+    assert_eq!(features[1]["properties"]["delay"], 0);
+    assert!((features[1]["geometry"]["coordinates"][1].as_f64().unwrap() - 51.0015).abs() < 1e-9);
+    let imported = import_geojson(&geojson.to_string(), &metadata).unwrap();
+    assert_eq!(imported.run.end_time, track[10].timestamp);
+}
@@ -15,6 +15,18 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    gtfs_mappings (id) {
+        id -> Int8,
+        region -> Int8,
+        line -> Int4,
+        run -> Nullable<Int4>,
+        destination_number -> Nullable<Int4>,
+        route_id -> Text,
+        direction_id -> Nullable<Int4>,
+    }
+}
+
 diesel::table! {
     org_users_relations (id) {
         id -> Uuid,
@@ -34,6 +46,30 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    r09_reporting_point_sequences (id) {
+        id -> Int8,
+        region -> Int8,
+        line -> Int4,
+        destination_number -> Int4,
+        branch -> Int4,
+        position -> Int4,
+        reporting_point -> Int4,
+        confidence -> Float8,
+    }
+}
+
+diesel::table! {
+    r09_telegram_rollups (bucket, region, station, line, reporting_point) {
+        bucket -> Timestamp,
+        region -> Int8,
+        station -> Uuid,
+        line -> Int4,
+        reporting_point -> Int4,
+        telegrams -> Int8,
+    }
+}
+
 diesel::table! {
     r09_telegrams (id) {
         id -> Int8,
@@ -102,6 +138,7 @@ diesel::table! {
         month_gps -> Int8,
         week_gps -> Int8,
         day_gps -> Int8,
+        last_gps_id -> Nullable<Int8>,
     }
 }
 
@@ -119,6 +156,7 @@ diesel::table! {
         lon -> Float8,
         zoom -> Float8,
         work_in_progress -> Bool,
+        boundary -> Nullable<Jsonb>,
     }
 }
 
@@ -156,6 +194,18 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    trekkie_clock_offsets (id) {
+        id -> Int8,
+        trekkie_run -> Uuid,
+        station -> Nullable<Uuid>,
+        offset_ms -> Int8,
+        score -> Float8,
+        samples -> Int4,
+        ground_truth -> Bool,
+    }
+}
+
 diesel::table! {
     trekkie_runs (id) {
         start_time -> Timestamp,
@@ -180,6 +230,7 @@ diesel::table! {
         month_gps -> Int8,
         week_gps -> Int8,
         day_gps -> Int8,
+        last_gps_id -> Nullable<Int8>,
     }
 }
 
@@ -196,9 +247,13 @@ diesel::table! {
 }
 
 diesel::joinable!(gps_points -> trekkie_runs (trekkie_run));
+diesel::joinable!(gtfs_mappings -> regions (region));
 diesel::joinable!(org_users_relations -> organizations (organization));
 diesel::joinable!(org_users_relations -> users (user_id));
 diesel::joinable!(organizations -> users (owner));
+diesel::joinable!(r09_reporting_point_sequences -> regions (region));
+diesel::joinable!(r09_telegram_rollups -> regions (region));
+diesel::joinable!(r09_telegram_rollups -> stations (station));
 diesel::joinable!(r09_telegrams -> regions (region));
 diesel::joinable!(r09_telegrams -> stations (station));
 diesel::joinable!(r09_transmission_locations -> regions (region));
@@ -211,14 +266,19 @@ diesel::joinable!(station_statistics -> stations (id));
 diesel::joinable!(stations -> organizations (organization));
 diesel::joinable!(stations -> regions (region));
 diesel::joinable!(stations -> users (owner));
+diesel::joinable!(trekkie_clock_offsets -> stations (station));
+diesel::joinable!(trekkie_clock_offsets -> trekkie_runs (trekkie_run));
 diesel::joinable!(trekkie_runs -> regions (region));
 diesel::joinable!(trekkie_runs -> users (owner));
 diesel::joinable!(user_statistics -> users (id));
 
 diesel::allow_tables_to_appear_in_same_query!(
     gps_points,
+    gtfs_mappings,
     org_users_relations,
     organizations,
+    r09_reporting_point_sequences,
+    r09_telegram_rollups,
     r09_telegrams,
     r09_transmission_locations,
     r09_transmission_locations_raw,
@@ -227,6 +287,7 @@ diesel::allow_tables_to_appear_in_same_query!(
     regions,
     station_statistics,
     stations,
+    trekkie_clock_offsets,
     trekkie_runs,
     user_statistics,
     users,
@@ -0,0 +1,353 @@
+//! This module computes the [`RegionStatistics`], [`StationStatistics`] and [`UserStatistics`]
+//! from `r09_telegrams` and `gps_points`. Totals are updated incrementally: telegrams are counted
+//! by time, as they are stored when they are received, while gps points are counted after the
+//! `last_gps_id`, as trekkie runs are uploaded long after they were recorded. The day, week and
+//! month windows are counted over their time range, which is cheap thanks to the
+//! `r09_telegrams_time` index. Every entity is upserted in its own transaction.
+//!
+//! Telegrams are only counted up to [`SETTLE_LAG`] before the update, and the next update
+//! continues from there, so telegrams committed late by slow stations are still counted.
+
+use super::{RegionStatistics, StationStatistics, UserStatistics};
+use crate::schema::*;
+
+use chrono::{Duration, NaiveDateTime};
+use diesel::dsl::{count_star, max};
+use diesel::pg::Pg;
+use diesel::{
+    Connection, ExpressionMethods, OptionalExtension, PgConnection, QueryDsl, QueryResult,
+    RunQueryDsl,
+};
+use uuid::Uuid;
+
+/// Length of the `day_*` window
+pub const DAY: Duration = Duration::days(1);
+/// Length of the `week_*` window
+pub const WEEK: Duration = Duration::days(7);
+/// Length of the `month_*` window
+pub const MONTH: Duration = Duration::days(30);
+/// Telegrams younger than this are not counted yet, as they may still be committed (10 minutes)
+pub const SETTLE_LAG: Duration = Duration::minutes(10);
+
+/// Total and windowed number of records
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(super) struct Counts {
+    pub(super) total: i64,
+    pub(super) month: i64,
+    pub(super) week: i64,
+    pub(super) day: i64,
+}
+
+/// Counts records up to `now`, the total is taken as is. `count` returns the number of records
+/// after the given time.
+pub(super) fn counts(
+    total: i64,
+    now: NaiveDateTime,
+    mut count: impl FnMut(NaiveDateTime) -> QueryResult<i64>,
+) -> QueryResult<Counts> {
+    Ok(Counts {
+        total,
+        month: count(now - MONTH)?,
+        week: count(now - WEEK)?,
+        day: count(now - DAY)?,
+    })
+}
+
+/// Total of records up to `lag` before `now` by time. `count` returns the number of records
+/// after the first given time, or of all records if none is given, up to the second one. The
+/// total is continued from `previous` (last update and total), which counted up to `lag` before
+/// its update, unless that lies in the future.
+pub(super) fn total_since(
+    previous: Option<(NaiveDateTime, i64)>,
+    now: NaiveDateTime,
+    lag: Duration,
+    count: impl FnOnce(Option<NaiveDateTime>, NaiveDateTime) -> QueryResult<i64>,
+) -> QueryResult<i64> {
+    let settled = now - lag;
+    match previous {
+        Some((last_updated, total)) if last_updated <= now => {
+            Ok(total + count(Some(last_updated - lag), settled)?)
+        }
+        _ => count(None, settled),
+    }
+}
+
+/// Total of records by id, returned with the id of the last counted record. `count` returns the
+/// number and the largest id of the records after the given id, or of all records if none is
+/// given. The total is continued from `previous` (last counted id and total).
+pub(super) fn total_after(
+    previous: Option<(i64, i64)>,
+    count: impl FnOnce(Option<i64>) -> QueryResult<(i64, Option<i64>)>,
+) -> QueryResult<(i64, Option<i64>)> {
+    match previous {
+        Some((last_id, total)) => {
+            let (new, last) = count(Some(last_id))?;
+            Ok((total + new, last.or(Some(last_id))))
+        }
+        None => count(None),
+    }
+}
+
+/// Counts the telegrams matching `query` after `from` and up to `until`
+fn count_telegrams(
+    database_connection: &mut PgConnection,
+    query: r09_telegrams::BoxedQuery<'static, Pg>,
+    from: Option<NaiveDateTime>,
+    until: NaiveDateTime,
+) -> QueryResult<i64> {
+    let mut query = query.filter(r09_telegrams::time.le(until));
+    if let Some(from) = from {
+        query = query.filter(r09_telegrams::time.gt(from));
+    }
+    query.count().get_result(database_connection)
+}
+
+/// Counts the gps points matching `query` after `from` and up to `until`
+fn count_gps_points(
+    database_connection: &mut PgConnection,
+    query: gps_points::BoxedQuery<'static, Pg>,
+    from: NaiveDateTime,
+    until: NaiveDateTime,
+) -> QueryResult<i64> {
+    query
+        .filter(gps_points::timestamp.gt(from))
+        .filter(gps_points::timestamp.le(until))
+        .count()
+        .get_result(database_connection)
+}
+
+/// Counts the gps points matching `query` with an id after `after` and returns their largest id
+fn count_gps_points_after(
+    database_connection: &mut PgConnection,
+    query: gps_points::BoxedQuery<'static, Pg>,
+    after: Option<i64>,
+) -> QueryResult<(i64, Option<i64>)> {
+    let mut query = query;
+    if let Some(after) = after {
+        query = query.filter(gps_points::id.gt(after));
+    }
+    query
+        .select((count_star(), max(gps_points::id)))
+        .get_result(database_connection)
+}
+
+/// Gps points of all trekkie runs matching the filter
+macro_rules! gps_points_of_runs {
+    ($filter:expr) => {
+        gps_points::table
+            .filter(
+                gps_points::trekkie_run
+                    .eq_any(trekkie_runs::table.filter($filter).select(trekkie_runs::id)),
+            )
+            .into_boxed()
+    };
+}
+
+/// Updates the statistics of the region up to `now`
+pub fn update_region(
+    database_connection: &mut PgConnection,
+    region_id: i64,
+    now: NaiveDateTime,
+) -> QueryResult<RegionStatistics> {
+    database_connection.transaction(|connection| {
+        let previous: Option<RegionStatistics> = region_statistics::table
+            .find(region_id)
+            .first(connection)
+            .optional()?;
+
+        let telegrams_of_region = || {
+            r09_telegrams::table
+                .filter(r09_telegrams::region.eq(region_id))
+                .into_boxed()
+        };
+        let total_telegrams = total_since(
+            previous
+                .as_ref()
+                .map(|p| (p.last_updated, p.total_telegrams)),
+            now,
+            SETTLE_LAG,
+            |from, until| count_telegrams(connection, telegrams_of_region(), from, until),
+        )?;
+        let settled = now - SETTLE_LAG;
+        let telegrams = counts(total_telegrams, settled, |from| {
+            count_telegrams(connection, telegrams_of_region(), Some(from), settled)
+        })?;
+
+        let (total_gps, last_gps_id) = total_after(
+            previous
+                .as_ref()
+                .and_then(|p| Some((p.last_gps_id?, p.total_gps))),
+            |after| {
+                let query = gps_points_of_runs!(trekkie_runs::region.eq(region_id));
+                count_gps_points_after(connection, query, after)
+            },
+        )?;
+        let gps = counts(total_gps, now, |from| {
+            let query = gps_points_of_runs!(trekkie_runs::region.eq(region_id));
+            count_gps_points(connection, query, from, now)
+        })?;
+
+        let statistics = RegionStatistics {
+            id: region_id,
+            last_updated: now,
+            total_telegrams: telegrams.total,
+            month_telegrams: telegrams.month,
+            week_telegrams: telegrams.week,
+            day_telegrams: telegrams.day,
+            total_gps: gps.total,
+            month_gps: gps.month,
+            week_gps: gps.week,
+            day_gps: gps.day,
+            last_gps_id,
+        };
+        diesel::insert_into(region_statistics::table)
+            .values(&statistics)
+            .on_conflict(region_statistics::id)
+            .do_update()
+            .set(&statistics)
+            .execute(connection)?;
+
+        Ok(statistics)
+    })
+}
+
+/// Updates the statistics of the station up to `now`
+pub fn update_station(
+    database_connection: &mut PgConnection,
+    station_id: Uuid,
+    now: NaiveDateTime,
+) -> QueryResult<StationStatistics> {
+    database_connection.transaction(|connection| {
+        let previous: Option<StationStatistics> = station_statistics::table
+            .find(station_id)
+            .first(connection)
+            .optional()?;
+
+        let telegrams_of_station = || {
+            r09_telegrams::table
+                .filter(r09_telegrams::station.eq(station_id))
+                .into_boxed()
+        };
+        let total_telegrams = total_since(
+            previous
+                .as_ref()
+                .map(|p| (p.last_updated, p.total_telegrams)),
+            now,
+            SETTLE_LAG,
+            |from, until| count_telegrams(connection, telegrams_of_station(), from, until),
+        )?;
+        let settled = now - SETTLE_LAG;
+        let telegrams = counts(total_telegrams, settled, |from| {
+            count_telegrams(connection, telegrams_of_station(), Some(from), settled)
+        })?;
+
+        let statistics = StationStatistics {
+            id: station_id,
+            last_updated: now,
+            total_telegrams: telegrams.total,
+            month_telegrams: telegrams.month,
+            week_telegrams: telegrams.week,
+            day_telegrams: telegrams.day,
+        };
+        diesel::insert_into(station_statistics::table)
+            .values(&statistics)
+            .on_conflict(station_statistics::id)
+            .do_update()
+            .set(&statistics)
+            .execute(connection)?;
+
+        Ok(statistics)
+    })
+}
+
+/// Updates the statistics of the user up to `now`
+pub fn update_user(
+    database_connection: &mut PgConnection,
+    user_id: Uuid,
+    now: NaiveDateTime,
+) -> QueryResult<UserStatistics> {
+    database_connection.transaction(|connection| {
+        let previous: Option<UserStatistics> = user_statistics::table
+            .find(user_id)
+            .first(connection)
+            .optional()?;
+
+        let (total_gps, last_gps_id) = total_after(
+            previous
+                .as_ref()
+                .and_then(|p| Some((p.last_gps_id?, p.total_gps))),
+            |after| {
+                let query = gps_points_of_runs!(trekkie_runs::owner.eq(user_id));
+                count_gps_points_after(connection, query, after)
+            },
+        )?;
+        let gps = counts(total_gps, now, |from| {
+            let query = gps_points_of_runs!(trekkie_runs::owner.eq(user_id));
+            count_gps_points(connection, query, from, now)
+        })?;
+
+        let statistics = UserStatistics {
+            id: user_id,
+            last_updated: now,
+            total_gps: gps.total,
+            month_gps: gps.month,
+            week_gps: gps.week,
+            day_gps: gps.day,
+            last_gps_id,
+        };
+        diesel::insert_into(user_statistics::table)
+            .values(&statistics)
+            .on_conflict(user_statistics::id)
+            .do_update()
+            .set(&statistics)
+            .execute(connection)?;
+
+        Ok(statistics)
+    })
+}
+
+/// Number of entities updated by [`update_all`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UpdateSummary {
+    /// updated regions
+    pub regions: usize,
+    /// updated stations
+    pub stations: usize,
+    /// updated users
+    pub users: usize,
+}
+
+/// Updates the statistics of all active regions, stations and users up to `now`. Stops at the
+/// first error, entities updated before stay updated.
+pub fn update_all(
+    database_connection: &mut PgConnection,
+    now: NaiveDateTime,
+) -> QueryResult<UpdateSummary> {
+    let region_ids: Vec<i64> = regions::table
+        .filter(regions::deactivated.eq(false))
+        .select(regions::id)
+        .load(database_connection)?;
+    let station_ids: Vec<Uuid> = stations::table
+        .filter(stations::deactivated.eq(false))
+        .select(stations::id)
+        .load(database_connection)?;
+    let user_ids: Vec<Uuid> = users::table
+        .filter(users::deactivated.eq(false))
+        .select(users::id)
+        .load(database_connection)?;
+
+    let mut summary = UpdateSummary::default();
+    for region_id in region_ids {
+        update_region(database_connection, region_id, now)?;
+        summary.regions += 1;
+    }
+    for station_id in station_ids {
+        update_station(database_connection, station_id, now)?;
+        summary.stations += 1;
+    }
+    for user_id in user_ids {
+        update_user(database_connection, user_id, now)?;
+        summary.users += 1;
+    }
+    Ok(summary)
+}
@@ -0,0 +1,402 @@
+//! This module aggregates the `delay` of `r09_telegrams` into punctuality statistics of a region.
+//! A [`DelayReport`] holds the [`DelayDistribution`] over all telegrams of a time range and
+//! broken down by line, reporting point, hour of day and weekday.
+//!
+//! R09 delays are whole minutes between -7 and +7, positive values mean the vehicle is late.
+//! Delays are therefore collected into exact [`DelayHistogram`]s instead of keeping every
+//! telegram. The telegram time is stored in UTC, hours and weekdays are taken from it in the
+//! time zone of the region, e.g. [`DEFAULT_TIME_ZONE`].
+//!
+//! Vehicles repeat their telegrams and several stations may receive the same telegram, so
+//! identical telegrams within a short window only count once, like in
+//! [`count_unique`](super::quality::count_unique).
+
+use chrono::{Duration, NaiveDateTime};
+use diesel::pg::Pg;
+use diesel::query_builder::{BoxedSqlQuery, SqlQuery};
+use diesel::sql_types::{BigInt, Integer, Interval, Nullable, Text, Timestamp};
+use diesel::{PgConnection, QueryableByName, RunQueryDsl};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use utoipa::ToSchema;
+
+/// Default earliest delay in minutes that is still on time
+pub const DEFAULT_ON_TIME_EARLY: i32 = -1;
+/// Default latest delay in minutes that is still on time
+pub const DEFAULT_ON_TIME_LATE: i32 = 3;
+/// Default time zone for hours and weekdays, the one of the regions served so far
+pub const DEFAULT_TIME_ZONE: &str = "Europe/Berlin";
+
+/// Range of delays in minutes that are considered on time, both ends inclusive
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct OnTimeWindow {
+    /// earliest delay that is on time, usually negative
+    pub early: i32,
+    /// latest delay that is on time
+    pub late: i32,
+}
+
+impl Default for OnTimeWindow {
+    fn default() -> Self {
+        OnTimeWindow {
+            early: DEFAULT_ON_TIME_EARLY,
+            late: DEFAULT_ON_TIME_LATE,
+        }
+    }
+}
+
+impl OnTimeWindow {
+    /// Returns true if the delay is inside the window
+    pub fn contains(&self, delay: i32) -> bool {
+        (self.early..=self.late).contains(&delay)
+    }
+}
+
+/// Number of telegrams per delay in minutes
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DelayHistogram {
+    counts: BTreeMap<i32, u64>,
+}
+
+impl DelayHistogram {
+    /// Adds `count` telegrams with the given delay
+    pub fn add(&mut self, delay: i32, count: u64) {
+        *self.counts.entry(delay).or_default() += count;
+    }
+
+    /// Number of telegrams
+    pub fn samples(&self) -> u64 {
+        self.counts.values().sum()
+    }
+
+    /// Mean delay in minutes, `None` if empty
+    pub fn mean(&self) -> Option<f64> {
+        let samples = self.samples();
+        if samples == 0 {
+            return None;
+        }
+        let sum: f64 = self
+            .counts
+            .iter()
+            .map(|(delay, count)| *delay as f64 * *count as f64)
+            .sum();
+        Some(sum / samples as f64)
+    }
+
+    /// Delay at the given quantile (0 to 1) using the nearest-rank method, `None` if empty
+    pub fn percentile(&self, quantile: f64) -> Option<i32> {
+        let samples = self.samples();
+        let rank = ((quantile.clamp(0_f64, 1_f64) * samples as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (delay, count) in &self.counts {
+            seen += count;
+            if seen >= rank {
+                return Some(*delay);
+            }
+        }
+        None
+    }
+
+    /// Share of telegrams inside the window, `None` if empty
+    pub fn share_within(&self, window: &OnTimeWindow) -> Option<f64> {
+        let samples = self.samples();
+        if samples == 0 {
+            return None;
+        }
+        let within: u64 = self
+            .counts
+            .range(window.early..=window.late)
+            .map(|(_, count)| count)
+            .sum();
+        Some(within as f64 / samples as f64)
+    }
+}
+
+/// Summary of a [`DelayHistogram`]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct DelayDistribution {
+    /// number of telegrams with a delay
+    pub samples: u64,
+    /// mean delay in minutes
+    pub mean: f64,
+    /// median delay in minutes
+    pub median: i32,
+    /// 90th percentile of the delay in minutes
+    pub p90: i32,
+    /// share of telegrams inside the [`OnTimeWindow`], between 0 and 1
+    pub on_time: f64,
+}
+
+impl DelayDistribution {
+    /// Summarizes the histogram, `None` if it is empty
+    pub fn new(histogram: &DelayHistogram, window: &OnTimeWindow) -> Option<Self> {
+        Some(DelayDistribution {
+            samples: histogram.samples(),
+            mean: histogram.mean()?,
+            median: histogram.percentile(0.5)?,
+            p90: histogram.percentile(0.9)?,
+            on_time: histogram.share_within(window)?,
+        })
+    }
+}
+
+/// What a [`DelayRow`] is grouped by
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, ToSchema,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum DelayDimension {
+    /// line (ger. linie)
+    Line,
+    /// reporting point (ger. meldepunkt)
+    ReportingPoint,
+    /// hour of day from 0 to 23 in local time
+    HourOfDay,
+    /// weekday from 0 (monday) to 6 (sunday) in local time
+    Weekday,
+}
+
+/// Delay distribution of one line, reporting point, hour or weekday
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct DelayRow {
+    /// what the row is grouped by
+    pub dimension: DelayDimension,
+    /// line, reporting point, hour or weekday
+    pub key: i32,
+    /// number of telegrams with a delay
+    pub samples: u64,
+    /// mean delay in minutes
+    pub mean: f64,
+    /// median delay in minutes
+    pub median: i32,
+    /// 90th percentile of the delay in minutes
+    pub p90: i32,
+    /// share of telegrams inside the [`OnTimeWindow`], between 0 and 1
+    pub on_time: f64,
+}
+
+/// Punctuality of a region over a time range
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct DelayReport {
+    /// region identifier
+    pub region: i64,
+    /// start of the range
+    #[serde(with = "crate::time_serializer")]
+    pub from: NaiveDateTime,
+    /// end of the range, exclusive
+    #[serde(with = "crate::time_serializer")]
+    pub until: NaiveDateTime,
+    /// delays considered on time
+    pub on_time_window: OnTimeWindow,
+    /// distribution over all telegrams, `None` if no telegram had a delay
+    pub overall: Option<DelayDistribution>,
+    /// distributions ordered by dimension and key
+    pub rows: Vec<DelayRow>,
+}
+
+/// Error returned when writing a [`DelayReport`]
+#[derive(Debug)]
+pub enum DelayReportError {
+    /// See [`csv::Error`]
+    CsvError(csv::Error),
+    /// See [`serde_json::Error`]
+    JsonError(serde_json::Error),
+    /// See [`std::io::Error`]
+    IoError(std::io::Error),
+}
+
+impl From<csv::Error> for DelayReportError {
+    fn from(e: csv::Error) -> DelayReportError {
+        DelayReportError::CsvError(e)
+    }
+}
+
+impl From<serde_json::Error> for DelayReportError {
+    fn from(e: serde_json::Error) -> DelayReportError {
+        DelayReportError::JsonError(e)
+    }
+}
+
+impl From<std::io::Error> for DelayReportError {
+    fn from(e: std::io::Error) -> DelayReportError {
+        DelayReportError::IoError(e)
+    }
+}
+
+impl DelayReport {
+    /// Rows of a single dimension
+    pub fn rows(&self, dimension: DelayDimension) -> impl Iterator<Item = &DelayRow> {
+        self.rows
+            .iter()
+            .filter(move |row| row.dimension == dimension)
+    }
+
+    /// Writes the report as json
+    pub fn write_json(&self, writer: impl Write) -> Result<(), DelayReportError> {
+        Ok(serde_json::to_writer(writer, self)?)
+    }
+
+    /// Writes the rows as csv with a header line, the overall distribution is left out
+    pub fn write_csv(&self, writer: impl Write) -> Result<(), DelayReportError> {
+        let mut writer = csv::Writer::from_writer(writer);
+        for row in &self.rows {
+            writer.serialize(row)?;
+        }
+        Ok(writer.flush()?)
+    }
+}
+
+/// Collects delays into histograms per dimension
+#[derive(Debug, Clone, Default)]
+pub struct DelayAnalysis {
+    overall: DelayHistogram,
+    groups: HashMap<(DelayDimension, i32), DelayHistogram>,
+}
+
+impl DelayAnalysis {
+    /// Creates an empty analysis
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `count` telegrams with the given delay, received in the hour of day (0 to 23) of the
+    /// weekday (0 for monday to 6 for sunday). Telegrams without line only count towards the
+    /// other dimensions.
+    pub fn add(
+        &mut self,
+        hour: i32,
+        weekday: i32,
+        line: Option<i32>,
+        reporting_point: i32,
+        delay: i32,
+        count: u64,
+    ) {
+        self.overall.add(delay, count);
+        let keys = [
+            line.map(|line| (DelayDimension::Line, line)),
+            Some((DelayDimension::ReportingPoint, reporting_point)),
+            Some((DelayDimension::HourOfDay, hour)),
+            Some((DelayDimension::Weekday, weekday)),
+        ];
+        for key in keys.into_iter().flatten() {
+            self.groups.entry(key).or_default().add(delay, count);
+        }
+    }
+
+    /// Summarizes the collected delays
+    pub fn finish(
+        self,
+        region: i64,
+        from: NaiveDateTime,
+        until: NaiveDateTime,
+        on_time_window: OnTimeWindow,
+    ) -> DelayReport {
+        let mut rows: Vec<DelayRow> = self
+            .groups
+            .iter()
+            .filter_map(|((dimension, key), histogram)| {
+                let distribution = DelayDistribution::new(histogram, &on_time_window)?;
+                Some(DelayRow {
+                    dimension: *dimension,
+                    key: *key,
+                    samples: distribution.samples,
+                    mean: distribution.mean,
+                    median: distribution.median,
+                    p90: distribution.p90,
+                    on_time: distribution.on_time,
+                })
+            })
+            .collect();
+        rows.sort_by_key(|row| (row.dimension, row.key));
+
+        DelayReport {
+            region,
+            from,
+            until,
+            on_time_window,
+            overall: DelayDistribution::new(&self.overall, &on_time_window),
+            rows,
+        }
+    }
+
+    /// Query grouping the telegrams of a region with a delay in the time range by hour of day,
+    /// weekday, line, reporting point and delay. Hours and weekdays are taken in `time_zone`,
+    /// an IANA name like [`DEFAULT_TIME_ZONE`]. A telegram is left out if a telegram with the
+    /// same line, run number, reporting point, request status and delay was received less than
+    /// `duplicate_window` before, by any station. Telegrams without line or run number can't be
+    /// told apart from other vehicles and are never left out.
+    pub fn query(
+        region: i64,
+        from: NaiveDateTime,
+        until: NaiveDateTime,
+        duplicate_window: Duration,
+        time_zone: &str,
+    ) -> BoxedSqlQuery<'static, Pg, SqlQuery> {
+        diesel::sql_query(
+            "SELECT extract(hour FROM local)::INT AS hour, \
+             (extract(isodow FROM local) - 1)::INT AS weekday, line, reporting_point, delay, \
+             count(*) AS telegrams FROM (\
+             SELECT time AT TIME ZONE 'UTC' AT TIME ZONE $5 AS local, line, reporting_point, \
+             delay, time - lag(time) OVER (\
+             PARTITION BY line, run_number, reporting_point, request_status, delay, \
+             CASE WHEN line IS NULL OR run_number IS NULL THEN id END \
+             ORDER BY time) AS since FROM r09_telegrams \
+             WHERE region = $1 AND time >= $2 AND time < $3 AND delay IS NOT NULL\
+             ) AS telegrams WHERE since IS NULL OR since > $4 \
+             GROUP BY 1, 2, 3, 4, 5",
+        )
+        .into_boxed()
+        .bind::<BigInt, _>(region)
+        .bind::<Timestamp, _>(from)
+        .bind::<Timestamp, _>(until)
+        .bind::<Interval, _>(duplicate_window)
+        .bind::<Text, _>(time_zone.to_string())
+    }
+
+    /// Computes the report of a region from the telegrams with a delay in the time range, see
+    /// [`DelayAnalysis::query`]. The telegrams are grouped in the database, so only one row per
+    /// distinct combination of hour, weekday, line, reporting point and delay is transferred.
+    pub fn from_postgres(
+        database_connection: &mut PgConnection,
+        region: i64,
+        from: NaiveDateTime,
+        until: NaiveDateTime,
+        duplicate_window: Duration,
+        time_zone: &str,
+        on_time_window: OnTimeWindow,
+    ) -> diesel::QueryResult<DelayReport> {
+        let groups: Vec<DelayGroup> = Self::query(region, from, until, duplicate_window, time_zone)
+            .load(database_connection)?;
+
+        let mut analysis = DelayAnalysis::new();
+        for group in groups {
+            analysis.add(
+                group.hour,
+                group.weekday,
+                group.line,
+                group.reporting_point,
+                group.delay,
+                group.telegrams as u64,
+            );
+        }
+        Ok(analysis.finish(region, from, until, on_time_window))
+    }
+}
+
+/// Row returned by the grouping query of [`DelayAnalysis::from_postgres`]
+#[derive(QueryableByName)]
+struct DelayGroup {
+    #[diesel(sql_type = Integer)]
+    hour: i32,
+    #[diesel(sql_type = Integer)]
+    weekday: i32,
+    #[diesel(sql_type = Nullable<Integer>)]
+    line: Option<i32>,
+    #[diesel(sql_type = Integer)]
+    reporting_point: i32,
+    #[diesel(sql_type = Integer)]
+    delay: i32,
+    #[diesel(sql_type = BigInt)]
+    telegrams: i64,
+}
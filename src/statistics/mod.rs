@@ -1,5 +1,16 @@
+//! This module holds the per region, station and user statistics tables. They are filled by
+//! [`compute`], [`timeseries`] holds bucketed telegram counts over arbitrary time ranges,
+//! [`delay`] the punctuality of lines and [`quality`] the reception quality of stations.
+
+pub mod compute;
+pub mod delay;
+pub mod quality;
+#[cfg(test)]
+mod tests;
+pub mod timeseries;
+
 use chrono::NaiveDateTime;
-use diesel::{Insertable, Queryable};
+use diesel::{AsChangeset, Insertable, Queryable};
 use serde::{Deserialize, Serialize};
 use utoipa::ToSchema;
 use uuid::Uuid;
@@ -7,7 +18,7 @@ use uuid::Uuid;
 use crate::schema::{region_statistics, station_statistics, user_statistics};
 
 /// Statistics for Regions
-#[derive(Debug, Clone, Serialize, Deserialize, Queryable, Insertable, ToSchema)]
+#[derive(Debug, Clone, Serialize, Deserialize, Queryable, Insertable, AsChangeset, ToSchema)]
 #[diesel(table_name = region_statistics)]
 pub struct RegionStatistics {
     /// region identifier
@@ -31,10 +42,13 @@ pub struct RegionStatistics {
     pub week_gps: i64,
     /// amount of gps points received in this region in the last 24h
     pub day_gps: i64,
+    /// id of the last gps point counted into `total_gps`
+    #[serde(default)]
+    pub last_gps_id: Option<i64>,
 }
 
 /// Statistics for Stations
-#[derive(Debug, Clone, Serialize, Deserialize, Queryable, Insertable, ToSchema)]
+#[derive(Debug, Clone, Serialize, Deserialize, Queryable, Insertable, AsChangeset, ToSchema)]
 #[diesel(table_name = station_statistics)]
 pub struct StationStatistics {
     /// station identifier
@@ -53,7 +67,7 @@ pub struct StationStatistics {
 }
 
 /// Statistics for Users
-#[derive(Debug, Clone, Serialize, Deserialize, Queryable, Insertable, ToSchema)]
+#[derive(Debug, Clone, Serialize, Deserialize, Queryable, Insertable, AsChangeset, ToSchema)]
 #[diesel(table_name = user_statistics)]
 pub struct UserStatistics {
     /// user identifier
@@ -69,4 +83,7 @@ pub struct UserStatistics {
     pub week_gps: i64,
     /// amount of gps points received in this region in the last 24h
     pub day_gps: i64,
+    /// id of the last gps point counted into `total_gps`
+    #[serde(default)]
+    pub last_gps_id: Option<i64>,
 }
@@ -0,0 +1,306 @@
+//! This module rates how well a station receives telegrams over a time range. Beyond the plain
+//! counts of [`StationStatistics`](super::StationStatistics) a [`StationQuality`] holds
+//!
+//! - the share of telegrams that are repetitions of a telegram the station already received,
+//! - the share of raw (unparsable) telegrams in `raw_telegrams`,
+//! - the gaps in which the station received nothing at all,
+//! - and per reporting point, how many telegrams the station received compared to the best other
+//!   station of the region hearing the same reporting point.
+
+use crate::schema::{r09_telegrams, raw_telegrams, stations};
+
+use chrono::{Duration, NaiveDateTime};
+use diesel::dsl::count_star;
+use diesel::{ExpressionMethods, PgConnection, QueryDsl, QueryResult, RunQueryDsl};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+/// Default time in which an identical telegram counts as a repetition (10 seconds)
+pub const DEFAULT_DUPLICATE_WINDOW: Duration = Duration::seconds(10);
+/// Default minimal length of a reception gap (30 minutes)
+pub const DEFAULT_GAP_THRESHOLD: Duration = Duration::minutes(30);
+
+/// Parameters of the quality computation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StationQualityConfig {
+    /// time in which an identical telegram counts as a repetition
+    pub duplicate_window: Duration,
+    /// minimal time without any telegram that counts as a gap
+    pub gap_threshold: Duration,
+}
+
+impl Default for StationQualityConfig {
+    fn default() -> Self {
+        StationQualityConfig {
+            duplicate_window: DEFAULT_DUPLICATE_WINDOW,
+            gap_threshold: DEFAULT_GAP_THRESHOLD,
+        }
+    }
+}
+
+/// Content of a telegram that stays the same when the vehicle repeats it: reporting point,
+/// junction, direction, request status, line, run number and destination number
+pub type TelegramContent = (i32, i32, i16, i16, Option<i32>, Option<i32>, Option<i32>);
+
+/// Time in which a station received no telegram at all
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct ReceptionGap {
+    /// last reception before the gap, or start of the range
+    #[serde(with = "crate::time_serializer")]
+    pub from: NaiveDateTime,
+    /// first reception after the gap, or end of the range
+    #[serde(with = "crate::time_serializer")]
+    pub until: NaiveDateTime,
+}
+
+impl ReceptionGap {
+    /// Length of the gap
+    pub fn duration(&self) -> Duration {
+        self.until - self.from
+    }
+}
+
+/// Telegrams of one reporting point received by the station and its best neighbour
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct ReportingPointComparison {
+    /// reporting point (ger. meldepunkt)
+    pub reporting_point: i32,
+    /// telegrams received by the station
+    pub telegrams: i64,
+    /// other station of the region that received most telegrams of the reporting point
+    pub neighbour: Option<Uuid>,
+    /// telegrams received by that neighbour
+    pub neighbour_telegrams: i64,
+}
+
+/// Reception quality of a station over a time range
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct StationQuality {
+    /// station identifier
+    pub station: Uuid,
+    /// start of the range
+    #[serde(with = "crate::time_serializer")]
+    pub from: NaiveDateTime,
+    /// end of the range, exclusive
+    #[serde(with = "crate::time_serializer")]
+    pub until: NaiveDateTime,
+    /// parsed telegrams received
+    pub telegrams: i64,
+    /// parsed telegrams that were not a repetition of an earlier one
+    pub unique_telegrams: i64,
+    /// share of parsed telegrams that were not a repetition, `None` without telegrams
+    pub unique_ratio: Option<f64>,
+    /// raw telegrams received
+    pub raw_telegrams: i64,
+    /// share of raw telegrams among all received telegrams, `None` without telegrams
+    pub raw_share: Option<f64>,
+    /// gaps longer than the threshold, ordered by time
+    pub gaps: Vec<ReceptionGap>,
+    /// reporting points heard by the station, ordered by reporting point
+    pub reporting_points: Vec<ReportingPointComparison>,
+    /// telegrams of the station divided by the telegrams of the best station per reporting point
+    /// (including itself), `None` without telegrams
+    pub relative_reception: Option<f64>,
+}
+
+/// Counts the telegrams that are not a repetition of an identical telegram in the preceding
+/// `window`. The telegrams have to be ordered by time.
+pub fn count_unique(telegrams: &[(NaiveDateTime, TelegramContent)], window: Duration) -> i64 {
+    let mut last_seen: HashMap<&TelegramContent, NaiveDateTime> = HashMap::new();
+    let mut unique = 0;
+    for (time, content) in telegrams {
+        if last_seen
+            .insert(content, *time)
+            .is_none_or(|last| *time - last > window)
+        {
+            unique += 1;
+        }
+    }
+    unique
+}
+
+/// Finds the gaps longer than `threshold` between the ordered reception `times`, including the
+/// ones at the start and end of the range
+pub fn reception_gaps(
+    times: impl IntoIterator<Item = NaiveDateTime>,
+    from: NaiveDateTime,
+    until: NaiveDateTime,
+    threshold: Duration,
+) -> Vec<ReceptionGap> {
+    let mut gaps = Vec::new();
+    let mut previous = from;
+    for time in times.into_iter().chain(std::iter::once(until)) {
+        if time - previous > threshold {
+            gaps.push(ReceptionGap {
+                from: previous,
+                until: time,
+            });
+        }
+        previous = previous.max(time);
+    }
+    gaps
+}
+
+/// Compares the telegrams per reporting point of `station` with the best other station in
+/// `counts`, which holds the telegrams per station and reporting point
+pub fn compare_reporting_points(
+    station: Uuid,
+    counts: &[(Uuid, i32, i64)],
+) -> Vec<ReportingPointComparison> {
+    let mut comparisons: HashMap<i32, ReportingPointComparison> = counts
+        .iter()
+        .filter(|(id, _, _)| *id == station)
+        .map(|(_, reporting_point, telegrams)| {
+            (
+                *reporting_point,
+                ReportingPointComparison {
+                    reporting_point: *reporting_point,
+                    telegrams: *telegrams,
+                    neighbour: None,
+                    neighbour_telegrams: 0,
+                },
+            )
+        })
+        .collect();
+
+    for (id, reporting_point, telegrams) in counts {
+        if *id == station {
+            continue;
+        }
+        if let Some(comparison) = comparisons.get_mut(reporting_point) {
+            if *telegrams > comparison.neighbour_telegrams {
+                comparison.neighbour = Some(*id);
+                comparison.neighbour_telegrams = *telegrams;
+            }
+        }
+    }
+
+    let mut comparisons: Vec<ReportingPointComparison> = comparisons.into_values().collect();
+    comparisons.sort_by_key(|comparison| comparison.reporting_point);
+    comparisons
+}
+
+/// Ratio of the two counts, `None` if the denominator is zero
+fn ratio(numerator: i64, denominator: i64) -> Option<f64> {
+    (denominator > 0).then(|| numerator as f64 / denominator as f64)
+}
+
+impl StationQuality {
+    /// Puts the quality together from the telegrams of the station, which have to be ordered by
+    /// time, the times of its raw telegrams and the telegrams per station and reporting point of
+    /// its region
+    pub fn new(
+        station: Uuid,
+        from: NaiveDateTime,
+        until: NaiveDateTime,
+        telegrams: &[(NaiveDateTime, TelegramContent)],
+        raw_times: &[NaiveDateTime],
+        region_counts: &[(Uuid, i32, i64)],
+        config: &StationQualityConfig,
+    ) -> StationQuality {
+        let mut times: Vec<NaiveDateTime> = telegrams
+            .iter()
+            .map(|(time, _)| *time)
+            .chain(raw_times.iter().copied())
+            .collect();
+        times.sort_unstable();
+
+        let total = telegrams.len() as i64;
+        let unique_telegrams = count_unique(telegrams, config.duplicate_window);
+        let raw_telegrams = raw_times.len() as i64;
+        let reporting_points = compare_reporting_points(station, region_counts);
+        let (own, best) = reporting_points
+            .iter()
+            .fold((0, 0), |(own, best), comparison| {
+                (
+                    own + comparison.telegrams,
+                    best + comparison.telegrams.max(comparison.neighbour_telegrams),
+                )
+            });
+
+        StationQuality {
+            station,
+            from,
+            until,
+            telegrams: total,
+            unique_telegrams,
+            unique_ratio: ratio(unique_telegrams, total),
+            raw_telegrams,
+            raw_share: ratio(raw_telegrams, total + raw_telegrams),
+            gaps: reception_gaps(times, from, until, config.gap_threshold),
+            reporting_points,
+            relative_reception: ratio(own, best),
+        }
+    }
+
+    /// Computes the quality of the station from `r09_telegrams` and `raw_telegrams`. All
+    /// telegrams of the station in the range are loaded, so the range should stay in the order
+    /// of days.
+    pub fn from_postgres(
+        database_connection: &mut PgConnection,
+        station: Uuid,
+        from: NaiveDateTime,
+        until: NaiveDateTime,
+        config: &StationQualityConfig,
+    ) -> QueryResult<StationQuality> {
+        let region: i64 = stations::table
+            .find(station)
+            .select(stations::region)
+            .first(database_connection)?;
+
+        let telegrams: Vec<(NaiveDateTime, TelegramContent)> = r09_telegrams::table
+            .filter(r09_telegrams::station.eq(station))
+            .filter(r09_telegrams::time.ge(from))
+            .filter(r09_telegrams::time.lt(until))
+            .order(r09_telegrams::time)
+            .select((
+                r09_telegrams::time,
+                (
+                    r09_telegrams::reporting_point,
+                    r09_telegrams::junction,
+                    r09_telegrams::direction,
+                    r09_telegrams::request_status,
+                    r09_telegrams::line,
+                    r09_telegrams::run_number,
+                    r09_telegrams::destination_number,
+                ),
+            ))
+            .load(database_connection)?;
+
+        let raw_times: Vec<NaiveDateTime> = raw_telegrams::table
+            .filter(raw_telegrams::station.eq(station))
+            .filter(raw_telegrams::time.ge(from))
+            .filter(raw_telegrams::time.lt(until))
+            .select(raw_telegrams::time)
+            .load(database_connection)?;
+
+        let mut own_reporting_points: Vec<i32> =
+            telegrams.iter().map(|(_, content)| content.0).collect();
+        own_reporting_points.sort_unstable();
+        own_reporting_points.dedup();
+        let region_counts: Vec<(Uuid, i32, i64)> = r09_telegrams::table
+            .filter(r09_telegrams::region.eq(region))
+            .filter(r09_telegrams::time.ge(from))
+            .filter(r09_telegrams::time.lt(until))
+            .filter(r09_telegrams::reporting_point.eq_any(own_reporting_points))
+            .group_by((r09_telegrams::station, r09_telegrams::reporting_point))
+            .select((
+                r09_telegrams::station,
+                r09_telegrams::reporting_point,
+                count_star(),
+            ))
+            .load(database_connection)?;
+
+        Ok(StationQuality::new(
+            station,
+            from,
+            until,
+            &telegrams,
+            &raw_times,
+            &region_counts,
+            config,
+        ))
+    }
+}
@@ -0,0 +1,318 @@
+use super::compute::{counts, total_after, total_since, Counts, DAY, MONTH, SETTLE_LAG, WEEK};
+use chrono::{Duration, NaiveDate, NaiveDateTime};
+
+#[test]
+fn test_incremental_counts() {
+    let now = NaiveDate::from_ymd_opt(2023, 5, 1)
+        .unwrap()
+        .and_hms_opt(12, 0, 0)
+        .unwrap();
+    // one record every hour for the last 60 days
+    let records: Vec<NaiveDateTime> = (0..60 * 24).map(|h| now - Duration::hours(h)).collect();
+    let mut queries = Vec::new();
+    let mut count = |from: Option<NaiveDateTime>, until: NaiveDateTime| {
+        queries.push(from);
+        Ok(records
+            .iter()
+            .filter(|time| from.is_none_or(|from| **time > from) && **time <= until)
+            .count() as i64)
+    };
+
+    let total = total_since(None, now, Duration::zero(), &mut count).unwrap();
+    let full = counts(total, now, |from| count(Some(from), now)).unwrap();
+    assert_eq!(
+        full,
+        Counts {
+            total: 60 * 24,
+            month: 30 * 24,
+            week: 7 * 24,
+            day: 24,
+        }
+    );
+
+    // continued from an update two hours ago, which counted 1000 records
+    let incremental = total_since(
+        Some((now - Duration::hours(2), 1000)),
+        now,
+        Duration::zero(),
+        &mut count,
+    )
+    .unwrap();
+    assert_eq!(incremental, 1002);
+
+    // a last update in the future forces a full count
+    let future = total_since(Some((now + DAY, 1000)), now, Duration::zero(), &mut count).unwrap();
+    assert_eq!(future, full.total);
+
+    assert_eq!(
+        queries,
+        vec![
+            None,
+            Some(now - MONTH),
+            Some(now - WEEK),
+            Some(now - DAY),
+            Some(now - Duration::hours(2)),
+            None,
+        ]
+    );
+}
+
+#[test]
+fn test_late_telegrams_counted() {
+    let now = NaiveDate::from_ymd_opt(2023, 5, 1)
+        .unwrap()
+        .and_hms_opt(12, 0, 0)
+        .unwrap();
+    let earlier = now - Duration::hours(1);
+    // one telegram every minute of the last two hours
+    let mut records: Vec<NaiveDateTime> = (0..120).map(|m| now - Duration::minutes(m)).collect();
+    let count = |records: &[NaiveDateTime], from: Option<NaiveDateTime>, until: NaiveDateTime| {
+        Ok(records
+            .iter()
+            .filter(|time| from.is_none_or(|from| **time > from) && **time <= until)
+            .count() as i64)
+    };
+
+    // the earlier update only saw telegrams up to itself and counts those before the lag
+    let committed: Vec<NaiveDateTime> = records.iter().copied().filter(|t| *t <= earlier).collect();
+    let first = total_since(None, earlier, SETTLE_LAG, |from, until| {
+        count(&committed, from, until)
+    })
+    .unwrap();
+    assert_eq!(first, 60 - SETTLE_LAG.num_minutes());
+
+    // a station commits a telegram from just before the earlier update late
+    records.push(earlier - Duration::seconds(30));
+    let second = total_since(Some((earlier, first)), now, SETTLE_LAG, |from, until| {
+        count(&records, from, until)
+    })
+    .unwrap();
+    assert_eq!(second, count(&records, None, now - SETTLE_LAG).unwrap());
+}
+
+#[test]
+fn test_incremental_counts_by_id() {
+    let now = NaiveDate::from_ymd_opt(2023, 5, 1)
+        .unwrap()
+        .and_hms_opt(12, 0, 0)
+        .unwrap();
+    // gps points as id and timestamp, one every minute of the last hour
+    let mut points: Vec<(i64, NaiveDateTime)> = (0..60)
+        .map(|i| (i + 1, now - Duration::minutes(60 - i)))
+        .collect();
+    let count = |points: &[(i64, NaiveDateTime)], after: Option<i64>| {
+        let counted: Vec<i64> = points
+            .iter()
+            .map(|(id, _)| *id)
+            .filter(|id| after.is_none_or(|after| *id > after))
+            .collect();
+        Ok((counted.len() as i64, counted.into_iter().max()))
+    };
+
+    let (total, last_id) = total_after(None, |after| count(&points, after)).unwrap();
+    assert_eq!((total, last_id), (60, Some(60)));
+
+    // nothing new keeps the last id
+    let unchanged = total_after(Some((60, total)), |after| count(&points, after)).unwrap();
+    assert_eq!(unchanged, (60, Some(60)));
+
+    // a trekkie run uploaded after the update, recorded a day before it
+    points.extend((0..30).map(|i| (61 + i, now - DAY - Duration::minutes(i))));
+    let (total, last_id) = total_after(Some((60, total)), |after| count(&points, after)).unwrap();
+    assert_eq!((total, last_id), (90, Some(90)));
+
+    // the windows are still counted by time
+    let windows = counts(total, now, |from| {
+        Ok(points.iter().filter(|(_, time)| *time > from).count() as i64)
+    })
+    .unwrap();
+    assert_eq!((windows.total, windows.week, windows.day), (90, 90, 60));
+}
+
+#[test]
+fn test_timeseries_buckets() {
+    use super::timeseries::{
+        rollup_start, Bucket, Subject, TimeSeries, TimeSeriesQuery, DEFAULT_ROLLUP_LOOKBACK,
+    };
+
+    let at = |day: u32, hour: u32, minute: u32| {
+        NaiveDate::from_ymd_opt(2023, 5, day)
+            .unwrap()
+            .and_hms_opt(hour, minute, 0)
+            .unwrap()
+    };
+    let query = TimeSeriesQuery {
+        subject: Subject::Station {
+            station: uuid::Uuid::nil(),
+        },
+        bucket: Bucket::Hour,
+        from: at(1, 8, 30),
+        until: at(1, 12, 15),
+    };
+    assert_eq!(query.start(), at(1, 8, 0));
+    assert_eq!(query.buckets(), 5);
+
+    let series = query.fill([(at(1, 9, 0), 3), (at(1, 12, 0), 7)]);
+    let counts: Vec<i64> = series.points.iter().map(|p| p.count).collect();
+    assert_eq!(counts, vec![0, 3, 0, 0, 7]);
+    assert_eq!(series.points[4].start, at(1, 12, 0));
+    assert_eq!(Bucket::Day.truncate(at(3, 23, 59)), at(3, 0, 0));
+    assert_eq!(series.horizon, None);
+
+    // late telegrams within the lookback are counted again, even if later minutes were rolled up
+    let now = at(1, 12, 15) + Duration::seconds(30);
+    assert_eq!(
+        rollup_start(at(1, 12, 14), now, DEFAULT_ROLLUP_LOOKBACK),
+        at(1, 12, 5)
+    );
+    assert_eq!(
+        rollup_start(at(1, 11, 0), now, DEFAULT_ROLLUP_LOOKBACK),
+        at(1, 11, 0)
+    );
+
+    let mut rolled_up = series.clone();
+    rolled_up.horizon = Some(at(1, 12, 15));
+    let json = serde_json::to_value(&rolled_up).unwrap();
+    assert_eq!(json["horizon"], "2023-05-01T12:15:00Z");
+    assert_eq!(
+        serde_json::from_value::<TimeSeries>(json).unwrap(),
+        rolled_up
+    );
+    assert!(serde_json::to_value(&series).unwrap()["horizon"].is_null());
+
+    let json = serde_json::to_value(query).unwrap();
+    assert_eq!(json["subject"]["kind"], "station");
+    assert_eq!(json["bucket"], "hour");
+    assert_eq!(
+        serde_json::from_value::<TimeSeriesQuery>(json).unwrap(),
+        query
+    );
+}
+
+#[test]
+fn test_delay_report() {
+    use super::delay::{DelayAnalysis, DelayDimension, OnTimeWindow, DEFAULT_TIME_ZONE};
+    use super::quality::DEFAULT_DUPLICATE_WINDOW;
+    use diesel::debug_query;
+    use diesel::pg::Pg;
+
+    // monday 8:00 and 9:00
+    let eight = NaiveDate::from_ymd_opt(2023, 5, 1)
+        .unwrap()
+        .and_hms_opt(8, 0, 0)
+        .unwrap();
+    let nine = eight + Duration::hours(1);
+
+    let mut analysis = DelayAnalysis::new();
+    analysis.add(8, 0, Some(3), 100, 0, 6);
+    analysis.add(8, 0, Some(3), 101, 2, 2);
+    analysis.add(9, 0, Some(3), 101, 5, 2);
+    analysis.add(9, 0, None, 100, -2, 1);
+    let report = analysis.finish(0, eight, nine, OnTimeWindow::default());
+
+    let overall = report.overall.unwrap();
+    assert_eq!(overall.samples, 11);
+    assert_eq!(overall.median, 0);
+    assert_eq!(overall.p90, 5);
+    assert!((overall.mean - 12_f64 / 11_f64).abs() < 1e-9);
+    assert!((overall.on_time - 8_f64 / 11_f64).abs() < 1e-9);
+
+    let lines: Vec<_> = report.rows(DelayDimension::Line).collect();
+    assert_eq!(lines.len(), 1);
+    assert_eq!(lines[0].samples, 10);
+    let hours: Vec<(i32, u64)> = report
+        .rows(DelayDimension::HourOfDay)
+        .map(|row| (row.key, row.samples))
+        .collect();
+    assert_eq!(hours, vec![(8, 8), (9, 3)]);
+    assert_eq!(report.rows(DelayDimension::Weekday).next().unwrap().key, 0);
+
+    let mut csv = Vec::new();
+    report.write_csv(&mut csv).unwrap();
+    let csv = String::from_utf8(csv).unwrap();
+    let mut lines = csv.lines();
+    assert_eq!(
+        lines.next(),
+        Some("dimension,key,samples,mean,median,p90,on_time")
+    );
+    assert_eq!(lines.next(), Some("line,3,10,1.4,0,5,0.8"));
+    assert_eq!(lines.count(), report.rows.len() - 1);
+
+    let mut json = Vec::new();
+    report.write_json(&mut json).unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+    assert_eq!(json["rows"][0]["dimension"], "line");
+
+    // repetitions are dropped before grouping by hour of day and weekday
+    let query = DelayAnalysis::query(0, eight, nine, DEFAULT_DUPLICATE_WINDOW, DEFAULT_TIME_ZONE);
+    let sql = debug_query::<Pg, _>(&query).to_string();
+    assert!(sql.contains("time AT TIME ZONE 'UTC' AT TIME ZONE $5 AS local"));
+    assert!(sql.contains("extract(hour FROM local)::INT AS hour"));
+    assert!(sql.contains("(extract(isodow FROM local) - 1)::INT AS weekday"));
+    assert!(sql.contains("\"Europe/Berlin\""));
+    assert!(sql.contains(
+        "PARTITION BY line, run_number, reporting_point, request_status, delay, \
+         CASE WHEN line IS NULL OR run_number IS NULL THEN id END"
+    ));
+    assert!(sql.contains("WHERE since IS NULL OR since > $4"));
+    assert!(!sql.contains("date_trunc"));
+}
+
+#[test]
+fn test_station_quality() {
+    use super::quality::{StationQuality, StationQualityConfig};
+    use uuid::Uuid;
+
+    let from = NaiveDate::from_ymd_opt(2023, 5, 1)
+        .unwrap()
+        .and_hms_opt(0, 0, 0)
+        .unwrap();
+    let until = from + Duration::hours(4);
+    let station = Uuid::from_u128(1);
+    let neighbour = Uuid::from_u128(2);
+    let content = |reporting_point| (reporting_point, 0, 1, 0, Some(3), Some(7), Some(42));
+
+    // repeated after 5 seconds, then again 2 minutes later, then a gap of more than 3 hours
+    let telegrams = vec![
+        (from + Duration::minutes(10), content(100)),
+        (
+            from + Duration::minutes(10) + Duration::seconds(5),
+            content(100),
+        ),
+        (
+            from + Duration::minutes(10) + Duration::seconds(6),
+            content(101),
+        ),
+        (from + Duration::minutes(12), content(100)),
+    ];
+    let raw_times = vec![from + Duration::minutes(11)];
+    let region_counts = vec![
+        (station, 100, 3),
+        (station, 101, 1),
+        (neighbour, 100, 2),
+        (neighbour, 101, 4),
+        (neighbour, 102, 9),
+    ];
+
+    let quality = StationQuality::new(
+        station,
+        from,
+        until,
+        &telegrams,
+        &raw_times,
+        &region_counts,
+        &StationQualityConfig::default(),
+    );
+    assert_eq!(quality.telegrams, 4);
+    assert_eq!(quality.unique_telegrams, 3);
+    assert_eq!(quality.raw_share, Some(0.2));
+    assert_eq!(quality.gaps.len(), 1);
+    assert_eq!(quality.gaps[0].from, from + Duration::minutes(12));
+    assert_eq!(quality.gaps[0].until, until);
+
+    assert_eq!(quality.reporting_points.len(), 2);
+    assert_eq!(quality.reporting_points[0].neighbour, Some(neighbour));
+    assert_eq!(quality.reporting_points[0].neighbour_telegrams, 2);
+    assert_eq!(quality.reporting_points[1].neighbour_telegrams, 4);
+    assert_eq!(quality.relative_reception, Some(4_f64 / 7_f64));
+}
@@ -0,0 +1,326 @@
+//! This module answers questions like "telegrams per hour for station X last week" with
+//! [`TimeSeries`] of bucketed telegram counts for a region, station, line or reporting point.
+//!
+//! Counts are either taken directly from `r09_telegrams` or from the optional
+//! `r09_telegram_rollups` table, which holds telegrams per minute and is kept up to date by
+//! calling [`update_rollups`] periodically. Rollups are much cheaper to query over long ranges,
+//! but only contain complete minutes up to the last update, which is reported as
+//! [`TimeSeries::horizon`].
+
+use crate::schema::r09_telegram_rollups;
+
+use chrono::{Duration, DurationRound, NaiveDateTime};
+use diesel::dsl::{max, min};
+use diesel::pg::Pg;
+use diesel::sql_types::{BigInt, Integer, Timestamp};
+use diesel::{Connection, PgConnection, QueryDsl, QueryableByName, RunQueryDsl};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+/// Maximum number of buckets of a single [`TimeSeries`]
+pub const MAX_BUCKETS: i64 = 10_000;
+/// Line stored in `r09_telegram_rollups` for telegrams without line
+pub const UNKNOWN_LINE: i32 = -1;
+/// Range of telegrams rolled up in one transaction by [`update_rollups`]
+pub const ROLLUP_CHUNK: Duration = Duration::days(1);
+/// Default range before the last update counted again by [`update_rollups`] (10 minutes)
+pub const DEFAULT_ROLLUP_LOOKBACK: Duration = Duration::minutes(10);
+
+/// Width of a bucket
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Bucket {
+    /// one minute
+    Minute,
+    /// one hour
+    Hour,
+    /// one day, starting at midnight UTC
+    Day,
+}
+
+impl Bucket {
+    /// Length of the bucket
+    pub fn duration(&self) -> Duration {
+        match self {
+            Bucket::Minute => Duration::minutes(1),
+            Bucket::Hour => Duration::hours(1),
+            Bucket::Day => Duration::days(1),
+        }
+    }
+
+    /// Start of the bucket containing `time`
+    pub fn truncate(&self, time: NaiveDateTime) -> NaiveDateTime {
+        time.duration_trunc(self.duration()).unwrap_or(time)
+    }
+
+    /// Field name for postgres `date_trunc`
+    fn field(&self) -> &'static str {
+        match self {
+            Bucket::Minute => "minute",
+            Bucket::Hour => "hour",
+            Bucket::Day => "day",
+        }
+    }
+}
+
+/// What the telegrams are counted for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Subject {
+    /// all telegrams of a region
+    Region {
+        /// region identifier
+        region: i64,
+    },
+    /// all telegrams received by a station
+    Station {
+        /// station identifier
+        station: Uuid,
+    },
+    /// all telegrams of a line in a region
+    Line {
+        /// region identifier
+        region: i64,
+        /// line (ger. linie)
+        line: i32,
+    },
+    /// all telegrams of a reporting point in a region
+    ReportingPoint {
+        /// region identifier
+        region: i64,
+        /// reporting point (ger. meldepunkt)
+        reporting_point: i32,
+    },
+}
+
+/// Where the telegrams are counted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    /// `r09_telegrams`, exact but expensive over long ranges
+    Telegrams,
+    /// `r09_telegram_rollups`, only complete minutes up to the last [`update_rollups`]
+    Rollups,
+}
+
+/// Request for a [`TimeSeries`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct TimeSeriesQuery {
+    /// what to count
+    pub subject: Subject,
+    /// width of the buckets
+    pub bucket: Bucket,
+    /// start of the range, rounded down to the start of its bucket
+    #[serde(with = "crate::time_serializer")]
+    pub from: NaiveDateTime,
+    /// end of the range, exclusive
+    #[serde(with = "crate::time_serializer")]
+    pub until: NaiveDateTime,
+}
+
+/// Number of telegrams in one bucket
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct TimeSeriesPoint {
+    /// start of the bucket
+    #[serde(with = "crate::time_serializer")]
+    pub start: NaiveDateTime,
+    /// number of telegrams
+    pub count: i64,
+}
+
+/// Telegram counts for every bucket of the requested range, including empty ones
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct TimeSeries {
+    /// the request
+    pub query: TimeSeriesQuery,
+    /// one point per bucket, ordered by time
+    pub points: Vec<TimeSeriesPoint>,
+    /// end of the last rolled up minute when loaded from [`Source::Rollups`], later telegrams
+    /// are missing from the counts. `None` for [`Source::Telegrams`] or without rollups.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "crate::time_serializer::option"
+    )]
+    pub horizon: Option<NaiveDateTime>,
+}
+
+/// Error returned by [`TimeSeriesQuery::load`]
+#[derive(Debug)]
+pub enum TimeSeriesError {
+    /// the range is empty or reversed
+    InvalidRange,
+    /// the range spans more than [`MAX_BUCKETS`] buckets
+    TooManyBuckets(i64),
+    /// See [`diesel::result::Error`]
+    DieselError(diesel::result::Error),
+}
+
+impl From<diesel::result::Error> for TimeSeriesError {
+    fn from(e: diesel::result::Error) -> TimeSeriesError {
+        TimeSeriesError::DieselError(e)
+    }
+}
+
+/// Row returned by the bucket queries
+#[derive(QueryableByName)]
+struct BucketRow {
+    #[diesel(sql_type = Timestamp)]
+    start: NaiveDateTime,
+    #[diesel(sql_type = BigInt)]
+    count: i64,
+}
+
+impl TimeSeriesQuery {
+    /// Start of the first bucket
+    pub fn start(&self) -> NaiveDateTime {
+        self.bucket.truncate(self.from)
+    }
+
+    /// Number of buckets in the range
+    pub fn buckets(&self) -> i64 {
+        let width = self.bucket.duration().num_seconds();
+        let seconds = (self.until - self.start()).num_seconds();
+        (seconds + width - 1).div_euclid(width)
+    }
+
+    /// Counts the telegrams in every bucket
+    pub fn load(
+        &self,
+        database_connection: &mut PgConnection,
+        source: Source,
+    ) -> Result<TimeSeries, TimeSeriesError> {
+        if self.until <= self.from {
+            return Err(TimeSeriesError::InvalidRange);
+        }
+        let buckets = self.buckets();
+        if buckets > MAX_BUCKETS {
+            return Err(TimeSeriesError::TooManyBuckets(buckets));
+        }
+
+        let (table, time, count) = match source {
+            Source::Telegrams => ("r09_telegrams", "time", "count(*)"),
+            Source::Rollups => ("r09_telegram_rollups", "bucket", "sum(telegrams)::BIGINT"),
+        };
+        let filter = match self.subject {
+            Subject::Region { .. } => "region = $3",
+            Subject::Station { .. } => "station = $3",
+            Subject::Line { .. } => "region = $3 AND line = $4",
+            Subject::ReportingPoint { .. } => "region = $3 AND reporting_point = $4",
+        };
+        let sql = format!(
+            "SELECT date_trunc('{field}', {time}) AS start, {count} AS count FROM {table} \
+             WHERE {time} >= $1 AND {time} < $2 AND {filter} GROUP BY 1 ORDER BY 1",
+            field = self.bucket.field(),
+        );
+
+        let query = diesel::sql_query(sql)
+            .into_boxed::<Pg>()
+            .bind::<Timestamp, _>(self.start())
+            .bind::<Timestamp, _>(self.until);
+        let query = match self.subject {
+            Subject::Region { region } => query.bind::<BigInt, _>(region),
+            Subject::Station { station } => query.bind::<diesel::sql_types::Uuid, _>(station),
+            Subject::Line { region, line } => {
+                query.bind::<BigInt, _>(region).bind::<Integer, _>(line)
+            }
+            Subject::ReportingPoint {
+                region,
+                reporting_point,
+            } => query
+                .bind::<BigInt, _>(region)
+                .bind::<Integer, _>(reporting_point),
+        };
+        let rows: Vec<BucketRow> = query.load(database_connection)?;
+
+        let mut series = self.fill(rows.into_iter().map(|row| (row.start, row.count)));
+        if source == Source::Rollups {
+            series.horizon = r09_telegram_rollups::table
+                .select(max(r09_telegram_rollups::bucket))
+                .first::<Option<NaiveDateTime>>(database_connection)?
+                .map(|last| last + Bucket::Minute.duration());
+        }
+        Ok(series)
+    }
+
+    /// Builds the series from the non-empty buckets, without horizon
+    pub fn fill(&self, counts: impl IntoIterator<Item = (NaiveDateTime, i64)>) -> TimeSeries {
+        let counts: HashMap<NaiveDateTime, i64> = counts.into_iter().collect();
+        let points = (0..self.buckets().max(0))
+            .map(|i| {
+                let start = self.start() + self.bucket.duration() * i as i32;
+                TimeSeriesPoint {
+                    start,
+                    count: counts.get(&start).copied().unwrap_or(0),
+                }
+            })
+            .collect();
+
+        TimeSeries {
+            query: *self,
+            points,
+            horizon: None,
+        }
+    }
+}
+
+/// First minute counted again by [`update_rollups`]: the `last` rolled up minute or the start of
+/// the `lookback` before `until`, whichever is earlier
+pub fn rollup_start(
+    last: NaiveDateTime,
+    until: NaiveDateTime,
+    lookback: Duration,
+) -> NaiveDateTime {
+    last.min(Bucket::Minute.truncate(until - lookback))
+}
+
+/// Rolls up all complete minutes of `r09_telegrams` up to `now` that were not rolled up yet, in
+/// transactions of [`ROLLUP_CHUNK`]. The `lookback` before `now` and the last rolled up minute
+/// are counted again, as telegrams for them may have arrived late, e.g. from stations with a
+/// delayed upload. Returns the number of upserted rows.
+pub fn update_rollups(
+    database_connection: &mut PgConnection,
+    now: NaiveDateTime,
+    lookback: Duration,
+) -> diesel::QueryResult<usize> {
+    use crate::schema::r09_telegrams;
+
+    let until = Bucket::Minute.truncate(now);
+    let last: Option<NaiveDateTime> = r09_telegram_rollups::table
+        .select(max(r09_telegram_rollups::bucket))
+        .first(database_connection)?;
+    let first = match last {
+        Some(last) => Some(rollup_start(last, until, lookback)),
+        None => r09_telegrams::table
+            .select(min(r09_telegrams::time))
+            .first::<Option<NaiveDateTime>>(database_connection)?
+            .map(|time| Bucket::Minute.truncate(time)),
+    };
+    let Some(mut from) = first else {
+        return Ok(0);
+    };
+
+    let mut upserted = 0;
+    while from < until {
+        let chunk_until = (from + ROLLUP_CHUNK).min(until);
+        upserted += database_connection.transaction(|connection| {
+            diesel::sql_query(
+                "INSERT INTO r09_telegram_rollups \
+                 (bucket, region, station, line, reporting_point, telegrams) \
+                 SELECT date_trunc('minute', time), region, station, COALESCE(line, $3), \
+                 reporting_point, count(*) FROM r09_telegrams \
+                 WHERE time >= $1 AND time < $2 GROUP BY 1, 2, 3, 4, 5 \
+                 ON CONFLICT (bucket, region, station, line, reporting_point) \
+                 DO UPDATE SET telegrams = EXCLUDED.telegrams",
+            )
+            .bind::<Timestamp, _>(from)
+            .bind::<Timestamp, _>(chunk_until)
+            .bind::<Integer, _>(UNKNOWN_LINE)
+            .execute(connection)
+        })?;
+        from = chunk_until;
+    }
+
+    Ok(upserted)
+}
@@ -0,0 +1,422 @@
+//! This module interpolates vehicle positions between two R09 reporting points. R09 telegrams
+//! only give us a position when a vehicle passes a reporting point, so we build a [`PathModel`]
+//! per region, line and direction from the GPS tracks of correlated trekkie runs. Reporting
+//! points from `r09_transmission_locations` are placed along that track, and the position of a
+//! vehicle is estimated from the time elapsed since it passed the last reporting point and the
+//! typical travel time to the next one.
+
+use crate::locations::gps::GpsPoint;
+use crate::locations::projection::{Coordinate, Projectable};
+use crate::locations::waypoint::{WayPointType, Waypoint};
+use crate::locations::{TransmissionLocation, SANE_INTERPOLATION_DISTANCE};
+use crate::schema::*;
+use crate::trekkie::TrekkieRun;
+
+use chrono::{Duration, NaiveDateTime};
+use diesel::{ExpressionMethods, PgConnection, QueryDsl, RunQueryDsl};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+/// Identifies a path of a line. The direction of travel is identified by the destination number
+/// of the R09 telegrams, because the same line runs on different tracks in each direction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct PathKey {
+    /// region identifier
+    pub region: i64,
+    /// line (ger. linie) of the vehicle
+    pub line: i32,
+    /// destination number of the R09 telegrams sent by vehicles going this way
+    pub destination_number: i32,
+}
+
+/// Single point of the track polyline
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct TrackPoint {
+    /// latitude
+    pub lat: f64,
+    /// longitude
+    pub lon: f64,
+    /// distance in meters from the start of the track
+    pub distance: f64,
+}
+
+/// Reporting point placed on the track of a [`PathModel`]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct PathReportingPoint {
+    /// Reporting Point inside the r09 telegram (*meldepunkt*) ID
+    pub reporting_point: i32,
+    /// distance in meters from the start of the track
+    pub distance: f64,
+    /// typical travel time in milliseconds to the next reporting point on the path, if there is
+    /// any and it was observed in at least one track
+    pub travel_time: Option<u64>,
+}
+
+/// Model of the real track a line takes in one direction, with the reporting points on it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PathModel {
+    /// path identifier
+    pub key: PathKey,
+    /// polyline of the track
+    pub track: Vec<TrackPoint>,
+    /// reporting points on the track, ordered by distance from the start of the track
+    pub reporting_points: Vec<PathReportingPoint>,
+}
+
+/// Result of projecting a point onto a polyline
+struct Projection {
+    /// distance along the polyline in meters
+    along: f64,
+    /// distance from the polyline in meters
+    offset: f64,
+    /// index of the segment the point was projected onto
+    segment: usize,
+    /// position of the projection within the segment, from 0 to 1
+    fraction: f64,
+}
+
+/// Projects `(lat, lon)` onto the polyline, returning the closest projection
+fn project(track: &[TrackPoint], lat: f64, lon: f64) -> Option<Projection> {
+    let origin = Coordinate::new(track.first()?.lat, track.first()?.lon);
+    let (px, py) = Coordinate::new(lat, lon).to_local(&origin);
+
+    let mut best: Option<Projection> = None;
+    for (segment, pair) in track.windows(2).enumerate() {
+        let (ax, ay) = Coordinate::new(pair[0].lat, pair[0].lon).to_local(&origin);
+        let (bx, by) = Coordinate::new(pair[1].lat, pair[1].lon).to_local(&origin);
+        let (dx, dy) = (bx - ax, by - ay);
+        let length_sq = dx * dx + dy * dy;
+
+        let fraction = if length_sq > 0_f64 {
+            (((px - ax) * dx + (py - ay) * dy) / length_sq).clamp(0_f64, 1_f64)
+        } else {
+            0_f64
+        };
+        let (cx, cy) = (ax + fraction * dx, ay + fraction * dy);
+        let offset = ((px - cx).powi(2) + (py - cy).powi(2)).sqrt();
+
+        if best.as_ref().is_none_or(|b| offset < b.offset) {
+            best = Some(Projection {
+                along: pair[0].distance + fraction * (pair[1].distance - pair[0].distance),
+                offset,
+                segment,
+                fraction,
+            });
+        }
+    }
+
+    best
+}
+
+/// Builds the polyline with cumulative distances from a GPS track
+fn build_track(points: &[GpsPoint]) -> Vec<TrackPoint> {
+    let Some(first) = points.first() else {
+        return Vec::new();
+    };
+    let origin = first.coordinate();
+
+    let mut track: Vec<TrackPoint> = Vec::with_capacity(points.len());
+    for point in points {
+        let distance = match track.last() {
+            Some(last) => {
+                let (ax, ay) = Coordinate::new(last.lat, last.lon).to_local(&origin);
+                let (bx, by) = point.coordinate().to_local(&origin);
+                last.distance + ((bx - ax).powi(2) + (by - ay).powi(2)).sqrt()
+            }
+            None => 0_f64,
+        };
+        track.push(TrackPoint {
+            lat: point.lat,
+            lon: point.lon,
+            distance,
+        });
+    }
+
+    track
+}
+
+/// Time at which the GPS track passed closest to the given position, if it
+/// passed within [`SANE_INTERPOLATION_DISTANCE`].
+fn passing_time(
+    points: &[GpsPoint],
+    track: &[TrackPoint],
+    lat: f64,
+    lon: f64,
+) -> Option<NaiveDateTime> {
+    let projection = project(track, lat, lon)?;
+    if projection.offset > SANE_INTERPOLATION_DISTANCE {
+        return None;
+    }
+
+    let start = points[projection.segment].timestamp;
+    let end = points[projection.segment + 1].timestamp;
+    let offset = ((end - start).num_milliseconds() as f64 * projection.fraction) as i64;
+    Some(start + Duration::milliseconds(offset))
+}
+
+fn median(values: &mut [i64]) -> Option<i64> {
+    if values.is_empty() {
+        return None;
+    }
+    values.sort_unstable();
+    Some(values[values.len() / 2])
+}
+
+impl PathModel {
+    /// Builds the path model from the GPS tracks of trekkie runs on this path and the
+    /// transmission locations of the region. The longest track is used as geometry, every track
+    /// contributes to the typical travel times. Only the `reported` reporting points, the ones
+    /// vehicles on this path send telegrams for, are placed on the path. This keeps out reporting
+    /// points of the opposite direction and of other lines sharing the track. Transmission
+    /// locations that are further than [`SANE_INTERPOLATION_DISTANCE`] away from the track are
+    /// not part of the path either. Returns [`None`] if no usable track was provided.
+    pub fn from_tracks(
+        key: PathKey,
+        tracks: &[Vec<GpsPoint>],
+        locations: &[TransmissionLocation],
+        reported: &HashSet<i32>,
+    ) -> Option<PathModel> {
+        let mut tracks: Vec<Vec<GpsPoint>> = tracks
+            .iter()
+            .filter(|track| track.len() >= 2)
+            .map(|track| {
+                let mut sorted = track.clone();
+                sorted.sort_by_key(|point| point.timestamp);
+                sorted
+            })
+            .collect();
+        tracks.sort_by_key(|track| std::cmp::Reverse(track.len()));
+
+        let reference = build_track(tracks.first()?);
+        let locations: Vec<&TransmissionLocation> = locations
+            .iter()
+            .filter(|location| location.region == key.region)
+            .filter(|location| reported.contains(&location.reporting_point))
+            .collect();
+
+        let mut reporting_points: Vec<PathReportingPoint> = locations
+            .iter()
+            .filter_map(|location| {
+                let projection = project(&reference, location.lat, location.lon)?;
+                (projection.offset <= SANE_INTERPOLATION_DISTANCE).then_some(PathReportingPoint {
+                    reporting_point: location.reporting_point,
+                    distance: projection.along,
+                    travel_time: None,
+                })
+            })
+            .collect();
+        reporting_points.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+
+        // collect the time every track needed between two consecutive reporting points
+        let positions: HashMap<i32, (f64, f64)> = locations
+            .iter()
+            .map(|location| (location.reporting_point, (location.lat, location.lon)))
+            .collect();
+        let mut travel_times: Vec<Vec<i64>> = vec![Vec::new(); reporting_points.len()];
+        for points in &tracks {
+            let track = build_track(points);
+            let passed: Vec<Option<NaiveDateTime>> = reporting_points
+                .iter()
+                .map(|rp| {
+                    let (lat, lon) = positions[&rp.reporting_point];
+                    passing_time(points, &track, lat, lon)
+                })
+                .collect();
+
+            for (i, pair) in passed.windows(2).enumerate() {
+                if let (Some(from), Some(to)) = (pair[0], pair[1]) {
+                    if to > from {
+                        travel_times[i].push((to - from).num_milliseconds());
+                    }
+                }
+            }
+        }
+
+        for (rp, times) in reporting_points.iter_mut().zip(travel_times.iter_mut()) {
+            rp.travel_time = median(times).map(|t| t as u64);
+        }
+
+        Some(PathModel {
+            key,
+            track: reference,
+            reporting_points,
+        })
+    }
+
+    /// Loads correlated trekkie runs of the line from the database and builds the path model from
+    /// them. A run belongs to the path if the majority of its telegrams carry the destination
+    /// number of the path. The reporting points of these telegrams make up the path.
+    pub fn from_postgres(
+        key: PathKey,
+        database_connection: &mut PgConnection,
+    ) -> Result<Option<PathModel>, diesel::result::Error> {
+        let runs = trekkie_runs::table
+            .filter(trekkie_runs::region.eq(key.region))
+            .filter(trekkie_runs::line.eq(key.line))
+            .filter(trekkie_runs::correlated.eq(true))
+            .load::<TrekkieRun>(database_connection)?;
+
+        let mut tracks: Vec<Vec<GpsPoint>> = Vec::new();
+        let mut reported: HashSet<i32> = HashSet::new();
+        for run in runs {
+            let telegrams = r09_telegrams::table
+                .filter(r09_telegrams::region.eq(run.region))
+                .filter(r09_telegrams::line.eq(run.line))
+                .filter(r09_telegrams::run_number.eq(run.run))
+                .filter(r09_telegrams::time.between(run.start_time, run.end_time))
+                .select((
+                    r09_telegrams::destination_number,
+                    r09_telegrams::reporting_point,
+                ))
+                .load::<(Option<i32>, i32)>(database_connection)?;
+
+            let matching: Vec<i32> = telegrams
+                .iter()
+                .filter(|(destination, _)| *destination == Some(key.destination_number))
+                .map(|(_, reporting_point)| *reporting_point)
+                .collect();
+            if telegrams.is_empty() || matching.len() * 2 <= telegrams.len() {
+                continue;
+            }
+            reported.extend(matching);
+
+            tracks.push(
+                gps_points::table
+                    .filter(gps_points::trekkie_run.eq(run.id))
+                    .order(gps_points::timestamp.asc())
+                    .load::<GpsPoint>(database_connection)?,
+            );
+        }
+
+        let locations = r09_transmission_locations::table
+            .filter(r09_transmission_locations::region.eq(key.region))
+            .load::<TransmissionLocation>(database_connection)?;
+
+        Ok(Self::from_tracks(key, &tracks, &locations, &reported))
+    }
+
+    /// Returns the reporting point that follows `reporting_point` on this path
+    pub fn next_reporting_point(&self, reporting_point: i32) -> Option<&PathReportingPoint> {
+        let index = self
+            .reporting_points
+            .iter()
+            .position(|rp| rp.reporting_point == reporting_point)?;
+        self.reporting_points.get(index + 1)
+    }
+
+    /// Returns the (latitude, longitude) at the given distance along the track
+    pub fn position_at(&self, distance: f64) -> Option<(f64, f64)> {
+        let first = self.track.first()?;
+        if distance <= first.distance {
+            return Some((first.lat, first.lon));
+        }
+
+        for pair in self.track.windows(2) {
+            if distance <= pair[1].distance {
+                let length = pair[1].distance - pair[0].distance;
+                let fraction = if length > 0_f64 {
+                    (distance - pair[0].distance) / length
+                } else {
+                    0_f64
+                };
+                return Some((
+                    pair[0].lat + fraction * (pair[1].lat - pair[0].lat),
+                    pair[0].lon + fraction * (pair[1].lon - pair[0].lon),
+                ));
+            }
+        }
+
+        self.track.last().map(|last| (last.lat, last.lon))
+    }
+
+    /// Interpolates the position of a vehicle that passed `reporting_point` `elapsed`
+    /// milliseconds ago and is heading to the next reporting point on the path. The vehicle is
+    /// never placed beyond the next reporting point.
+    pub fn interpolate(&self, reporting_point: i32, elapsed: u64) -> Option<(f64, f64)> {
+        let index = self
+            .reporting_points
+            .iter()
+            .position(|rp| rp.reporting_point == reporting_point)?;
+        let from = self.reporting_points[index];
+        let to = self.reporting_points.get(index + 1)?;
+        let travel_time = from.travel_time?;
+
+        let fraction = if travel_time > 0 {
+            (elapsed as f64 / travel_time as f64).min(1_f64)
+        } else {
+            1_f64
+        };
+
+        self.position_at(from.distance + fraction * (to.distance - from.distance))
+    }
+
+    /// Creates a synthetic [`Waypoint`] with source [`WayPointType::Interpolated`] at time `now`
+    /// for a vehicle whose last R09 waypoint is `last`.
+    pub fn synthesize_waypoint(&self, last: &Waypoint, now: u64) -> Option<Waypoint> {
+        let reporting_point = last.r09_reporting_point?;
+        let (lat, lon) = self.interpolate(reporting_point, now.checked_sub(last.time)?)?;
+
+        Some(Waypoint {
+            id: last.id,
+            source: WayPointType::Interpolated,
+            time: now,
+            region: last.region,
+            lat,
+            lon,
+            line: last.line,
+            run: last.run,
+            delayed: last.delayed,
+            r09_reporting_point: last.r09_reporting_point,
+            r09_destination_number: last.r09_destination_number,
+        })
+    }
+}
+
+/// Collection of [`PathModel`]s for all known paths. Serializes as a list of path models.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(from = "Vec<PathModel>", into = "Vec<PathModel>")]
+pub struct PathModels {
+    paths: HashMap<PathKey, PathModel>,
+}
+
+impl From<Vec<PathModel>> for PathModels {
+    fn from(paths: Vec<PathModel>) -> Self {
+        PathModels {
+            paths: paths.into_iter().map(|path| (path.key, path)).collect(),
+        }
+    }
+}
+
+impl From<PathModels> for Vec<PathModel> {
+    fn from(models: PathModels) -> Self {
+        models.paths.into_values().collect()
+    }
+}
+
+impl PathModels {
+    /// Adds a path model, replacing a previous one with the same key
+    pub fn insert(&mut self, path: PathModel) {
+        self.paths.insert(path.key, path);
+    }
+
+    /// Returns the path model for the given key
+    pub fn get(&self, key: &PathKey) -> Option<&PathModel> {
+        self.paths.get(key)
+    }
+
+    /// Iterates over all path models
+    pub fn iter(&self) -> impl Iterator<Item = &PathModel> {
+        self.paths.values()
+    }
+
+    /// Creates a synthetic waypoint for the last R09 waypoint of a vehicle, looking up the path by
+    /// region, line and destination number of the waypoint.
+    pub fn synthesize_waypoint(&self, last: &Waypoint, now: u64) -> Option<Waypoint> {
+        let key = PathKey {
+            region: last.region,
+            line: last.line,
+            destination_number: last.r09_destination_number?,
+        };
+        self.get(&key)?.synthesize_waypoint(last, now)
+    }
+}
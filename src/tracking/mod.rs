@@ -0,0 +1,242 @@
+//! This module keeps the live state of vehicles, derived from the stream of
+//! [`Waypoint`]s. Every vehicle is identified by its region, line and run. The
+//! [`VehicleTracker`] stores the last known position, heading, speed, delay and the time of the
+//! last update for every vehicle, expires vehicles which were not heard of for some time and emits
+//! [`VehicleEvent`]s when vehicles enter or leave the map.
+
+pub mod interpolation;
+pub mod prediction;
+#[cfg(test)]
+mod tests;
+
+use crate::locations::projection::Coordinate;
+use crate::locations::waypoint::{WayPointType, Waypoint};
+use crate::locations::DistanceFrom;
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Default time in milliseconds after which a vehicle without updates is removed (10 minutes)
+pub const DEFAULT_VEHICLE_EXPIRATION: u64 = 10 * 60 * 1000;
+
+/// Unique identifier of a tracked vehicle
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct VehicleKey {
+    /// region identifier
+    pub region: i64,
+    /// line (ger. linie) of the vehicle
+    pub line: i32,
+    /// run (ger. Kurs, -Laufnummer) of the vehicle
+    pub run: i32,
+}
+
+impl From<&Waypoint> for VehicleKey {
+    fn from(waypoint: &Waypoint) -> Self {
+        VehicleKey {
+            region: waypoint.region,
+            line: waypoint.line,
+            run: waypoint.run,
+        }
+    }
+}
+
+/// Current state of a single vehicle, as seen by the [`VehicleTracker`]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VehicleState {
+    /// identifier of the vehicle
+    pub key: VehicleKey,
+    /// latitude of the last known position
+    pub lat: f64,
+    /// longitude of the last known position
+    pub lon: f64,
+    /// heading in degrees clockwise from north, if the vehicle has moved since it was first seen
+    pub heading: Option<f64>,
+    /// speed estimate in meters per second between the two last positions
+    pub speed: Option<f64>,
+    /// last known delay of the vehicle
+    pub delay: Option<f32>,
+    /// source of the last update
+    pub source: WayPointType,
+    /// last reporting point the vehicle passed
+    pub reporting_point: Option<i32>,
+    /// unix time stamp in milliseconds when the vehicle passed the last reporting point, only
+    /// known from r09 telegrams
+    #[serde(default)]
+    pub reporting_point_time: Option<u64>,
+    /// last known destination number of the vehicle
+    pub destination_number: Option<i32>,
+    /// unix time stamp in milliseconds when the vehicle was first seen
+    pub first_seen: u64,
+    /// unix time stamp in milliseconds of the last update
+    pub last_update: u64,
+}
+
+impl VehicleState {
+    fn from_waypoint(waypoint: &Waypoint) -> Self {
+        VehicleState {
+            key: VehicleKey::from(waypoint),
+            lat: waypoint.lat,
+            lon: waypoint.lon,
+            heading: None,
+            speed: None,
+            delay: waypoint.delayed,
+            source: waypoint.source,
+            reporting_point: waypoint.r09_reporting_point,
+            reporting_point_time: Self::passage_time(waypoint),
+            destination_number: waypoint.r09_destination_number,
+            first_seen: waypoint.time,
+            last_update: waypoint.time,
+        }
+    }
+
+    /// Time of the waypoint if it marks the passage of a reporting point. Interpolated waypoints
+    /// carry the reporting point they were interpolated from, but weren't sent there.
+    fn passage_time(waypoint: &Waypoint) -> Option<u64> {
+        (waypoint.source == WayPointType::R09Telegram && waypoint.r09_reporting_point.is_some())
+            .then_some(waypoint.time)
+    }
+
+    /// Applies a newer waypoint to the state. Heading and speed are derived from the movement
+    /// between the previous and the new position.
+    fn apply(&mut self, waypoint: &Waypoint) {
+        let previous = (self.lat, self.lon);
+        let current = (waypoint.lat, waypoint.lon);
+        let distance = previous.distance_from(current);
+        let elapsed = waypoint.time - self.last_update;
+
+        if elapsed > 0 {
+            self.speed = Some(distance / (elapsed as f64 / 1000_f64));
+        }
+        if distance > 0_f64 {
+            self.heading = Some(
+                Coordinate::new(previous.0, previous.1)
+                    .bearing_to(&Coordinate::new(current.0, current.1)),
+            );
+        }
+
+        self.lat = waypoint.lat;
+        self.lon = waypoint.lon;
+        self.source = waypoint.source;
+        self.last_update = waypoint.time;
+
+        // trekkie gps points carry no r09 information, so we keep the last known values
+        if waypoint.delayed.is_some() {
+            self.delay = waypoint.delayed;
+        }
+        if waypoint.r09_reporting_point.is_some() {
+            self.reporting_point = waypoint.r09_reporting_point;
+        }
+        if let Some(time) = Self::passage_time(waypoint) {
+            self.reporting_point_time = Some(time);
+        }
+        if waypoint.r09_destination_number.is_some() {
+            self.destination_number = waypoint.r09_destination_number;
+        }
+    }
+
+    /// Returns the time in milliseconds since the last update of this vehicle
+    pub fn staleness(&self, now: u64) -> u64 {
+        now.saturating_sub(self.last_update)
+    }
+}
+
+/// Events emitted by the [`VehicleTracker`] when the set of known vehicles changes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum VehicleEvent {
+    /// A vehicle that wasn't tracked before was seen
+    Entered(VehicleState),
+    /// A vehicle was expired after inactivity, contains its last known state
+    Left(VehicleState),
+}
+
+/// Keeps per-vehicle state for a stream of [`Waypoint`]s
+#[derive(Debug, Clone)]
+pub struct VehicleTracker {
+    /// time in milliseconds after which a vehicle without updates is removed
+    pub expiration: u64,
+    vehicles: HashMap<VehicleKey, VehicleState>,
+}
+
+impl Default for VehicleTracker {
+    fn default() -> Self {
+        VehicleTracker::new(DEFAULT_VEHICLE_EXPIRATION)
+    }
+}
+
+impl VehicleTracker {
+    /// Creates empty tracker, that expires vehicles after `expiration` milliseconds of inactivity
+    pub fn new(expiration: u64) -> Self {
+        VehicleTracker {
+            expiration,
+            vehicles: HashMap::new(),
+        }
+    }
+
+    /// Updates the vehicle state with the given waypoint. Returns [`VehicleEvent::Entered`] if the
+    /// vehicle wasn't tracked before. Waypoints of unknown source and waypoints older than the
+    /// last known state of the vehicle are ignored.
+    pub fn update(&mut self, waypoint: &Waypoint) -> Option<VehicleEvent> {
+        if waypoint.source == WayPointType::UnknownSource {
+            return None;
+        }
+
+        let key = VehicleKey::from(waypoint);
+        match self.vehicles.get_mut(&key) {
+            Some(state) => {
+                if waypoint.time >= state.last_update {
+                    state.apply(waypoint);
+                }
+                None
+            }
+            None => {
+                let state = VehicleState::from_waypoint(waypoint);
+                self.vehicles.insert(key, state.clone());
+                Some(VehicleEvent::Entered(state))
+            }
+        }
+    }
+
+    /// Removes all vehicles that haven't been updated for longer than the expiration time and
+    /// returns a [`VehicleEvent::Left`] for each of them.
+    pub fn expire(&mut self, now: u64) -> Vec<VehicleEvent> {
+        let expired: Vec<VehicleKey> = self
+            .vehicles
+            .values()
+            .filter(|state| state.staleness(now) > self.expiration)
+            .map(|state| state.key)
+            .collect();
+
+        expired
+            .into_iter()
+            .filter_map(|key| self.vehicles.remove(&key))
+            .map(VehicleEvent::Left)
+            .collect()
+    }
+
+    /// Returns the state of a specific vehicle
+    pub fn get(&self, key: &VehicleKey) -> Option<&VehicleState> {
+        self.vehicles.get(key)
+    }
+
+    /// Iterates over all currently tracked vehicles
+    pub fn vehicles(&self) -> impl Iterator<Item = &VehicleState> {
+        self.vehicles.values()
+    }
+
+    /// Iterates over all currently tracked vehicles in the given region
+    pub fn vehicles_in_region(&self, region: i64) -> impl Iterator<Item = &VehicleState> {
+        self.vehicles
+            .values()
+            .filter(move |state| state.key.region == region)
+    }
+
+    /// Number of currently tracked vehicles
+    pub fn len(&self) -> usize {
+        self.vehicles.len()
+    }
+
+    /// Returns true if no vehicles are tracked
+    pub fn is_empty(&self) -> bool {
+        self.vehicles.is_empty()
+    }
+}
@@ -0,0 +1,416 @@
+//! This module predicts arrival times at the reporting points a vehicle is going to pass next.
+//! The [`TravelTimeModel`] is trained from historic `r09_telegrams`: for every vehicle the time
+//! between two consecutive reporting points is collected per line and time-of-day bucket. Given
+//! the current [`VehicleState`] the model follows the most likely sequence of reporting points
+//! and sums up the travel times, returning an [`Eta`] with a confidence interval for each of them.
+//!
+//! Models are trained offline (e.g. from a Postgres dump) and can be stored to and loaded from
+//! disk as json.
+
+use crate::schema::r09_telegrams;
+use crate::telegrams::r09::R09SaveTelegram;
+use crate::tracking::VehicleState;
+
+use chrono::{DateTime, Duration, NaiveDateTime, Timelike};
+use diesel::{ExpressionMethods, PgConnection, QueryDsl, RunQueryDsl};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+/// Default width of a time-of-day bucket in hours
+pub const DEFAULT_BUCKET_WIDTH: u32 = 3;
+/// Default maximum time between two consecutive reporting points, that is still considered a
+/// single trip segment (15 minutes)
+pub const DEFAULT_MAX_SEGMENT_TIME: i64 = 15 * 60 * 1000;
+/// z-score for a two-sided 90% confidence interval
+pub const Z_SCORE_90: f64 = 1.645;
+
+/// Running mean and variance of travel times in milliseconds (Welford's algorithm)
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct RunningStatistics {
+    /// number of samples
+    pub count: u64,
+    /// mean travel time in milliseconds
+    pub mean: f64,
+    /// sum of squared differences from the mean
+    pub m2: f64,
+}
+
+impl RunningStatistics {
+    /// Adds a sample
+    pub fn push(&mut self, value: f64) {
+        self.count += 1;
+        let delta = value - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (value - self.mean);
+    }
+
+    /// Sample variance in milliseconds squared, zero for less than two samples
+    pub fn variance(&self) -> f64 {
+        if self.count > 1 {
+            self.m2 / (self.count - 1) as f64
+        } else {
+            0_f64
+        }
+    }
+}
+
+/// Identifies a segment between two consecutive reporting points of a line
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct SegmentKey {
+    /// region identifier
+    pub region: i64,
+    /// line (ger. linie) of the vehicle
+    pub line: i32,
+    /// destination number of the vehicle, if the telegrams contained it
+    pub destination_number: Option<i32>,
+    /// reporting point the segment starts at
+    pub from: i32,
+    /// reporting point the segment ends at
+    pub to: i32,
+}
+
+/// Travel time statistics of a single segment
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Segment {
+    /// segment identifier
+    #[serde(flatten)]
+    pub key: SegmentKey,
+    /// statistics over all samples, regardless of the time of day
+    pub all_day: RunningStatistics,
+    /// statistics per time-of-day bucket
+    pub buckets: Vec<RunningStatistics>,
+}
+
+/// Predicted arrival at a downstream reporting point
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Eta {
+    /// Reporting Point inside the r09 telegram (*meldepunkt*) ID
+    pub reporting_point: i32,
+    /// predicted unix time stamp of arrival in milliseconds
+    pub eta: u64,
+    /// lower bound of the confidence interval, unix time stamp in milliseconds
+    pub lower: u64,
+    /// upper bound of the confidence interval, unix time stamp in milliseconds
+    pub upper: u64,
+}
+
+/// Error enum for saving and loading [`TravelTimeModel`]s
+#[derive(Debug)]
+pub enum TravelTimeModelError {
+    /// See [`serde_json::Error`]
+    SerdeJsonError(serde_json::Error),
+    /// See [`std::io::Error`]
+    IOError(std::io::Error),
+}
+
+impl From<serde_json::Error> for TravelTimeModelError {
+    fn from(e: serde_json::Error) -> TravelTimeModelError {
+        TravelTimeModelError::SerdeJsonError(e)
+    }
+}
+impl From<std::io::Error> for TravelTimeModelError {
+    fn from(e: std::io::Error) -> TravelTimeModelError {
+        TravelTimeModelError::IOError(e)
+    }
+}
+
+/// The on-disk format of the [`TravelTimeModel`]
+#[derive(Serialize, Deserialize)]
+struct TravelTimeModelFile {
+    bucket_width: u32,
+    segments: Vec<Segment>,
+}
+
+/// Travel time model between consecutive reporting points, per line and time of day
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "TravelTimeModelFile", into = "TravelTimeModelFile")]
+pub struct TravelTimeModel {
+    bucket_width: u32,
+    segments: HashMap<SegmentKey, Segment>,
+    successors: HashMap<(i64, i32, Option<i32>, i32), Vec<i32>>,
+}
+
+impl From<TravelTimeModelFile> for TravelTimeModel {
+    fn from(file: TravelTimeModelFile) -> Self {
+        let mut model = TravelTimeModel::new(file.bucket_width);
+        for segment in file.segments {
+            model.insert(segment);
+        }
+        model
+    }
+}
+
+impl From<TravelTimeModel> for TravelTimeModelFile {
+    fn from(model: TravelTimeModel) -> Self {
+        TravelTimeModelFile {
+            bucket_width: model.bucket_width,
+            segments: model.segments.into_values().collect(),
+        }
+    }
+}
+
+impl TravelTimeModel {
+    /// Creates an empty model with time-of-day buckets `bucket_width` hours wide
+    pub fn new(bucket_width: u32) -> Self {
+        TravelTimeModel {
+            bucket_width: bucket_width.clamp(1, 24),
+            segments: HashMap::new(),
+            successors: HashMap::new(),
+        }
+    }
+
+    fn insert(&mut self, segment: Segment) {
+        let key = segment.key;
+        let successors = self
+            .successors
+            .entry((key.region, key.line, key.destination_number, key.from))
+            .or_default();
+        if !successors.contains(&key.to) {
+            successors.push(key.to);
+        }
+        self.segments.insert(key, segment);
+    }
+
+    /// Number of time-of-day buckets
+    pub fn bucket_count(&self) -> usize {
+        24_usize.div_ceil(self.bucket_width as usize)
+    }
+
+    /// Time-of-day bucket of the given time, time is interpreted as UTC
+    pub fn bucket(&self, time: &NaiveDateTime) -> usize {
+        (time.hour() / self.bucket_width) as usize
+    }
+
+    /// Adds a single travel time observation in milliseconds
+    pub fn add_sample(&mut self, key: SegmentKey, departure: &NaiveDateTime, travel_time: i64) {
+        let bucket = self.bucket(departure);
+        let bucket_count = self.bucket_count();
+
+        if !self.segments.contains_key(&key) {
+            self.insert(Segment {
+                key,
+                all_day: RunningStatistics::default(),
+                buckets: vec![RunningStatistics::default(); bucket_count],
+            });
+        }
+        if let Some(segment) = self.segments.get_mut(&key) {
+            segment.all_day.push(travel_time as f64);
+            if let Some(statistics) = segment.buckets.get_mut(bucket) {
+                statistics.push(travel_time as f64);
+            }
+        }
+    }
+
+    /// Returns the statistics of a segment
+    pub fn segment(&self, key: &SegmentKey) -> Option<&Segment> {
+        self.segments.get(key)
+    }
+
+    /// Iterates over all segments of the model
+    pub fn segments(&self) -> impl Iterator<Item = &Segment> {
+        self.segments.values()
+    }
+
+    /// Returns the most frequently observed reporting point following `from`. If no segment for
+    /// the destination number was recorded, segments without destination number are used.
+    pub fn next_reporting_point(
+        &self,
+        region: i64,
+        line: i32,
+        destination_number: Option<i32>,
+        from: i32,
+    ) -> Option<SegmentKey> {
+        let lookup = |destination_number: Option<i32>| {
+            self.successors
+                .get(&(region, line, destination_number, from))?
+                .iter()
+                .map(|to| SegmentKey {
+                    region,
+                    line,
+                    destination_number,
+                    from,
+                    to: *to,
+                })
+                .max_by_key(|key| self.segments[key].all_day.count)
+        };
+
+        lookup(destination_number).or_else(|| lookup(None))
+    }
+
+    /// Predicts the arrival at the next `max_reporting_points` reporting points for the vehicle,
+    /// counted from the time it passed its last reporting point. Without that time, e.g. for
+    /// vehicles only known from gps, nothing is predicted.
+    /// Travel times of the time-of-day bucket are used if available, otherwise the statistics over
+    /// the whole day. The confidence interval is `mean ± z_score * standard deviation` of the
+    /// summed travel times, see [`Z_SCORE_90`].
+    pub fn predict(
+        &self,
+        state: &VehicleState,
+        max_reporting_points: usize,
+        z_score: f64,
+    ) -> Vec<Eta> {
+        let mut etas: Vec<Eta> = Vec::new();
+        let Some(mut current) = state.reporting_point else {
+            return etas;
+        };
+        // later updates, e.g. from gps, don't move the vehicle past the reporting point
+        let Some(passed) = state.reporting_point_time else {
+            return etas;
+        };
+        let Some(start) = DateTime::from_timestamp_millis(passed as i64) else {
+            return etas;
+        };
+        let start = start.naive_utc();
+
+        let mut visited: HashSet<i32> = HashSet::from([current]);
+        let (mut mean, mut variance) = (0_f64, 0_f64);
+
+        while etas.len() < max_reporting_points {
+            let Some(key) = self.next_reporting_point(
+                state.key.region,
+                state.key.line,
+                state.destination_number,
+                current,
+            ) else {
+                break;
+            };
+            if !visited.insert(key.to) {
+                break;
+            }
+
+            let segment = &self.segments[&key];
+            let departure = start + Duration::milliseconds(mean as i64);
+            let statistics = segment
+                .buckets
+                .get(self.bucket(&departure))
+                .filter(|bucket| bucket.count > 0)
+                .unwrap_or(&segment.all_day);
+
+            mean += statistics.mean;
+            variance += statistics.variance();
+            let margin = z_score * variance.sqrt();
+
+            etas.push(Eta {
+                reporting_point: key.to,
+                eta: passed + mean as u64,
+                lower: passed + (mean - margin).max(0_f64) as u64,
+                upper: passed + (mean + margin) as u64,
+            });
+            current = key.to;
+        }
+
+        etas
+    }
+
+    /// Writes the model as json to `path`
+    pub fn save(&self, path: &Path) -> Result<(), TravelTimeModelError> {
+        fs::write(path, serde_json::to_string(self)?)?;
+        Ok(())
+    }
+
+    /// Reads the model from a json file at `path`
+    pub fn load(path: &Path) -> Result<Self, TravelTimeModelError> {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    /// Trains a model from the telegrams of `region` between `from` and `to`. Telegrams are loaded
+    /// day by day, to keep the memory footprint low on big dumps.
+    pub fn from_postgres(
+        database_connection: &mut PgConnection,
+        region: i64,
+        from: NaiveDateTime,
+        to: NaiveDateTime,
+        bucket_width: u32,
+    ) -> Result<Self, diesel::result::Error> {
+        let mut trainer = TravelTimeModelTrainer::new(bucket_width, DEFAULT_MAX_SEGMENT_TIME);
+
+        let mut window_start = from;
+        while window_start < to {
+            let window_end = std::cmp::min(window_start + Duration::days(1), to);
+            let telegrams = r09_telegrams::table
+                .filter(r09_telegrams::region.eq(region))
+                .filter(r09_telegrams::time.ge(window_start))
+                .filter(r09_telegrams::time.lt(window_end))
+                .order(r09_telegrams::time.asc())
+                .load::<R09SaveTelegram>(database_connection)?;
+
+            for telegram in &telegrams {
+                trainer.add(telegram);
+            }
+            window_start = window_end;
+        }
+
+        Ok(trainer.finish())
+    }
+}
+
+/// Last reporting point passed by a vehicle while training
+struct Passage {
+    reporting_point: i32,
+    time: NaiveDateTime,
+}
+
+/// Incrementally trains a [`TravelTimeModel`] from telegrams. Telegrams have to be added in
+/// chronological order.
+pub struct TravelTimeModelTrainer {
+    model: TravelTimeModel,
+    max_segment_time: i64,
+    last_passage: HashMap<(i64, i32, i32, Option<i32>), Passage>,
+}
+
+impl TravelTimeModelTrainer {
+    /// Creates a trainer for a model with `bucket_width` hours wide time-of-day buckets. Gaps
+    /// longer than `max_segment_time` milliseconds between two reporting points are not counted
+    /// as travel time.
+    pub fn new(bucket_width: u32, max_segment_time: i64) -> Self {
+        TravelTimeModelTrainer {
+            model: TravelTimeModel::new(bucket_width),
+            max_segment_time,
+            last_passage: HashMap::new(),
+        }
+    }
+
+    /// Adds a telegram. Telegrams without line or run number are ignored, as are repeated
+    /// telegrams for the reporting point the vehicle passed last (e.g. received by several
+    /// stations).
+    pub fn add(&mut self, telegram: &R09SaveTelegram) {
+        let (Some(line), Some(run)) = (telegram.line, telegram.run_number) else {
+            return;
+        };
+        let vehicle = (telegram.region, line, run, telegram.destination_number);
+
+        if let Some(last) = self.last_passage.get(&vehicle) {
+            if last.reporting_point == telegram.reporting_point {
+                return;
+            }
+
+            let travel_time = (telegram.time - last.time).num_milliseconds();
+            if travel_time > 0 && travel_time <= self.max_segment_time {
+                let key = SegmentKey {
+                    region: telegram.region,
+                    line,
+                    destination_number: telegram.destination_number,
+                    from: last.reporting_point,
+                    to: telegram.reporting_point,
+                };
+                let departure = last.time;
+                self.model.add_sample(key, &departure, travel_time);
+            }
+        }
+
+        self.last_passage.insert(
+            vehicle,
+            Passage {
+                reporting_point: telegram.reporting_point,
+                time: telegram.time,
+            },
+        );
+    }
+
+    /// Returns the trained model
+    pub fn finish(self) -> TravelTimeModel {
+        self.model
+    }
+}
@@ -0,0 +1,188 @@
+use super::*;
+
+fn waypoint(time: u64, lat: f64, lon: f64, source: WayPointType) -> Waypoint {
+    Waypoint {
+        id: 0,
+        source,
+        time,
+        region: 0,
+        lat,
+        lon,
+        line: 11,
+        run: 4,
+        delayed: Some(1.0),
+        r09_reporting_point: Some(1234),
+        r09_destination_number: Some(7),
+    }
+}
+
+#[test]
+fn test_enter_update_leave() {
+    let mut tracker = VehicleTracker::new(60_000);
+
+    let entered = tracker.update(&waypoint(0, 51.0, 13.0, WayPointType::R09Telegram));
+    assert!(matches!(entered, Some(VehicleEvent::Entered(_))));
+
+    // ~111 m north in 10 s
+    let mut update = waypoint(10_000, 51.001, 13.0, WayPointType::TrekkieGPS);
+    update.delayed = None;
+    update.r09_reporting_point = None;
+    assert!(tracker.update(&update).is_none());
+    assert_eq!(tracker.len(), 1);
+
+    let state = tracker.vehicles().next().unwrap();
+    assert!((state.speed.unwrap() - 11.1).abs() < 0.1);
+    assert!(state.heading.unwrap() < 0.1);
+    assert_eq!(state.delay, Some(1.0));
+    assert_eq!(state.reporting_point, Some(1234));
+
+    assert!(tracker.expire(60_000).is_empty());
+    let left = tracker.expire(70_001);
+    assert!(matches!(left.as_slice(), [VehicleEvent::Left(_)]));
+    assert!(tracker.is_empty());
+}
+
+#[test]
+fn test_ignores_outdated_and_unknown() {
+    let mut tracker = VehicleTracker::default();
+
+    assert!(tracker
+        .update(&waypoint(0, 51.0, 13.0, WayPointType::UnknownSource))
+        .is_none());
+    assert!(tracker.is_empty());
+
+    tracker.update(&waypoint(5_000, 51.0, 13.0, WayPointType::R09Telegram));
+    tracker.update(&waypoint(1_000, 52.0, 14.0, WayPointType::R09Telegram));
+
+    let state = tracker.vehicles().next().unwrap();
+    assert_eq!(state.last_update, 5_000);
+    assert_eq!(state.lat, 51.0);
+}
+
+#[test]
+fn test_path_interpolation() {
+    use crate::locations::gps::GpsPoint;
+    use crate::locations::TransmissionLocation;
+    use interpolation::{PathKey, PathModel};
+
+    let start = chrono::NaiveDate::from_ymd_opt(2023, 5, 1)
+        .unwrap()
+        .and_hms_opt(12, 0, 0)
+        .unwrap();
+    // straight track going north, one point every 10 s
+    let track: Vec<GpsPoint> = (0..=10)
+        .map(|i| GpsPoint {
+            id: i,
+            trekkie_run: uuid::Uuid::nil(),
+            timestamp: start + chrono::Duration::seconds(10 * i),
+            lat: 51.0 + 0.001 * i as f64,
+            lon: 13.0,
+            elevation: None,
+            accuracy: None,
+            vertical_accuracy: None,
+            bearing: None,
+            speed: None,
+        })
+        .collect();
+    let location = |id: i64, reporting_point: i32, lat: f64| TransmissionLocation {
+        id,
+        region: 0,
+        reporting_point,
+        lat,
+        lon: 13.0,
+        ground_truth: false,
+    };
+    // 150 is next to the track, but only reported by vehicles of the opposite direction
+    let locations = vec![
+        location(1, 100, 51.002),
+        location(2, 150, 51.004),
+        location(3, 200, 51.006),
+    ];
+    let reported = [100, 200].into_iter().collect();
+
+    let key = PathKey {
+        region: 0,
+        line: 11,
+        destination_number: 7,
+    };
+    let path = PathModel::from_tracks(key, &[track], &locations, &reported).unwrap();
+
+    assert_eq!(path.reporting_points.len(), 2);
+    assert_eq!(path.next_reporting_point(100).unwrap().reporting_point, 200);
+    assert_eq!(path.reporting_points[0].travel_time, Some(40_000));
+
+    let (lat, _) = path.interpolate(100, 20_000).unwrap();
+    assert!((lat - 51.004).abs() < 1e-6);
+    // vehicle is never placed beyond the next reporting point
+    let (lat, _) = path.interpolate(100, 100_000).unwrap();
+    assert!((lat - 51.006).abs() < 1e-6);
+
+    let mut last = waypoint(0, 51.002, 13.0, WayPointType::R09Telegram);
+    last.r09_reporting_point = Some(100);
+    let synthetic = path.synthesize_waypoint(&last, 10_000).unwrap();
+    assert_eq!(synthetic.source, WayPointType::Interpolated);
+    assert!((synthetic.lat - 51.003).abs() < 1e-6);
+}
+
+#[test]
+fn test_arrival_prediction() {
+    use crate::telegrams::r09::{R09SaveTelegram, R09Type};
+    use prediction::{TravelTimeModel, TravelTimeModelTrainer, Z_SCORE_90};
+
+    let start = chrono::NaiveDate::from_ymd_opt(2023, 5, 1)
+        .unwrap()
+        .and_hms_opt(8, 0, 0)
+        .unwrap();
+    let telegram = |run: i32, reporting_point: i32, seconds: i64| R09SaveTelegram {
+        id: None,
+        time: start + chrono::Duration::seconds(seconds),
+        station: uuid::Uuid::nil(),
+        r09_type: R09Type::R16,
+        delay: Some(0),
+        reporting_point,
+        junction: 0,
+        direction: 0,
+        request_status: 0,
+        priority: None,
+        direction_request: None,
+        line: Some(11),
+        run_number: Some(run),
+        destination_number: Some(7),
+        train_length: None,
+        vehicle_number: None,
+        operator: None,
+        region: 0,
+    };
+
+    let mut trainer = TravelTimeModelTrainer::new(3, 15 * 60 * 1000);
+    for (run, offset, first, second) in [(1, 0, 60, 120), (2, 600, 80, 100)] {
+        trainer.add(&telegram(run, 100, offset));
+        trainer.add(&telegram(run, 100, offset + 1));
+        trainer.add(&telegram(run, 200, offset + first));
+        trainer.add(&telegram(run, 300, offset + first + second));
+    }
+    let model = trainer.finish();
+
+    let json = serde_json::to_string(&model).unwrap();
+    let model: TravelTimeModel = serde_json::from_str(&json).unwrap();
+
+    let passed = 1_682_928_000_000;
+    let mut r09 = waypoint(passed, 51.0, 13.0, WayPointType::R09Telegram);
+    r09.r09_reporting_point = Some(100);
+    // gps update 30 s after the vehicle passed the reporting point
+    let mut gps = waypoint(passed + 30_000, 51.001, 13.0, WayPointType::TrekkieGPS);
+    gps.r09_reporting_point = None;
+    let mut tracker = VehicleTracker::default();
+    tracker.update(&r09);
+    tracker.update(&gps);
+    let state = tracker.vehicles().next().unwrap();
+    assert_eq!(state.last_update, passed + 30_000);
+
+    let etas = model.predict(state, 5, Z_SCORE_90);
+    assert_eq!(etas.len(), 2);
+    assert_eq!(etas[0].reporting_point, 200);
+    assert_eq!(etas[0].eta, passed + 70_000);
+    assert_eq!(etas[1].reporting_point, 300);
+    assert_eq!(etas[1].eta, passed + 180_000);
+    assert!(etas[1].lower < etas[1].eta && etas[1].eta < etas[1].upper);
+}